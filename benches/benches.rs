@@ -3,7 +3,7 @@ use std::thread;
 use std::time::Duration;
 
 use criterion::{criterion_group, criterion_main, BatchSize, Bencher, Criterion};
-use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool, RayonThreadPool};
+use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
 use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, SledKvsEngine};
 use rand::distributions::{Alphanumeric, DistString};
 use rand::{thread_rng, Rng};
@@ -100,8 +100,8 @@ where
             let logger = NullLoggerBuilder.build().unwrap();
             let thread_pool = T::new(*thread_num as u32).expect("Fail in ThreadPool initial");
 
-            let mut server =
-                KvsServer::new(logger, e, thread_pool, "127.0.0.1:0").expect("Fail in Server initial");
+            let mut server = KvsServer::new(logger, e, thread_pool, "127.0.0.1:0")
+                .expect("Fail in Server initial");
             let addr = server.get_address();
 
             let server = thread::spawn(move || {
@@ -122,7 +122,7 @@ where
                 let sender_cp = sender.clone();
                 client_pool.spawn(move || {
                     let mut client = KvsClient::new(addr).unwrap();
-                    if let Ok(_) = client.set(k, v) {
+                    if client.set(k, v).is_ok() {
                         while sender_cp.send(0).is_err() {}
                     } else {
                         panic!("set error");
@@ -156,8 +156,8 @@ where
                 e.set(k, v).expect("Fail in insert kv to sled db");
             }
 
-            let mut server =
-                KvsServer::new(logger, e, thread_pool, "127.0.0.1:0").expect("Fail in Server initial");
+            let mut server = KvsServer::new(logger, e, thread_pool, "127.0.0.1:0")
+                .expect("Fail in Server initial");
             let addr = server.get_address();
 
             let server = thread::spawn(move || {
@@ -1,9 +1,10 @@
+use std::io::{self, Write};
 use std::net;
 use std::process::exit;
 
 use clap::AppSettings;
 use clap::Parser;
-use kvs::KvsError;
+use kvs::{KvsError, WatchEvent};
 
 const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:4000";
 
@@ -61,6 +62,69 @@ enum SubCommand {
             default_value = DEFAULT_SERVER_ADDR)]
         addr: net::SocketAddr,
     },
+    /// List key-value pairs in key order, with keys in [START, END) or starting with a prefix
+    Scan {
+        /// Inclusive start key
+        #[clap(name = "START", conflicts_with = "prefix")]
+        start: Option<String>,
+        /// Exclusive end key
+        #[clap(name = "END", conflicts_with = "prefix")]
+        end: Option<String>,
+        /// List keys starting with the prefix instead of a range
+        #[clap(long, value_name = "PREFIX")]
+        prefix: Option<String>,
+        /// Accepts an IP address, either v4 or v6, and a port number, with the format 'IP:PORT'.
+        #[clap(
+            long,
+            value_name = "IP-PORT",
+            default_value = DEFAULT_SERVER_ADDR)]
+        addr: net::SocketAddr,
+    },
+    /// Compact the store to reclaim space of overwritten and removed values
+    Compact {
+        /// Accepts an IP address, either v4 or v6, and a port number, with the format 'IP:PORT'.
+        #[clap(
+            long,
+            value_name = "IP-PORT",
+            default_value = DEFAULT_SERVER_ADDR)]
+        addr: net::SocketAddr,
+    },
+    /// Write a consistent copy of the store into a path on the server
+    Backup {
+        /// A path in the backup directory of the server which doesn't hold a backup yet
+        #[clap(name = "PATH")]
+        path: String,
+        /// Accepts an IP address, either v4 or v6, and a port number, with the format 'IP:PORT'.
+        #[clap(
+            long,
+            value_name = "IP-PORT",
+            default_value = DEFAULT_SERVER_ADDR)]
+        addr: net::SocketAddr,
+    },
+    /// Replace every key of the store by a backup in a path on the server
+    Restore {
+        /// A path in the backup directory of the server holding a backup
+        #[clap(name = "PATH")]
+        path: String,
+        /// Accepts an IP address, either v4 or v6, and a port number, with the format 'IP:PORT'.
+        #[clap(
+            long,
+            value_name = "IP-PORT",
+            default_value = DEFAULT_SERVER_ADDR)]
+        addr: net::SocketAddr,
+    },
+    /// Print writes of keys starting with a prefix as they happen, one per line
+    Watch {
+        /// A string prefix, empty to watch every key
+        #[clap(name = "PREFIX", default_value = "")]
+        prefix: String,
+        /// Accepts an IP address, either v4 or v6, and a port number, with the format 'IP:PORT'.
+        #[clap(
+            long,
+            value_name = "IP-PORT",
+            default_value = DEFAULT_SERVER_ADDR)]
+        addr: net::SocketAddr,
+    },
 }
 fn main() {
     let opt = Opt::parse();
@@ -78,8 +142,10 @@ fn run_client(opt: Opt) -> kvs::Result<()> {
         }
         SubCommand::Get { key, addr } => {
             let mut client = kvs::KvsClient::new(addr)?;
-            if let Some(value) = client.get(key)? {
-                println!("{}", value);
+            if let Some(value) = client.get_bytes(key)? {
+                let mut stdout = io::stdout();
+                stdout.write_all(&value)?;
+                stdout.write_all(b"\n")?;
             } else {
                 println!("{}", KvsError::KeyNotFound);
             }
@@ -88,6 +154,59 @@ fn run_client(opt: Opt) -> kvs::Result<()> {
             let mut client = kvs::KvsClient::new(addr)?;
             client.remove(key)?;
         }
+        SubCommand::Scan {
+            start,
+            end,
+            prefix,
+            addr,
+        } => {
+            let mut client = kvs::KvsClient::new(addr)?;
+            let start = start.unwrap_or_default().into_bytes();
+            let pairs = match (prefix, end) {
+                (Some(prefix), _) => client.scan_prefix_bytes(prefix)?,
+                (None, Some(end)) => client.scan_bytes(start..end.into_bytes())?,
+                (None, None) => client.scan_bytes(start..)?,
+            };
+            let mut stdout = io::stdout().lock();
+            for (key, value) in pairs {
+                stdout.write_all(&key)?;
+                stdout.write_all(b"\t")?;
+                stdout.write_all(&value)?;
+                stdout.write_all(b"\n")?;
+            }
+        }
+        SubCommand::Compact { addr } => {
+            let mut client = kvs::KvsClient::new(addr)?;
+            client.compact()?;
+        }
+        SubCommand::Backup { path, addr } => {
+            let mut client = kvs::KvsClient::new(addr)?;
+            client.backup_to(path)?;
+        }
+        SubCommand::Restore { path, addr } => {
+            let mut client = kvs::KvsClient::new(addr)?;
+            client.restore_from(path)?;
+        }
+        SubCommand::Watch { prefix, addr } => {
+            let client = kvs::KvsClient::new(addr)?;
+            let mut stdout = io::stdout();
+            for event in client.watch_prefix(prefix)? {
+                match event? {
+                    WatchEvent::Set { key, value } => {
+                        stdout.write_all(b"set\t")?;
+                        stdout.write_all(&key)?;
+                        stdout.write_all(b"\t")?;
+                        stdout.write_all(&value)?;
+                    }
+                    WatchEvent::Rm { key } => {
+                        stdout.write_all(b"rm\t")?;
+                        stdout.write_all(&key)?;
+                    }
+                }
+                stdout.write_all(b"\n")?;
+                stdout.flush()?;
+            }
+        }
     }
     Ok(())
 }
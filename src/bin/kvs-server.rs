@@ -5,19 +5,25 @@ use std::io::{BufWriter, Write};
 use std::path::PathBuf;
 use std::process::exit;
 use std::str::FromStr;
-use std::{fs, net};
+use std::time::Duration;
+use std::{fs, net, thread};
 
 use clap::ArgEnum;
 use clap::Parser;
 use kvs::thread_pool::ThreadPool;
-use kvs::{thread_pool, KvStore, KvsError, KvsServer, Result, SledKvsEngine};
+use kvs::{
+    thread_pool, KvStore, KvStoreOptions, KvsEngine, KvsError, KvsServer, Protocol, Result,
+    SledKvsEngine, SyncPolicy,
+};
 
-use slog::{info, Logger};
+use slog::{error, info, Logger};
 use sloggers::terminal::{Destination, TerminalLoggerBuilder};
 use sloggers::Build;
 
 const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::Kvs;
+const DEFAULT_SYNC_INTERVAL_MS: &str = "1000";
+const DEFAULT_IDLE_TIMEOUT_SECS: &str = "60";
 
 #[derive(Parser, Debug)]
 #[clap(name = env!("CARGO_PKG_NAME"),
@@ -33,9 +39,67 @@ struct Opt {
         default_value = DEFAULT_SERVER_ADDR)]
     addr: net::SocketAddr,
 
+    /// Sets the listening address of the Redis RESP2 protocol, off by default
+    #[clap(long, value_name = "IP-PORT")]
+    resp_addr: Option<net::SocketAddr>,
+
+    /// Sets the listening address of the memcached text protocol, off by default
+    #[clap(long, value_name = "IP-PORT")]
+    memcached_addr: Option<net::SocketAddr>,
+
+    /// Sets the listening address of the HTTP/JSON gateway, off by default
+    #[clap(long, value_name = "IP-PORT")]
+    http_addr: Option<net::SocketAddr>,
+
+    /// Sets the seconds a connection may stay idle before it is closed, 0 to keep it open
+    #[clap(
+        long,
+        value_name = "SECS",
+        default_value = DEFAULT_IDLE_TIMEOUT_SECS)]
+    idle_timeout: u64,
+
+    /// Sets the directory clients may back up the store into and restore it from, off by default
+    #[clap(long, value_name = "DIR")]
+    backup_dir: Option<PathBuf>,
+
     /// Sets the storage engine
     #[clap(arg_enum, long, value_name = "ENGINE-NAME")]
     engine: Option<Engine>,
+
+    /// Sets when writes are synced to disk, defaults to the engine's own choice
+    #[clap(arg_enum, long, value_name = "SYNC-POLICY")]
+    sync: Option<SyncMode>,
+
+    /// Sets the sync interval in milliseconds of the `interval` policy
+    #[clap(
+        long,
+        value_name = "MS",
+        default_value = DEFAULT_SYNC_INTERVAL_MS)]
+    sync_interval: u64,
+
+    /// Sets the minimum dead bytes to trigger a compaction of `kvs` engine
+    #[clap(long, value_name = "BYTES")]
+    compaction_threshold: Option<u64>,
+
+    /// Sets the minimum ratio of dead bytes to total bytes to trigger a compaction of `kvs` engine
+    #[clap(long, value_name = "RATIO")]
+    compaction_ratio: Option<f64>,
+
+    /// Sets the minimum interval in seconds between two compactions of `kvs` engine
+    #[clap(long, value_name = "SECS")]
+    compaction_interval: Option<u64>,
+
+    /// Sets the size of a log block of `kvs` engine
+    #[clap(long, value_name = "BYTES")]
+    block_size: Option<u64>,
+}
+
+#[derive(Debug, ArgEnum, Clone, PartialEq, Eq)]
+enum SyncMode {
+    Never,
+    Always,
+    Interval,
+    Group,
 }
 
 #[derive(Debug, ArgEnum, Clone, PartialEq, Eq)]
@@ -127,23 +191,89 @@ fn save_selected_engine(path: impl Into<PathBuf>, engine: &Engine) -> Result<()>
 }
 
 fn run_server(logger: Logger, opt: Opt) -> Result<()> {
-    let addr = opt.addr;
-    let engine = opt.engine.unwrap();
-    let pool = thread_pool::SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+    let engine = opt.engine.clone().unwrap();
 
     info!(logger, "kvs-server version: {}", env!("CARGO_PKG_VERSION"));
-    info!(logger, "Start on `{}` with engine `{}`", addr, engine);
+    info!(logger, "Start on `{}` with engine `{}`", opt.addr, engine);
 
     let dir = current_dir()?.join("store");
+    let sync = opt.sync.as_ref().map(|sync| match sync {
+        SyncMode::Never => SyncPolicy::Never,
+        SyncMode::Always => SyncPolicy::Always,
+        SyncMode::Interval => SyncPolicy::Interval(opt.sync_interval),
+        SyncMode::Group => SyncPolicy::GroupCommit,
+    });
 
     match engine {
         Engine::Kvs => {
-            KvsServer::new(logger, KvStore::open(dir)?, pool, addr)?.run()?;
+            let mut options = KvStoreOptions {
+                logger: logger.clone(),
+                ..KvStoreOptions::default()
+            };
+            if let Some(sync) = sync {
+                options.sync = sync;
+            }
+            if let Some(threshold) = opt.compaction_threshold {
+                options.compaction_threshold = threshold;
+            }
+            if let Some(ratio) = opt.compaction_ratio {
+                options.compaction_ratio = ratio;
+            }
+            if let Some(secs) = opt.compaction_interval {
+                options.compaction_interval = Duration::from_secs(secs);
+            }
+            if let Some(size) = opt.block_size {
+                options.block_threshold = size;
+            }
+            serve(logger, KvStore::open_with(dir, options)?, &opt)?;
         }
         Engine::Sled => {
-            KvsServer::new(logger, SledKvsEngine::open(dir)?, pool, addr)?.run()?;
+            let engine = match sync {
+                Some(sync) => SledKvsEngine::open_with(dir, sync)?,
+                None => SledKvsEngine::open(dir)?,
+            };
+            serve(logger, engine, &opt)?;
         }
     }
 
     Ok(())
 }
+
+/// Serve `engine` on every listener of `opt`, each with a pool of its own
+fn serve<E: KvsEngine>(logger: Logger, engine: E, opt: &Opt) -> Result<()> {
+    let idle_timeout = Some(opt.idle_timeout)
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs);
+    let listeners = [
+        (opt.resp_addr, Protocol::Resp),
+        (opt.memcached_addr, Protocol::Memcached),
+        (opt.http_addr, Protocol::Http),
+    ];
+    for (addr, protocol) in listeners {
+        let addr = match addr {
+            Some(addr) => addr,
+            None => continue,
+        };
+        info!(logger, "Serve {:?} protocol on `{}`", protocol, addr);
+        let pool = thread_pool::SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+        let mut server = KvsServer::new(logger.clone(), engine.clone(), pool, addr)?
+            .with_protocol(protocol)
+            .with_idle_timeout(idle_timeout);
+        let logger = logger.clone();
+        thread::spawn(move || {
+            if let Err(e) = server.run() {
+                error!(logger, "Run {:?} listener failed: {}", protocol, e);
+            }
+        });
+    }
+
+    if let Some(dir) = &opt.backup_dir {
+        info!(logger, "Serve backups in `{}`", dir.display());
+        fs::create_dir_all(dir)?;
+    }
+    let pool = thread_pool::SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+    KvsServer::new(logger, engine, pool, opt.addr)?
+        .with_idle_timeout(idle_timeout)
+        .with_backup_dir(opt.backup_dir.clone())
+        .run()
+}
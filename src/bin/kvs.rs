@@ -1,4 +1,6 @@
 use std::env::current_dir;
+use std::io::{self, Write};
+use std::path::PathBuf;
 use std::process::exit;
 
 use clap::AppSettings;
@@ -41,6 +43,18 @@ enum SubCommand {
         #[clap(name = "KEY")]
         key: String,
     },
+    /// Write a consistent copy of the store into a path
+    Backup {
+        /// A path which doesn't hold a backup yet
+        #[clap(name = "PATH")]
+        path: PathBuf,
+    },
+    /// Replace every key of the store by a backup in a path
+    Restore {
+        /// A path holding a backup
+        #[clap(name = "PATH")]
+        path: PathBuf,
+    },
 }
 
 fn main() -> kvs::Result<()> {
@@ -53,7 +67,7 @@ fn main() -> kvs::Result<()> {
         SubCommand::Get { key } => {
             let store = kvs::KvStore::open(current_dir()?)?;
             match store.get(key) {
-                Ok(Some(value)) => print!("{}", value),
+                Ok(Some(value)) => io::stdout().write_all(&value)?,
                 Ok(None) | Err(kvs::KvsError::KeyNotFound) => {
                     print!("Key not found");
                     exit(0);
@@ -71,6 +85,14 @@ fn main() -> kvs::Result<()> {
                 other => return other,
             }
         }
+        SubCommand::Backup { path } => {
+            let store = kvs::KvStore::open(current_dir()?)?;
+            store.backup_to(path)?;
+        }
+        SubCommand::Restore { path } => {
+            let store = kvs::KvStore::open(current_dir()?)?;
+            store.restore_from(path)?;
+        }
     }
 
     Ok(())
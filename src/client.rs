@@ -1,70 +1,515 @@
 use std::{
-    io::{Read, Write},
+    io::{self, Write},
     net::{Shutdown, TcpStream, ToSocketAddrs},
+    ops::{Bound, RangeBounds},
+    thread,
+    time::Duration,
 };
 
-use crate::{serde, KvsError, Request, Response, Result};
+use crate::{
+    serde, KvsError, KvsTransaction, Request, Response, Result, WatchEvent, WriteBatch, FEATURES,
+    PROTOCOL_VERSION,
+};
 
 /// Key value store client
+///
+/// Requests of a client share one connection, which is kept open until
+/// the client is dropped.
 pub struct KvsClient {
     stream: TcpStream,
+    /// Negotiated protocol version
+    version: u64,
+    /// Negotiated optional features
+    features: Vec<String>,
 }
 
 impl KvsClient {
     /// Create a instance of `KvsClient` by connect to a given address of `KvsServer`
+    ///
+    /// The protocol version and optional features are negotiated with the
+    /// server first.
     pub fn new<A: ToSocketAddrs>(addr: A) -> Result<KvsClient> {
         let stream = TcpStream::connect(addr)?;
-        Ok(KvsClient { stream })
+        let mut client = KvsClient {
+            stream,
+            version: PROTOCOL_VERSION,
+            features: Vec::new(),
+        };
+        let request = Request::Hello {
+            version: PROTOCOL_VERSION,
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        };
+        match client.send(&request)? {
+            Response::Hello { version, features } => {
+                client.version = version;
+                client.features = features;
+                Ok(client)
+            }
+            _ => Err(KvsError::UnexpectedCommand),
+        }
     }
 
-    /// Get the value of a given key from the server
+    /// Get the protocol version negotiated with the server
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Get the optional features negotiated with the server, such as
+    /// `FEATURE_SCAN`, whose requests fail with `KvsError::Unsupported`
+    /// if missing
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    /// Get the string value of a given key from the server
+    ///
+    /// Fail with `KvsError::Utf8` if the value is not a valid string.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let request = Request::Get { key };
+        Ok(self.get_bytes(key)?.map(String::from_utf8).transpose()?)
+    }
 
-        self.stream
-            .write_all(serde::to_string(&request)?.as_bytes())?;
-        self.stream.flush()?;
-        self.stream.shutdown(Shutdown::Write)?;
+    /// Set the string value of a given key in the server
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key, value)
+    }
+
+    /// Remove the given key in the server
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key)
+    }
+
+    /// Set the string value of a given key in the server, which expires after `ttl`
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key, value, ttl)
+    }
+
+    /// Make a given key in the server expire after `ttl`
+    pub fn expire(&mut self, key: String, ttl: Duration) -> Result<()> {
+        self.expire_bytes(key, ttl)
+    }
+
+    /// Get the remaining time to live of a given key in the server,
+    /// `None` if the key never expires
+    pub fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key)
+    }
+
+    /// Remove the expiry of a given key in the server
+    pub fn persist(&mut self, key: String) -> Result<()> {
+        self.persist_bytes(key)
+    }
+
+    /// Swap the string value of a given key in the server if it is `expected`,
+    /// where `None` means the key does not exist or is to be removed
+    ///
+    /// Fail with `KvsError::CompareFailed` if the current value doesn't match.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        self.compare_and_swap_bytes(
+            key,
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Set the string value of a given key in the server if it doesn't exist
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<()> {
+        self.set_if_absent_bytes(key, value)
+    }
 
-        let mut buf = String::new();
-        self.stream.read_to_string(&mut buf)?;
-        match serde::from_str(&buf)? {
+    /// Set the string value of a given key in the server if it exists
+    pub fn set_if_present(&mut self, key: String, value: String) -> Result<()> {
+        self.set_if_present_bytes(key, value)
+    }
+
+    /// Get string key-value pairs with keys in `range` from the server, in key order
+    ///
+    /// Fail with `KvsError::Utf8` if any key or value is not a valid string.
+    pub fn scan<R: RangeBounds<String>>(&mut self, range: R) -> Result<Vec<(String, String)>> {
+        let range = (
+            range.start_bound().map(|start| start.clone().into_bytes()),
+            range.end_bound().map(|end| end.clone().into_bytes()),
+        );
+        to_string_pairs(self.scan_bytes(range)?)
+    }
+
+    /// Get string key-value pairs with keys starting with `prefix` from the server, in key order
+    ///
+    /// Fail with `KvsError::Utf8` if any key or value is not a valid string.
+    pub fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        to_string_pairs(self.scan_prefix_bytes(prefix)?)
+    }
+
+    /// Get the value of a given byte string key from the server
+    pub fn get_bytes(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        match self.send(&Request::Get { key: key.into() })? {
             Response::Success { result } => Ok(result),
-            Response::Fail { message } => Err(KvsError::Server(message)),
+            _ => Err(KvsError::UnexpectedCommand),
         }
     }
 
-    /// Set the value of a given key in the server
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let request = Request::Set { key, value };
+    /// Set the value of a given byte string key in the server
+    pub fn set_bytes(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let request = Request::Set {
+            key: key.into(),
+            value: value.into(),
+        };
+        match self.send(&request)? {
+            Response::Success { result: _ } => Ok(()),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
 
-        self.stream
-            .write_all(serde::to_string(&request)?.as_bytes())?;
-        self.stream.flush()?;
-        self.stream.shutdown(Shutdown::Write)?;
+    /// Remove the given byte string key in the server
+    pub fn remove_bytes(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        match self.send(&Request::Rm { key: key.into() })? {
+            Response::Success { result: _ } => Ok(()),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
 
-        let mut buf = String::new();
-        self.stream.read_to_string(&mut buf)?;
-        match serde::from_str(&buf)? {
+    /// Set the value of a given byte string key in the server, which expires after `ttl`
+    pub fn set_with_ttl_bytes(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let request = Request::SetWithTtl {
+            key: key.into(),
+            value: value.into(),
+            ttl_ms: ttl.as_millis() as u64,
+        };
+        match self.send(&request)? {
             Response::Success { result: _ } => Ok(()),
-            Response::Fail { message } => Err(KvsError::Server(message)),
+            _ => Err(KvsError::UnexpectedCommand),
         }
     }
 
-    /// Remove the given key in the server
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let request = Request::Rm { key };
+    /// Make a given byte string key in the server expire after `ttl`
+    pub fn expire_bytes(&mut self, key: impl Into<Vec<u8>>, ttl: Duration) -> Result<()> {
+        let request = Request::Expire {
+            key: key.into(),
+            ttl_ms: ttl.as_millis() as u64,
+        };
+        match self.send(&request)? {
+            Response::Success { result: _ } => Ok(()),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Get the remaining time to live of a given byte string key in the server
+    pub fn ttl_bytes(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>> {
+        match self.send(&Request::Ttl { key: key.into() })? {
+            Response::Ttl { ttl_ms } => Ok(ttl_ms.map(Duration::from_millis)),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Remove the expiry of a given byte string key in the server
+    pub fn persist_bytes(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        match self.send(&Request::Persist { key: key.into() })? {
+            Response::Success { result: _ } => Ok(()),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Swap the value of a given byte string key in the server if it is `expected`
+    pub fn compare_and_swap_bytes(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let request = Request::CompareAndSwap {
+            key: key.into(),
+            expected,
+            new,
+        };
+        match self.send(&request)? {
+            Response::Success { result: _ } => Ok(()),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Set the value of a given byte string key in the server if it doesn't exist
+    pub fn set_if_absent_bytes(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        let request = Request::SetIfAbsent {
+            key: key.into(),
+            value: value.into(),
+        };
+        match self.send(&request)? {
+            Response::Success { result: _ } => Ok(()),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Set the value of a given byte string key in the server if it exists
+    pub fn set_if_present_bytes(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        let request = Request::SetIfPresent {
+            key: key.into(),
+            value: value.into(),
+        };
+        match self.send(&request)? {
+            Response::Success { result: _ } => Ok(()),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Apply writes of a batch atomically in the server, in one round trip
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let request = Request::Batch {
+            ops: batch.into_ops(),
+        };
+        match self.send(&request)? {
+            Response::Success { result: _ } => Ok(()),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
 
-        self.stream
-            .write_all(serde::to_string(&request)?.as_bytes())?;
+    /// Get key-value pairs with keys in `range` from the server, in key order
+    pub fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &mut self,
+        range: R,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        // the protocol takes an inclusive start and an exclusive end,
+        // and a key followed by `\0` is the next key of it
+        let start = match range.start_bound() {
+            Bound::Included(start) => start.clone(),
+            Bound::Excluded(start) => [start.as_slice(), b"\0"].concat(),
+            Bound::Unbounded => Vec::new(),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => Some([end.as_slice(), b"\0"].concat()),
+            Bound::Excluded(end) if end.is_empty() => return Ok(Vec::new()),
+            Bound::Excluded(end) => Some(end.clone()),
+            Bound::Unbounded => None,
+        };
+        match self.send(&Request::Scan { start, end })? {
+            Response::Pairs { pairs } => Ok(pairs),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Get key-value pairs with keys starting with `prefix` from the server, in key order
+    pub fn scan_prefix_bytes(
+        &mut self,
+        prefix: impl Into<Vec<u8>>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = Request::ScanPrefix {
+            prefix: prefix.into(),
+        };
+        match self.send(&request)? {
+            Response::Pairs { pairs } => Ok(pairs),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Compact the store of the server
+    pub fn compact(&mut self) -> Result<()> {
+        match self.send(&Request::Compact)? {
+            Response::Success { result: _ } => Ok(()),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Write a consistent copy of the store into `path` on the server
+    ///
+    /// `path` is relative to the backup directory of the server, which
+    /// offers `FEATURE_BACKUP` only if it has one.
+    pub fn backup_to(&mut self, path: String) -> Result<()> {
+        match self.send(&Request::Backup { path })? {
+            Response::Success { result: _ } => Ok(()),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Replace every key of the store by the copy in `path` on the server,
+    /// relative to its backup directory
+    pub fn restore_from(&mut self, path: String) -> Result<()> {
+        match self.send(&Request::Restore { path })? {
+            Response::Success { result: _ } => Ok(()),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Send several requests at once, reading their responses in order meanwhile
+    ///
+    /// A request failing on the server doesn't stop the others, and its
+    /// response is turned into the error it stands for.
+    pub fn pipeline(&mut self, requests: &[Request]) -> Result<Vec<Result<Response>>> {
+        let mut bytes = Vec::new();
+        for request in requests {
+            serde::to_writer(&mut bytes, request)?;
+        }
+
+        // the server stops reading requests while its responses aren't read,
+        // so the requests are written by another thread meanwhile
+        let mut writer = self.stream.try_clone()?;
+        thread::scope(|scope| {
+            let sending = scope.spawn(move || writer.write_all(&bytes));
+            let received = requests
+                .iter()
+                .map(|_| Ok(into_result(self.receive()?.ok_or_else(closed)?)))
+                .collect::<Result<Vec<_>>>();
+            if received.is_err() {
+                // wake the writer up if the server is no longer reading
+                let _ = self.stream.shutdown(Shutdown::Both);
+            }
+            let sent = sending.join().expect("pipeline writer panicked");
+            let responses = received?;
+            sent?;
+            Ok(responses)
+        })
+    }
+
+    /// Begin an optimistic transaction on the server
+    ///
+    /// The transaction keeps the connection of the client until it ends.
+    pub fn begin(mut self) -> Result<KvsClientTransaction> {
+        match self.send(&Request::Begin)? {
+            Response::Success { result: _ } => Ok(KvsClientTransaction { client: self }),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Watch writes of keys starting with `prefix` in the server
+    ///
+    /// The watch keeps the connection of the client, and blocks on it for
+    /// each event.
+    pub fn watch_prefix(mut self, prefix: impl Into<Vec<u8>>) -> Result<KvsClientWatch> {
+        let request = Request::Watch {
+            prefix: prefix.into(),
+        };
+        match self.send(&request)? {
+            Response::Success { result: _ } => Ok(KvsClientWatch { client: self }),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Send a request and read its response
+    fn send(&mut self, request: &Request) -> Result<Response> {
+        serde::to_writer(&mut self.stream, request)?;
         self.stream.flush()?;
-        self.stream.shutdown(Shutdown::Write)?;
+        into_result(self.receive()?.ok_or_else(closed)?)
+    }
+
+    /// Read the next response, `None` if the server has closed the connection
+    fn receive(&mut self) -> Result<Option<Response>> {
+        serde::from_reader(&mut self.stream)
+    }
+}
+
+/// Optimistic transaction on a server, returned by `KvsClient::begin`
+///
+/// Dropping it without `commit` aborts the transaction.
+pub struct KvsClientTransaction {
+    client: KvsClient,
+}
 
-        let mut buf = String::new();
-        self.stream.read_to_string(&mut buf)?;
-        match serde::from_str(&buf)? {
+impl KvsClientTransaction {
+    /// Abort the transaction, discarding its writes
+    pub fn abort(mut self) -> Result<()> {
+        match self.send(&Request::Abort)? {
             Response::Success { result: _ } => Ok(()),
-            Response::Fail { message } => Err(KvsError::Server(message)),
+            _ => Err(KvsError::UnexpectedCommand),
         }
     }
+
+    fn send(&mut self, request: &Request) -> Result<Response> {
+        self.client.send(request)
+    }
+}
+
+impl KvsTransaction for KvsClientTransaction {
+    fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        match self.send(&Request::Get { key: key.into() })? {
+            Response::Success { result } => Ok(result),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let request = Request::Set {
+            key: key.into(),
+            value: value.into(),
+        };
+        match self.send(&request)? {
+            Response::Success { result: _ } => Ok(()),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        match self.send(&Request::Rm { key: key.into() })? {
+            Response::Success { result: _ } => Ok(()),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    fn commit(mut self) -> Result<()> {
+        match self.send(&Request::Commit)? {
+            Response::Success { result: _ } => Ok(()),
+            _ => Err(KvsError::UnexpectedCommand),
+        }
+    }
+}
+
+/// Events of keys watched on a server, returned by `KvsClient::watch_prefix`
+///
+/// It ends when the server closes the connection.
+pub struct KvsClientWatch {
+    client: KvsClient,
+}
+
+impl Iterator for KvsClientWatch {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.client.receive() {
+            Ok(Some(Response::Event { event })) => Some(Ok(event)),
+            Ok(Some(_)) => Some(Err(KvsError::UnexpectedCommand)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Error of a connection closed by the server before a response
+fn closed() -> KvsError {
+    KvsError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Connection closed by the server",
+    ))
+}
+
+/// Turn a fail response into the error it stands for
+fn into_result(response: Response) -> Result<Response> {
+    match response {
+        Response::Fail { code, message } => Err(code.into_error(message)),
+        Response::CompareFailed { current } => Err(KvsError::CompareFailed { current }),
+        Response::TransactionConflict => Err(KvsError::TransactionConflict),
+        Response::Unsupported { what } => Err(KvsError::Unsupported(what)),
+        response => Ok(response),
+    }
+}
+
+/// Decode byte string key-value pairs into strings
+fn to_string_pairs(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<(String, String)>> {
+    pairs
+        .into_iter()
+        .map(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)))
+        .collect()
 }
@@ -1,6 +1,8 @@
 use serde::{de, ser};
 use serde_json::error;
 use std::io;
+use std::path::PathBuf;
+use std::string::FromUtf8Error;
 use thiserror::Error;
 
 /// Alias for a `Result` with the error type `kvs::KvsError`.
@@ -39,6 +41,32 @@ pub enum KvsError {
     /// Unexpected command
     #[error("Unexpected command")]
     UnexpectedCommand,
+    /// Current value of a conditional write does not match the expected one
+    #[error("Compare failed, current value: {current:?}")]
+    CompareFailed {
+        /// Current value of the key, `None` if the key does not exist
+        current: Option<Vec<u8>>,
+    },
+    /// A key read by an optimistic transaction has changed before it commits
+    #[error("Transaction conflict, a key read by it has changed")]
+    TransactionConflict,
+    /// The peer has not negotiated a protocol version, feature or request
+    #[error("Unsupported by the peer: {0}")]
+    Unsupported(String),
+    /// Log record fails the checksum or is truncated
+    #[error("Corrupted record in {path:?} at offset {offset}")]
+    CorruptedRecord {
+        /// Log file containing the record
+        path: PathBuf,
+        /// Offset of the record in the log file
+        offset: u64,
+    },
+    /// Unknown format version of log files
+    #[error("Unsupported log format version: {0}")]
+    UnsupportedFormat(String),
+    /// Bytes read as a string are not valid UTF-8
+    #[error("Invalid UTF-8 string: {0}")]
+    Utf8(#[from] FromUtf8Error),
     /// `rayon::ThreadPool` building error
     #[error("`rayon::ThreadPool` building error: {0:?}")]
     RayonError(#[from] rayon::ThreadPoolBuildError),
@@ -0,0 +1,462 @@
+//! HTTP/1.1 gateway with JSON bodies
+//!
+//! - `GET /keys/{key}` answers `{"key": .., "value": ..}`
+//! - `PUT /keys/{key}` takes `{"value": .., "ttl_ms": ..}`, where `ttl_ms`
+//!   is optional
+//! - `DELETE /keys/{key}` removes the key
+//! - `GET /keys?prefix=..&limit=..&cursor=..` answers
+//!   `{"pairs": [{"key": .., "value": ..}], "next_cursor": ..}` in key order,
+//!   where every parameter is optional. At most `limit` pairs are answered,
+//!   1000 by default, and `next_cursor` is given to the next request while
+//!   more pairs are left
+//! - `GET /stats` answers the `KvsStats` of the engine
+//! - `GET /health` answers `{"status": "ok"}`
+//!
+//! Keys are percent-decoded from the path. A key or value which is not
+//! UTF-8 doesn't fit in a JSON string, so such a pair is answered with both
+//! in base64 and `"encoding": "base64"`, which `PUT` takes as well. Errors
+//! are answered by `{"error": ..}`.
+
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{Shutdown, TcpStream},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use slog::{info, Logger};
+
+use crate::{KvsEngine, KvsError, Result};
+
+/// Longest request line or header line
+const MAX_LINE_SIZE: u64 = 8 * 1024;
+/// Most header lines of a request
+const MAX_HEADERS: usize = 100;
+/// Largest request body
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+/// Pairs answered by `GET /keys` without a `limit`
+const DEFAULT_KEYS_LIMIT: usize = 1000;
+/// Largest `limit` of `GET /keys`
+const MAX_KEYS_LIMIT: usize = 10_000;
+/// Encoding of keys and values which are not UTF-8
+const BASE64: &str = "base64";
+
+struct Request {
+    method: String,
+    target: String,
+    body: Vec<u8>,
+    /// Whether the client keeps the connection for more requests
+    keep_alive: bool,
+}
+
+/// Response with a JSON body
+struct Response {
+    status: u16,
+    body: Value,
+    /// Methods of the resource, sent with `405 Method Not Allowed`
+    allow: Option<&'static str>,
+}
+
+impl Response {
+    fn ok(body: Value) -> Response {
+        Response {
+            status: 200,
+            body,
+            allow: None,
+        }
+    }
+
+    fn no_content() -> Response {
+        Response {
+            status: 204,
+            body: Value::Null,
+            allow: None,
+        }
+    }
+
+    fn error(status: u16, message: impl ToString) -> Response {
+        Response {
+            status,
+            body: json!({ "error": message.to_string() }),
+            allow: None,
+        }
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Response {
+        Response {
+            allow: Some(allow),
+            ..Response::error(405, "method not allowed")
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, keep_alive: bool) -> Result<()> {
+        let body = match self.body {
+            Value::Null => Vec::new(),
+            ref body => serde_json::to_vec(body)?,
+        };
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+            self.status,
+            reason(self.status),
+            body.len()
+        )?;
+        if let Some(allow) = self.allow {
+            write!(writer, "Allow: {}\r\n", allow)?;
+        }
+        if !keep_alive {
+            writer.write_all(b"Connection: close\r\n")?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&body)?;
+        Ok(())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Body of `PUT /keys/{key}`
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+    #[serde(default)]
+    ttl_ms: Option<u64>,
+    /// `"base64"` if `value` is encoded
+    #[serde(default)]
+    encoding: Option<String>,
+}
+
+/// Serve HTTP requests of a connection until either side closes it
+///
+/// A malformed request is answered by `400 Bad Request`, which closes the
+/// connection since the next request can not be found.
+pub(crate) fn handler<E: KvsEngine>(engine: E, stream: TcpStream, logger: &Logger) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(KvsError::Deserialize(e)) => {
+                Response::error(400, e).write_to(&mut writer, false)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        info!(
+            logger,
+            "Recieved HTTP: {} {}", request.method, request.target
+        );
+
+        let response = route(&engine, &request).unwrap_or_else(|e| match e {
+            KvsError::KeyNotFound => Response::error(404, e),
+            KvsError::Json(_) => Response::error(400, e),
+            e => Response::error(500, e),
+        });
+        response.write_to(&mut writer, request.keep_alive)?;
+        if !request.keep_alive {
+            break;
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    stream.shutdown(Shutdown::Write)?;
+    Ok(())
+}
+
+/// Read the next request with its body, `None` if the client has left
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method.to_owned(), target.to_owned(), version.to_owned())
+        }
+        _ => return Err(bad_request("malformed request line")),
+    };
+
+    let mut length = 0;
+    let mut keep_alive = version != "HTTP/1.0";
+    for _ in 0..=MAX_HEADERS {
+        let line = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        if line.is_empty() {
+            if length > MAX_BODY_SIZE {
+                return Err(bad_request("body too large"));
+            }
+            // grow with the bytes read rather than trusting the length up front
+            let mut body = Vec::new();
+            if reader.by_ref().take(length as u64).read_to_end(&mut body)? < length {
+                return Err(unexpected_eof());
+            }
+            return Ok(Some(Request {
+                method,
+                target,
+                body,
+                keep_alive,
+            }));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| bad_request("malformed header"))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                length = value
+                    .parse()
+                    .map_err(|_| bad_request("invalid Content-Length"))?;
+            }
+            "transfer-encoding" => {
+                return Err(bad_request("Transfer-Encoding is not supported"));
+            }
+            "connection" if value.eq_ignore_ascii_case("close") => keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => keep_alive = true,
+            _ => {}
+        }
+    }
+    Err(bad_request("too many headers"))
+}
+
+/// Read a line without its line ending, `None` at the end of the stream
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_SIZE)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let len = match line.strip_suffix(b"\n") {
+        Some(rest) => rest.strip_suffix(b"\r").unwrap_or(rest).len(),
+        None if line.len() as u64 == MAX_LINE_SIZE => return Err(bad_request("line too long")),
+        None => return Err(unexpected_eof()),
+    };
+    line.truncate(len);
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| bad_request("invalid UTF-8 in head"))
+}
+
+fn bad_request(message: &str) -> KvsError {
+    KvsError::Deserialize(message.to_owned())
+}
+
+fn unexpected_eof() -> KvsError {
+    KvsError::Io(io::Error::from(io::ErrorKind::UnexpectedEof))
+}
+
+/// Answer a request from the store engine
+fn route<E: KvsEngine>(engine: &E, request: &Request) -> Result<Response> {
+    let (path, query) = match request.target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (request.target.as_str(), ""),
+    };
+    let method = request.method.as_str();
+
+    if let Some(key) = path.strip_prefix("/keys/") {
+        let key = match percent_decode(key, false) {
+            Some(key) => key,
+            None => return Ok(Response::error(400, "invalid percent-encoding of key")),
+        };
+        return match method {
+            "GET" => {
+                let value = engine.get(key.clone())?.ok_or(KvsError::KeyNotFound)?;
+                Ok(Response::ok(pair_to_json(key, value)))
+            }
+            "PUT" => {
+                let body: PutBody = serde_json::from_slice(&request.body)?;
+                let value = match body.encoding.as_deref() {
+                    None => body.value.into_bytes(),
+                    Some(BASE64) => match base64_decode(&body.value) {
+                        Some(value) => value,
+                        None => return Ok(Response::error(400, "invalid base64 of value")),
+                    },
+                    Some(_) => return Ok(Response::error(400, "unknown encoding")),
+                };
+                match body.ttl_ms {
+                    Some(ttl_ms) => {
+                        engine.set_with_ttl(key, value, Duration::from_millis(ttl_ms))?
+                    }
+                    None => engine.set(key, value)?,
+                }
+                Ok(Response::no_content())
+            }
+            "DELETE" => {
+                engine.remove(key)?;
+                Ok(Response::no_content())
+            }
+            _ => Ok(Response::method_not_allowed("GET, PUT, DELETE")),
+        };
+    }
+
+    match (method, path) {
+        ("GET", "/keys") => list_keys(engine, query),
+        ("GET", "/stats") => Ok(Response::ok(serde_json::to_value(engine.engine_stats()?)?)),
+        ("GET", "/health") => Ok(Response::ok(json!({ "status": "ok" }))),
+        (_, "/keys" | "/stats" | "/health") => Ok(Response::method_not_allowed("GET")),
+        _ => Ok(Response::error(404, "not found")),
+    }
+}
+
+/// `GET /keys`, answering pairs from `cursor` on, which is the first key
+/// not answered yet
+fn list_keys<E: KvsEngine>(engine: &E, query: &str) -> Result<Response> {
+    let mut prefix = Vec::new();
+    let mut cursor = Vec::new();
+    let mut limit = DEFAULT_KEYS_LIMIT;
+    for pair in query.split('&') {
+        let (name, value) = match pair.split_once('=') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        match name {
+            "prefix" | "cursor" => {
+                let value = match percent_decode(value, true) {
+                    Some(value) => value,
+                    None => return Ok(Response::error(400, "invalid percent-encoding")),
+                };
+                match name {
+                    "prefix" => prefix = value,
+                    _ => cursor = value,
+                }
+            }
+            "limit" => {
+                limit = match value.parse() {
+                    Ok(limit) if limit > 0 && limit <= MAX_KEYS_LIMIT => limit,
+                    _ => return Ok(Response::error(400, "invalid limit")),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    let start = cursor.max(prefix.clone());
+    let mut pairs = Vec::new();
+    let mut next_cursor = Value::Null;
+    for pair in engine.scan(start..)? {
+        let (key, value) = pair?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        if pairs.len() == limit {
+            next_cursor = Value::String(percent_encode(&key));
+            break;
+        }
+        pairs.push(pair_to_json(key, value));
+    }
+    Ok(Response::ok(
+        json!({ "pairs": pairs, "next_cursor": next_cursor }),
+    ))
+}
+
+/// JSON of a pair, in base64 if the key or value is not UTF-8
+fn pair_to_json(key: Vec<u8>, value: Vec<u8>) -> Value {
+    match (String::from_utf8(key), String::from_utf8(value)) {
+        (Ok(key), Ok(value)) => json!({ "key": key, "value": value }),
+        (key, value) => {
+            let key = key.map_or_else(|e| e.into_bytes(), String::into_bytes);
+            let value = value.map_or_else(|e| e.into_bytes(), String::into_bytes);
+            json!({
+                "key": base64_encode(&key),
+                "value": base64_encode(&value),
+                "encoding": BASE64,
+            })
+        }
+    }
+}
+
+/// Escape every byte but unreserved ones as `%XX`, to fit in a query
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+/// Digits of the standard base64 alphabet
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode in the standard base64 alphabet with padding
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | ((b as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decode the standard base64 alphabet with padding
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(s.len() / 4 * 3);
+    for (i, chunk) in s.chunks(4).enumerate() {
+        let last = i == s.len() / 4 - 1;
+        let pad = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if pad > 2 || (pad > 0 && !last) {
+            return None;
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - pad] {
+            let digit = BASE64_ALPHABET.iter().position(|&a| a == c)?;
+            n = (n << 6) | digit as u32;
+        }
+        n <<= 6 * pad;
+        decoded.extend_from_slice(&n.to_be_bytes()[1..4 - pad]);
+    }
+    Some(decoded)
+}
+
+/// Decode `%XX` escapes, and `+` as a space in a query
+fn percent_decode(s: &str, plus_as_space: bool) -> Option<Vec<u8>> {
+    let mut bytes = s.bytes();
+    let mut decoded = Vec::with_capacity(s.len());
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                let hex = std::str::from_utf8(&hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+    }
+    Some(decoded)
+}
@@ -0,0 +1,139 @@
+//! Group commit of concurrent writers
+//!
+//! Writers push their item into a shared queue. The first writer finding no
+//! leader becomes the leader, takes every pending item and commits them at
+//! once, then wakes all waiters with their own results. Items arriving while
+//! a leader is committing form the next group.
+
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+
+pub(super) struct GroupCommit<T, R> {
+    queue: Mutex<Queue<T, R>>,
+    cond: Condvar,
+}
+
+struct Queue<T, R> {
+    pending: Vec<(u64, T)>,
+    // `None` for the items of a group whose leader panicked
+    done: HashMap<u64, Option<R>>,
+    next_ticket: u64,
+    leading: bool,
+}
+
+/// Hands the results of a group to its writers, and the lead to the next
+/// group, when the leader finishes or unwinds out of `commit`
+struct Leading<'a, T, R> {
+    group: &'a GroupCommit<T, R>,
+    tickets: Vec<u64>,
+    results: Option<Vec<R>>,
+}
+
+impl<T, R> Drop for Leading<'_, T, R> {
+    fn drop(&mut self) {
+        // the queue is never locked while committing, so it isn't poisoned
+        let mut queue = self.group.queue.lock().unwrap();
+        queue.leading = false;
+        let mut results = self.results.take().into_iter().flatten();
+        for ticket in self.tickets.drain(..) {
+            queue.done.insert(ticket, results.next());
+        }
+        self.group.cond.notify_all();
+    }
+}
+
+impl<T, R> GroupCommit<T, R> {
+    pub fn new() -> Self {
+        GroupCommit {
+            queue: Mutex::new(Queue {
+                pending: Vec::new(),
+                done: HashMap::new(),
+                next_ticket: 0,
+                leading: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Commit `item` together with other pending items
+    ///
+    /// `commit` is called by the leader with a group of items, and must
+    /// return one result for each item in the same order. If it panics, the
+    /// leader unwinds and every other writer of its group gets `None`.
+    pub fn commit<F>(&self, item: T, commit: F) -> Option<R>
+    where
+        F: Fn(Vec<T>) -> Vec<R>,
+    {
+        let mut queue = self.queue.lock().unwrap();
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.pending.push((ticket, item));
+
+        loop {
+            if let Some(result) = queue.done.remove(&ticket) {
+                return result;
+            }
+            if !queue.leading {
+                queue.leading = true;
+                let (tickets, items): (Vec<u64>, Vec<T>) =
+                    std::mem::take(&mut queue.pending).into_iter().unzip();
+                drop(queue);
+
+                let mut leading = Leading {
+                    group: self,
+                    tickets,
+                    results: None,
+                };
+                leading.results = Some(commit(items));
+                drop(leading);
+
+                queue = self.queue.lock().unwrap();
+                continue;
+            }
+            queue = self.cond.wait(queue).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GroupCommit;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn panicking_leader() {
+        let group = Arc::new(GroupCommit::new());
+        let commit = |items: Vec<u32>| {
+            if items == [0] {
+                // let the others queue up behind as the next group
+                thread::sleep(Duration::from_millis(200));
+            } else if items.contains(&1) {
+                panic!("commit failed");
+            }
+            items
+        };
+
+        let first = {
+            let group = group.clone();
+            thread::spawn(move || group.commit(0, commit))
+        };
+        thread::sleep(Duration::from_millis(50));
+        let others: Vec<_> = (1..=2)
+            .map(|item| {
+                let group = group.clone();
+                thread::spawn(move || group.commit(item, commit))
+            })
+            .collect();
+
+        assert_eq!(first.join().unwrap(), Some(0));
+        let mut results: Vec<_> = others.into_iter().map(|t| t.join()).collect();
+        // the leader unwinds, and the follower learns its item failed
+        results.sort_by_key(|r| r.is_ok());
+        assert!(results[0].is_err());
+        assert_eq!(results[1].as_ref().ok(), Some(&None));
+        // and the next writer can lead again
+        assert_eq!(group.commit(3, commit), Some(3));
+    }
+}
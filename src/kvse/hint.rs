@@ -0,0 +1,139 @@
+//! Hint files of `KvStore` blocks
+//!
+//! A hint file `N.hint` is written beside every block `N.log` produced by
+//! compaction. It holds the key, kind and position of every record in the
+//! block, so the index could be loaded without reading values.
+//!
+//! ```text
+//! +------------+-----------+
+//! | entries... | crc32 u32 |
+//! +------------+-----------+
+//! entry: | tag u8 | key_len u32 | key | position u64 | size u64 | expire_at u64 |
+//! ```
+//!
+//! `expire_at` is only written for records of expiring keys.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::Result;
+
+const TAG_SET: u8 = 1;
+const TAG_RM: u8 = 2;
+const TAG_SET_EXPIRING: u8 = 3;
+
+/// A record of a block recorded in hint file
+pub(super) struct HintEntry {
+    pub key: Vec<u8>,
+    /// Whether the record removes the key
+    pub removed: bool,
+    pub position: u64,
+    pub size: u64,
+    /// When the key expires, in milliseconds since the unix epoch
+    pub expire_at: Option<u64>,
+}
+
+/// Get the path of the hint file of `block` in a generation directory
+pub(super) fn hint_path(dir: &Path, block: u64) -> PathBuf {
+    dir.join(format!("{}.hint", block))
+}
+
+/// Write the hint file of `block` into a generation directory
+///
+/// The file is written aside and renamed, so a crash never leaves a partial hint.
+pub(super) fn write_hint(dir: &Path, block: u64, entries: &[HintEntry]) -> Result<()> {
+    let path = hint_path(dir, block);
+    let tmp_path = path.with_extension("hint.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut put = |buf: &[u8]| -> Result<()> {
+        hasher.update(buf);
+        writer.write_all(buf)?;
+        Ok(())
+    };
+
+    for entry in entries {
+        let tag = match (entry.removed, entry.expire_at) {
+            (true, _) => TAG_RM,
+            (false, None) => TAG_SET,
+            (false, Some(_)) => TAG_SET_EXPIRING,
+        };
+        put(&[tag])?;
+        put(&(entry.key.len() as u32).to_le_bytes())?;
+        put(&entry.key)?;
+        put(&entry.position.to_le_bytes())?;
+        put(&entry.size.to_le_bytes())?;
+        if let Some(expire_at) = entry.expire_at {
+            put(&expire_at.to_le_bytes())?;
+        }
+    }
+
+    writer.write_all(&hasher.finalize().to_le_bytes())?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Read the hint file of `block` in a generation directory
+///
+/// Return `None` if there is no hint file or it fails the checksum.
+pub(super) fn read_hint(dir: &Path, block: u64) -> Result<Option<Vec<HintEntry>>> {
+    let path = hint_path(dir, block);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let buf = fs::read(path)?;
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let (content, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(content).to_le_bytes() != crc {
+        return Ok(None);
+    }
+    Ok(parse_hint(content))
+}
+
+fn parse_hint(mut buf: &[u8]) -> Option<Vec<HintEntry>> {
+    let mut entries = Vec::new();
+    while !buf.is_empty() {
+        let tag = take(&mut buf, 1)?[0];
+        let removed = match tag {
+            TAG_SET | TAG_SET_EXPIRING => false,
+            TAG_RM => true,
+            _ => return None,
+        };
+        let len = u32::from_le_bytes(take(&mut buf, 4)?.try_into().unwrap()) as usize;
+        let key = take(&mut buf, len)?.to_vec();
+        let position = get_u64(&mut buf)?;
+        let size = get_u64(&mut buf)?;
+        let expire_at = match tag {
+            TAG_SET_EXPIRING => Some(get_u64(&mut buf)?),
+            _ => None,
+        };
+        entries.push(HintEntry {
+            key,
+            removed,
+            position,
+            size,
+            expire_at,
+        });
+    }
+    Some(entries)
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Some(head)
+}
+
+fn get_u64(buf: &mut &[u8]) -> Option<u64> {
+    take(buf, 8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+}
@@ -1,128 +1,531 @@
-use evmap::{ReadHandle, ShallowCopy, WriteHandle};
-use serde::{Deserialize, Serialize};
+use crossbeam_skiplist::SkipMap;
+use slog::{o, warn, Logger};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, remove_dir_all, File};
-use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::mem::ManuallyDrop;
-use std::ops::Deref;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::{Bound, Deref, RangeBounds};
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender, SyncSender};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+use super::group_commit::GroupCommit;
+use super::hint::{self, HintEntry};
+use super::record::{self, Command, LegacyCommand, ReadRecord};
 use crate::err::KvsError;
-use crate::KvsEngine;
+use crate::kvse::{
+    now_millis, BatchOp, KeyIter, KvsStats, ScanIter, WatchEvent, WatchIter, WriteBatch,
+};
 use crate::Result;
+use crate::SyncPolicy;
+use crate::{KvsEngine, KvsSnapshot, KvsTransaction};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const COMPACTION_RATIO: f64 = 0.5;
 const BLOCK_THRESHOLD: u64 = 1024 * 1024 * 256;
+/// Directory holding the copy of the backup of a restore in progress
+const RESTORE_MARKER: &str = "RESTORE";
+/// Events a watcher may fall behind by before it is dropped
+const WATCH_CAPACITY: usize = 1024;
 
 /// The `KvStore` stores key-value pairs
 ///
-/// Key-value pairs are stored in memory by `HashMap` and not persisted in disk.
+/// Writes are appended to log blocks on disk, and a `SkipMap` index keeps
+/// the position of the latest record of every key, in key order.
 /// - Support concorrent access with lock-free read operation
+/// - Reclaim space of stale records by compaction in the background
 ///
 /// Example:
 ///
 /// ```rust
-/// # use kvs::KvStore;
-/// let store = KvStore::new();
-/// store.set(String::from("key"), String::from("value"));
-/// let val = store.get(String::from("key"));
-/// assert_eq!(val, Some(String::from("value")));
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn main() -> Result<()> {
+/// let store = KvStore::open("./data")?;
+/// store.set("key", "value")?;
+/// let val = store.get("key")?;
+/// assert_eq!(val, Some(b"value".to_vec()));
+/// # Ok(())
+/// # }
 /// ```
 #[derive(Clone)]
 pub struct KvStore {
     writer: Arc<Mutex<KvStoreWriter>>,
     reader: KvStoreReader,
     path: PathBuf,
+    group: Option<Arc<GroupCommit<Command, Result<Position>>>>,
+    /// Dropped after the writer, so the last handle waits for a running
+    /// compaction, which may hold the writer meanwhile
+    _compactor: Arc<Compactor>,
 }
 
-/// `KvStoreReader` hold the read handle of index
-/// which could shared by thread with `clone()` method
+/// Options to open a `KvStore`
 #[derive(Clone)]
-struct KvStoreReader(ReadHandle<String, Position>);
+pub struct KvStoreOptions {
+    /// Logger for recovery and maintenance messages
+    pub logger: Logger,
+    /// When written logs are synced to disk, `SyncPolicy::Never` by default
+    pub sync: SyncPolicy,
+    /// Minimum dead bytes to trigger a compaction, 1 MiB by default
+    pub compaction_threshold: u64,
+    /// Minimum ratio of dead bytes to total bytes to trigger a compaction, 0.5 by default
+    pub compaction_ratio: f64,
+    /// Minimum interval between the start of two compactions, zero by default
+    pub compaction_interval: Duration,
+    /// Size of a log block before a new one is created, 256 MiB by default
+    pub block_threshold: u64,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            logger: Logger::root(slog::Discard, o!()),
+            sync: SyncPolicy::Never,
+            compaction_threshold: COMPACTION_THRESHOLD,
+            compaction_ratio: COMPACTION_RATIO,
+            compaction_interval: Duration::ZERO,
+            block_threshold: BLOCK_THRESHOLD,
+        }
+    }
+}
+
+/// Space usage of a `KvStore`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KvStoreStats {
+    /// Number of live keys
+    pub live_keys: u64,
+    /// Bytes of live records
+    pub live_bytes: u64,
+    /// Bytes of overwritten or removed records, which compaction reclaims
+    pub dead_bytes: u64,
+    /// Generation of the store directory
+    pub generation: u64,
+    /// Number of log blocks
+    pub blocks: u64,
+}
 
-/// `KvStoreWriter` hold the write handle of index
+/// `KvStoreReader` hold the ordered index, which is lock-free to read
+/// and could shared by thread with `clone()` method
+#[derive(Clone)]
+struct KvStoreReader {
+    index: Arc<SkipMap<Vec<u8>, Position>>,
+    /// Held exclusively while the writes of a batch are applied to the index,
+    /// so that lookups of `get` see all of them or none
+    batch: Arc<RwLock<()>>,
+}
+
+/// `KvStoreWriter` hold the log files and update the index
 /// Only synchronous access
 struct KvStoreWriter {
     writer: BufWriter<File>,
     current_block: u64,
     uncompacted: u64,
+    total: u64,
+    /// Size and dead bytes of every block
+    blocks: BTreeMap<u64, BlockStat>,
     gen: u64,
-    index: WriteHandle<String, Position>,
     reader: KvStoreReader,
     path: PathBuf,
+    sync: SyncPolicy,
+    unsynced: bool,
+    compaction_threshold: u64,
+    compaction_ratio: f64,
+    compaction_interval: Duration,
+    block_threshold: u64,
+    last_compaction: Option<Instant>,
+    compacting: bool,
+    compaction: Option<Sender<CompactionRequest>>,
+    pins: Arc<Mutex<Pins>>,
+    /// Version of the latest write
+    version: u64,
+    /// Watched prefixes and where to send their events
+    watchers: Vec<(Vec<u8>, SyncSender<WatchEvent>)>,
+    /// Index entries replaced since snapshots being copied were taken, by
+    /// the id of every such snapshot
+    copies: HashMap<u64, Undo>,
+    next_copy: u64,
 }
 
 impl Deref for KvStoreReader {
-    type Target = ReadHandle<String, Position>;
+    type Target = SkipMap<Vec<u8>, Position>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.index
     }
 }
 
 impl KvStoreReader {
-    fn get(&self, key: String, path: &Path) -> Result<Option<String>> {
-        match self.0.get_one(&key) {
-            Some(pos) => {
-                let buf = read_string_from(
-                    path.join(get_file_path(pos.gen, pos.file)),
-                    pos.position,
-                    pos.size,
-                )?;
-
-                match serde_json::from_str(&buf).unwrap() {
-                    Command::Set { key: _, value } => Ok(Some(value)),
-                    Command::Rm { key: _ } => unreachable!(),
+    /// Get the position of the latest record of `key`
+    fn position(&self, key: &[u8]) -> Option<Position> {
+        self.index.get(key).map(|entry| *entry.value())
+    }
+
+    /// Get the position of the latest record of `key` unless it is expired
+    fn live_position(&self, key: &[u8]) -> Option<Position> {
+        let pos = {
+            let _batch = self.batch.read().unwrap();
+            self.position(key)
+        };
+        pos.filter(|pos| !pos.is_expired(now_millis()))
+    }
+
+    fn get(&self, key: &[u8], path: &Path) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_with_position(key, path)?.map(|(_, value)| value))
+    }
+
+    /// Get the value of `key` with the position it is read at
+    fn get_with_position(&self, key: &[u8], path: &Path) -> Result<Option<(Position, Vec<u8>)>> {
+        loop {
+            let pos = match self.live_position(key) {
+                Some(pos) => pos,
+                None => return Ok(None),
+            };
+            let file_path = path.join(get_file_path(pos.gen, pos.file));
+            let buf = match read_bytes_from(&file_path, pos.position, pos.size) {
+                Ok(buf) => buf,
+                // the block is deleted by compaction after the record is moved
+                Err(KvsError::Io(e))
+                    if e.kind() == io::ErrorKind::NotFound && self.position(key) != Some(pos) =>
+                {
+                    continue
                 }
+                Err(e) => return Err(e),
+            };
+
+            return decode_value(&buf, file_path, &pos).map(|value| Some((pos, value)));
+        }
+    }
+}
+
+/// Iterator of a `KvStore` scan
+///
+/// Every step looks up the next key after the last one in the index,
+/// so it holds no borrow of the index between steps.
+struct KvStoreScan {
+    reader: KvStoreReader,
+    path: PathBuf,
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    prefix: Option<Vec<u8>>,
+}
+
+impl KvStoreScan {
+    /// Move on to the next key of the index in range, live or not
+    fn next_key(&mut self) -> Option<Vec<u8>> {
+        let range = (
+            self.next.as_ref().map(Vec::as_slice),
+            self.end.as_ref().map(Vec::as_slice),
+        );
+        let key = self.reader.range::<[u8], _>(range).next()?.key().clone();
+        if let Some(prefix) = &self.prefix {
+            if !key.starts_with(prefix) {
+                self.end = Bound::Excluded(key);
+                return None;
+            }
+        }
+        self.next = Bound::Excluded(key.clone());
+        Some(key)
+    }
+}
+
+impl Iterator for KvStoreScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.next_key()?;
+            // the key may be removed since it is found
+            match self.reader.get(&key, &self.path) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Iterator of live keys of a `KvStore`, which reads no value
+struct KvStoreKeys(KvStoreScan);
+
+impl Iterator for KvStoreKeys {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.0.next_key()?;
+            if self.0.reader.live_position(&key).is_some() {
+                return Some(Ok(key));
             }
-            None => Ok(None),
         }
     }
 }
 
 impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let position = self.write_cmd_to(Command::Set {
-            key: key.clone(),
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expire_at: Option<u64>) -> Result<()> {
+        let cmd = Command::Set {
+            key,
             value,
-        })?;
+            expire_at,
+        };
+        let position = self.write_cmd_to(&cmd)?;
 
-        self.index.update(key.clone(), position);
+        self.update_index(cmd, position);
+        self.try_compact()?;
 
-        if let Some(pos) = self.reader.get_one(&key) {
-            self.uncompacted += pos.size;
+        Ok(())
+    }
+
+    /// Write `new` value of `key`, or remove it if `new` is `None`,
+    /// if its current value passes `check`
+    fn write_if<F>(&mut self, key: Vec<u8>, new: Option<Vec<u8>>, check: F) -> Result<()>
+    where
+        F: FnOnce(&Option<Vec<u8>>) -> bool,
+    {
+        // values only change under the writer lock, which is held here
+        let current = self.reader.get(&key, &self.path)?;
+        if !check(&current) {
+            return Err(KvsError::CompareFailed { current });
+        }
+        match new {
+            Some(value) => self.set(key, value, None),
+            None if current.is_some() => self.remove(key),
+            None => Ok(()),
+        }
+    }
+
+    /// Write `value` of `key` expiring at `expire_at` if the version of `key`
+    /// is `expected`
+    fn write_if_version(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expire_at: Option<u64>,
+        expected: Option<u64>,
+    ) -> Result<()> {
+        // versions only change under the writer lock, which is held here
+        let version = self.reader.live_position(&key).map(|pos| pos.version);
+        if version != expected {
+            let current = self.reader.get(&key, &self.path)?;
+            return Err(KvsError::CompareFailed { current });
         }
+        self.set(key, value, expire_at)
+    }
+
+    /// Rewrite the value of `key` with the expiry `expire_at`
+    fn set_expiry(&mut self, key: Vec<u8>, expire_at: Option<u64>) -> Result<()> {
+        let pos = self
+            .reader
+            .live_position(&key)
+            .ok_or(KvsError::KeyNotFound)?;
+        if pos.expire_at == expire_at {
+            return Ok(());
+        }
+        match self.reader.get(&key, &self.path)? {
+            Some(value) => self.set(key, value, expire_at),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
 
-        self.index.refresh();
+    fn write_batch(&mut self, cmds: Vec<Command>) -> Result<()> {
+        let cmd = Command::Batch(cmds);
+        let position = self.write_cmd_to(&cmd)?;
+
+        self.update_index(cmd, position);
         self.try_compact()?;
 
         Ok(())
     }
 
+    /// Write a batch of a restore, synced whatever the sync policy, since
+    /// the restore marker is removed once every batch is written
+    fn write_restored(&mut self, cmds: Vec<Command>) -> Result<()> {
+        self.write_batch(cmds)?;
+        self.unsynced = true;
+        self.sync()
+    }
+
+    /// Update index by a command written at `position`
+    fn update_index(&mut self, cmd: Command, position: Position) {
+        match cmd {
+            Command::Set {
+                key,
+                value,
+                expire_at,
+            } => {
+                if let Some(pos) = self.reader.position(&key) {
+                    self.mark_dead(pos);
+                }
+                self.keep_for_copies(&key);
+                self.version += 1;
+                self.reader.insert(
+                    key.clone(),
+                    Position {
+                        expire_at,
+                        version: self.version,
+                        ..position
+                    },
+                );
+                if !self.watchers.is_empty() {
+                    self.notify(WatchEvent::Set { key, value });
+                }
+            }
+            Command::Rm { key } => {
+                if let Some(pos) = self.reader.position(&key) {
+                    self.mark_dead(pos);
+                }
+                self.keep_for_copies(&key);
+                self.reader.remove(&key);
+
+                self.mark_dead(position);
+                if !self.watchers.is_empty() {
+                    self.notify(WatchEvent::Rm { key });
+                }
+            }
+            Command::Batch(cmds) => {
+                let batch = Arc::clone(&self.reader.batch);
+                let _batch = batch.write().unwrap();
+                let records = Command::Batch(cmds).into_records(position.position, position.size);
+                for (cmd, offset, size) in records {
+                    let position = Position {
+                        position: offset,
+                        size,
+                        ..position
+                    };
+                    self.update_index(cmd, position);
+                }
+                // the header of a batch is garbage once it is applied
+                self.mark_dead(Position {
+                    size: record::BATCH_HEADER_SIZE,
+                    ..position
+                });
+            }
+        }
+    }
+
+    /// Keep the position of `key` for snapshots being copied, before the
+    /// index entry of it is replaced
+    fn keep_for_copies(&mut self, key: &[u8]) {
+        if self.copies.is_empty() {
+            return;
+        }
+        let pos = self.reader.position(key);
+        for undo in self.copies.values_mut() {
+            undo.entry(key.to_vec()).or_insert(pos);
+        }
+    }
+
+    /// Send `event` to watchers of its key, dropping watchers gone or too
+    /// far behind, rather than waiting for them
+    fn notify(&mut self, event: WatchEvent) {
+        self.watchers.retain(|(prefix, sender)| {
+            !event.key().starts_with(prefix) || sender.try_send(event.clone()).is_ok()
+        });
+    }
+
+    /// Account the record at `pos` as garbage of its block
+    fn mark_dead(&mut self, pos: Position) {
+        if let Some(stat) = self.blocks.get_mut(&pos.file) {
+            stat.dead += pos.size;
+            self.uncompacted += pos.size;
+        }
+    }
+
+    /// Account bytes appended to the active block
+    fn mark_written(&mut self, len: u64) {
+        self.blocks.entry(self.current_block).or_default().size += len;
+        self.total += len;
+    }
+
+    /// Write a group of commands with one write and one sync
+    ///
+    /// Return the position of each command, or `KvsError::KeyNotFound`
+    /// for removing a key which doesn't exist when the command is applied.
+    fn write_group(&mut self, cmds: Vec<Command>) -> Vec<Result<Position>> {
+        // keys set or removed by earlier commands of this group
+        let mut exists = HashMap::new();
+        let mut applied = Vec::new();
+        let mut results = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            match &cmd {
+                Command::Set { key, .. } => {
+                    exists.insert(key.clone(), true);
+                }
+                Command::Batch(cmds) => {
+                    for cmd in cmds {
+                        match cmd {
+                            Command::Set { key, .. } => exists.insert(key.clone(), true),
+                            Command::Rm { key } => exists.insert(key.clone(), false),
+                            Command::Batch(_) => unreachable!(),
+                        };
+                    }
+                }
+                Command::Rm { key } => {
+                    let found = match exists.get(key) {
+                        Some(found) => *found,
+                        None => self.reader.live_position(key).is_some(),
+                    };
+                    if !found {
+                        results.push(Some(Err(KvsError::KeyNotFound)));
+                        continue;
+                    }
+                    exists.insert(key.clone(), false);
+                }
+            }
+            results.push(None);
+            applied.push(cmd);
+        }
+
+        let written = self.write_cmds_to(&applied).and_then(|positions| {
+            for (cmd, position) in applied.into_iter().zip(positions.iter()) {
+                self.update_index(cmd, *position);
+            }
+            self.try_compact()?;
+            Ok(positions)
+        });
+
+        match written {
+            Ok(positions) => {
+                let mut positions = positions.into_iter();
+                results
+                    .into_iter()
+                    .map(|r| r.unwrap_or_else(|| Ok(positions.next().unwrap())))
+                    .collect()
+            }
+            Err(e) => results
+                .into_iter()
+                .map(|r| r.unwrap_or_else(|| Err(KvsError::Io(shared_io_error(&e)))))
+                .collect(),
+        }
+    }
+
     fn new_block(&mut self) -> Result<()> {
+        if self.sync != SyncPolicy::Never {
+            self.sync()?;
+        }
         self.current_block += 1;
 
         self.writer = BufWriter::new(File::create(
             self.path.join(get_file_path(self.gen, self.current_block)),
         )?);
+        self.blocks.insert(self.current_block, BlockStat::default());
 
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let exist_size = if let Some(pos) = self.reader.get_one(&key) {
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        let exist_size = if let Some(pos) = self.reader.live_position(&key) {
             pos.size
         } else {
             0
         };
         if exist_size != 0 {
-            let pos = self.write_cmd_to(Command::Rm { key: key.clone() })?;
-            self.index.empty(key);
-
-            self.uncompacted += exist_size + pos.size as u64;
+            let cmd = Command::Rm { key };
+            let position = self.write_cmd_to(&cmd)?;
 
-            self.index.refresh();
+            self.update_index(cmd, position);
             self.try_compact()?;
 
             Ok(())
@@ -131,95 +534,1120 @@ impl KvStoreWriter {
         }
     }
 
-    fn write_cmd_to(&mut self, cmd: Command) -> Result<Position> {
-        let mut cmd = serde_json::to_string(&cmd)?;
-        cmd += "\n";
+    fn write_cmd_to(&mut self, cmd: &Command) -> Result<Position> {
+        self.write_bytes_to(&record::encode(cmd)?)
+    }
+
+    /// Write commands with as few writes as possible, then sync once
+    fn write_cmds_to(&mut self, cmds: &[Command]) -> Result<Vec<Position>> {
+        let mut positions = Vec::with_capacity(cmds.len());
+        let mut buf = Vec::new();
+        let mut offset = self.writer.stream_position()?;
+        for cmd in cmds {
+            let record = record::encode(cmd)?;
+            if offset + (buf.len() + record.len()) as u64 > self.block_threshold {
+                self.writer.write_all(&buf)?;
+                self.mark_written(buf.len() as u64);
+                self.unsynced = true;
+                buf.clear();
+                self.new_block()?;
+                offset = 0;
+            }
+            positions.push(Position {
+                file: self.current_block,
+                position: offset + buf.len() as u64,
+                size: record.len() as u64,
+                gen: self.gen,
+                expire_at: None,
+                version: 0,
+            });
+            buf.extend_from_slice(&record);
+        }
+
+        self.writer.write_all(&buf)?;
+        self.mark_written(buf.len() as u64);
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.unsynced = false;
 
-        self.write_string_to(cmd)
+        Ok(positions)
     }
 
-    fn write_string_to(&mut self, s: String) -> Result<Position> {
-        if s.len() as u64 + self.writer.stream_position()? > BLOCK_THRESHOLD {
+    fn write_bytes_to(&mut self, buf: &[u8]) -> Result<Position> {
+        if buf.len() as u64 + self.writer.stream_position()? > self.block_threshold {
             self.new_block()?;
         }
 
         let position = self.writer.stream_position()?;
-        let size = self.writer.write(s.as_bytes())?;
+        self.writer.write_all(buf)?;
+        self.mark_written(buf.len() as u64);
         let file = self.current_block;
 
         self.writer.flush()?;
+        // conditional writes skip group commit, but are synced all the same
+        if let SyncPolicy::Always | SyncPolicy::GroupCommit = self.sync {
+            self.writer.get_ref().sync_data()?;
+        } else {
+            self.unsynced = true;
+        }
 
         Ok(Position {
             file,
             position,
-            size: size as u64,
+            size: buf.len() as u64,
             gen: self.gen,
+            expire_at: None,
+            version: 0,
         })
     }
 
-    /// Compaction steps:
-    /// 1. current `gen` add 1, and create a new directory
-    /// 2. reset some pointer and counter
-    /// 3. traverse index and write them into new directory
-    /// 4. delete second last backup
+    /// Sync written logs of the active block to disk
+    fn sync(&mut self) -> Result<()> {
+        if self.unsynced {
+            self.writer.flush()?;
+            self.writer.get_ref().sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// Trigger a background compaction if there is enough garbage
+    ///
+    /// Both the dead bytes and their ratio to total bytes must reach
+    /// the thresholds, and the last compaction must be long enough ago.
     fn try_compact(&mut self) -> Result<()> {
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            // Create new genaration of store
-            self.gen += 1;
-            let new_generation_path = self.path.join(get_store_dir_by(self.gen));
-            if new_generation_path.exists() {
-                fs::remove_dir_all(&new_generation_path)?;
+        if self.compacting
+            || self.uncompacted <= self.compaction_threshold
+            || (self.uncompacted as f64) < self.compaction_ratio * self.total as f64
+        {
+            return Ok(());
+        }
+        if let Some(last) = self.last_compaction {
+            if last.elapsed() < self.compaction_interval {
+                return Ok(());
+            }
+        }
+        if let Some(compaction) = &self.compaction {
+            self.compacting = true;
+            self.last_compaction = Some(Instant::now());
+            let _ = compaction.send(CompactionRequest::Auto);
+        }
+        Ok(())
+    }
+
+    /// Pick the blocks to compact, those whose ratio of dead bytes reaches
+    /// the threshold, or else the one with most dead bytes
+    ///
+    /// Every block with dead bytes is picked if `all` is set.
+    fn pick_victims(&self, all: bool) -> Vec<u64> {
+        let garbage = self.blocks.iter().filter(|(_, stat)| stat.dead > 0);
+        if all {
+            return garbage.map(|(block, _)| *block).collect();
+        }
+        let mut victims = garbage
+            .clone()
+            .filter(|(_, stat)| stat.dead as f64 >= self.compaction_ratio * stat.size as f64)
+            .map(|(block, _)| *block)
+            .collect::<Vec<u64>>();
+        if victims.is_empty() {
+            victims.extend(garbage.max_by_key(|(_, stat)| stat.dead).map(|(b, _)| *b));
+        }
+        victims
+    }
+
+    /// Seal the active block for compaction
+    ///
+    /// A block number is reserved for the copy of each picked block, and new
+    /// writes go to a fresh block after them, so that replaying blocks in
+    /// order always sees copied records before records written later.
+    /// Return `None` if there is nothing to compact.
+    fn seal(&mut self, all: bool) -> Result<Option<Sealed>> {
+        let victims = self.pick_victims(all);
+        if victims.is_empty() {
+            return Ok(None);
+        }
+
+        self.writer.flush()?;
+        self.unsynced = true;
+        let reserved = victims.len() as u64;
+        let outputs = (self.current_block + 1..=self.current_block + reserved).collect();
+        self.current_block += reserved;
+        self.new_block()?;
+
+        let oldest_kept = self
+            .blocks
+            .keys()
+            .find(|block| !victims.contains(block))
+            .copied();
+        Ok(Some(Sealed {
+            gen: self.gen,
+            victims,
+            outputs,
+            oldest_kept,
+            block_threshold: self.block_threshold,
+        }))
+    }
+
+    /// Install blocks copied by a compaction and delete the compacted blocks
+    ///
+    /// Copied blocks are renamed in place, which is the commit point. Index
+    /// entries still pointing at the copied records are switched to the
+    /// copies, then compacted blocks are deleted.
+    fn install_compaction(&mut self, compacted: Compacted, sealed: &Sealed) -> Result<()> {
+        let dir = self.path.join(get_store_dir_by(sealed.gen));
+        for block in &compacted.blocks {
+            fs::rename(
+                get_compacting_path(&dir, block.number),
+                self.path.join(get_file_path(sealed.gen, block.number)),
+            )?;
+            // tombstones are garbage once the keys are gone from older blocks
+            let dead = block
+                .entries
+                .iter()
+                .filter(|entry| entry.removed)
+                .map(|entry| entry.size)
+                .sum();
+            self.blocks.insert(
+                block.number,
+                BlockStat {
+                    size: block.size,
+                    dead,
+                },
+            );
+            self.total += block.size;
+            self.uncompacted += dead;
+        }
+
+        for (key, old_pos, new_pos) in compacted.positions {
+            if self.reader.position(&key) == Some(old_pos) {
+                self.keep_for_copies(&key);
+                let version = old_pos.version;
+                self.reader.insert(key, Position { version, ..new_pos });
+            } else {
+                self.mark_dead(new_pos);
+            }
+        }
+        for (key, old_pos) in compacted.expired {
+            if self.reader.position(&key) == Some(old_pos) {
+                self.keep_for_copies(&key);
+                self.reader.remove(&key);
+            }
+        }
+
+        // open snapshots may still read compacted blocks
+        let mut pins = self.pins.lock().unwrap();
+        for block in &sealed.victims {
+            if let Some(stat) = self.blocks.remove(block) {
+                self.total -= stat.size;
+                self.uncompacted = self.uncompacted.saturating_sub(stat.dead);
+            }
+            let block_path = self.path.join(get_file_path(sealed.gen, *block));
+            if pins.snapshots > 0 {
+                let pinned_path = get_pinned_path(&dir, *block);
+                fs::rename(block_path, &pinned_path)?;
+                pins.files.push(pinned_path);
+            } else {
+                fs::remove_file(block_path)?;
+            }
+            let hint_path = hint::hint_path(&dir, *block);
+            if hint_path.exists() {
+                fs::remove_file(hint_path)?;
+            }
+        }
+        drop(pins);
+        self.compacting = false;
+
+        // the legacy generation is kept until the upgraded one is compacted
+        if self.gen > 0 && self.path.join(get_store_dir_by(self.gen - 1)).exists() {
+            remove_dir_all(self.path.join(get_store_dir_by(self.gen - 1)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Positions of the latest records of keys
+type Index = HashMap<Vec<u8>, Position>;
+
+/// First position replaced of every key changed, `None` if it was absent
+type Undo = HashMap<Vec<u8>, Option<Position>>;
+
+/// Open snapshots of a `KvStore`, and compacted blocks kept for them
+///
+/// Compacted blocks are renamed to `.pinned` files rather than deleted
+/// while any snapshot is open, and deleted with the last snapshot.
+/// Pinned files left by a crash are deleted on open.
+#[derive(Default)]
+struct Pins {
+    snapshots: usize,
+    files: Vec<PathBuf>,
+}
+
+/// Registration of a snapshot in `Pins`, dropped with the last clone of it
+struct SnapshotPin {
+    pins: Arc<Mutex<Pins>>,
+}
+
+impl Drop for SnapshotPin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap();
+        pins.snapshots -= 1;
+        if pins.snapshots == 0 {
+            for path in pins.files.drain(..) {
+                // left to be deleted on open if it fails
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Read-only view of a `KvStore` at the time it is taken
+///
+/// It holds a copy of the index, so taking it is linear in the number of
+/// keys, though writes go on during the copy, while values are read from
+/// the log on demand. Blocks compacted meanwhile are kept until every
+/// snapshot is dropped.
+#[derive(Clone)]
+pub struct KvStoreSnapshot {
+    index: Arc<BTreeMap<Vec<u8>, Position>>,
+    path: PathBuf,
+    now: u64,
+    _pin: Arc<SnapshotPin>,
+}
+
+impl KvStoreSnapshot {
+    fn read_value(&self, pos: &Position) -> Result<Vec<u8>> {
+        let file_path = self.path.join(get_file_path(pos.gen, pos.file));
+        let buf = match read_bytes_from(&file_path, pos.position, pos.size) {
+            // the block is compacted after the snapshot is taken
+            Err(KvsError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                let dir = self.path.join(get_store_dir_by(pos.gen));
+                read_bytes_from(&get_pinned_path(&dir, pos.file), pos.position, pos.size)?
+            }
+            result => result?,
+        };
+        decode_value(&buf, file_path, pos)
+    }
+
+    /// Entries of keys in `range` live at the time of the snapshot
+    fn entries<R: RangeBounds<[u8]>>(&self, range: R) -> Vec<(Vec<u8>, Position)> {
+        self.index
+            .range::<[u8], _>(range)
+            .filter(|(_, pos)| !pos.is_expired(self.now))
+            .map(|(key, pos)| (key.clone(), *pos))
+            .collect()
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        match self.index.get(&key.into()) {
+            Some(pos) if !pos.is_expired(self.now) => self.read_value(pos).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Scan key-value pairs with keys in `range`, in key order.
+    ///
+    /// Keys are collected up front, and values are read lazily.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter> {
+        let range = (
+            range.start_bound().map(Vec::as_slice),
+            range.end_bound().map(Vec::as_slice),
+        );
+        Ok(Box::new(KvStoreSnapshotScan {
+            entries: self.entries(range).into_iter(),
+            snapshot: self.clone(),
+        }))
+    }
+
+    fn scan_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<ScanIter> {
+        let prefix = prefix.into();
+        let mut entries = self.entries((Bound::Included(prefix.as_slice()), Bound::Unbounded));
+        entries.retain(|(key, _)| key.starts_with(&prefix));
+        Ok(Box::new(KvStoreSnapshotScan {
+            entries: entries.into_iter(),
+            snapshot: self.clone(),
+        }))
+    }
+}
+
+/// Optimistic transaction of a `KvStore`
+///
+/// Reads remember the versions of keys in the index, which `commit` checks
+/// again under the writer lock before the writes are written as one batch.
+pub struct KvStoreTransaction {
+    store: KvStore,
+    /// Version of every key read, `None` if it did not exist
+    reads: HashMap<Vec<u8>, Option<u64>>,
+    /// Buffered writes in key order, `None` to remove the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl KvsTransaction for KvStoreTransaction {
+    fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let found = self
+            .store
+            .reader
+            .get_with_position(&key, &self.store.path)?;
+        let version = found.as_ref().map(|(pos, _)| pos.version);
+        self.reads.entry(key).or_insert(version);
+        Ok(found.map(|(_, value)| value))
+    }
+
+    fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        self.writes.insert(key.into(), Some(value.into()));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        self.writes.insert(key.into(), None);
+        Ok(())
+    }
+
+    fn commit(self) -> Result<()> {
+        let mut writer = self.store.writer.lock().unwrap();
+        for (key, version) in &self.reads {
+            if writer.reader.live_position(key).map(|pos| pos.version) != *version {
+                return Err(KvsError::TransactionConflict);
+            }
+        }
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let cmds = self
+            .writes
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => Command::Set {
+                    key,
+                    value,
+                    expire_at: None,
+                },
+                None => Command::Rm { key },
+            })
+            .collect();
+        writer.write_batch(cmds)
+    }
+}
+
+/// Iterator of a `KvStoreSnapshot` scan
+struct KvStoreSnapshotScan {
+    entries: std::vec::IntoIter<(Vec<u8>, Position)>,
+    snapshot: KvStoreSnapshot,
+}
+
+impl Iterator for KvStoreSnapshotScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, pos) = self.entries.next()?;
+        Some(self.snapshot.read_value(&pos).map(|value| (key, value)))
+    }
+}
+
+/// Size and dead bytes of a block
+#[derive(Default, Clone, Copy)]
+struct BlockStat {
+    size: u64,
+    dead: u64,
+}
+
+/// Blocks picked by a compaction when the active block is sealed
+struct Sealed {
+    gen: u64,
+    /// Blocks to compact
+    victims: Vec<u64>,
+    /// Block numbers reserved for copied records
+    outputs: Vec<u64>,
+    /// Oldest block which is not compacted
+    oldest_kept: Option<u64>,
+    block_threshold: u64,
+}
+
+/// Blocks written by a compaction
+struct Compacted {
+    blocks: Vec<CompactedBlock>,
+    /// Key, position before and after compaction of each copied live record
+    positions: Vec<(Vec<u8>, Position, Position)>,
+    /// Key and position of each dropped expired record which was live
+    expired: Vec<(Vec<u8>, Position)>,
+}
+
+/// A block of copied records, with the entries of its hint file
+struct CompactedBlock {
+    number: u64,
+    size: u64,
+    entries: Vec<HintEntry>,
+}
+
+/// Handle of the background compaction thread
+///
+/// The thread is stopped when the handle is dropped with the last
+/// `KvStore`.
+struct Compactor {
+    sender: Option<Sender<CompactionRequest>>,
+    handle: Option<JoinHandle<()>>,
+    closed: Arc<AtomicBool>,
+}
+
+/// Request sent to the compaction thread
+enum CompactionRequest {
+    /// Compaction triggered by the garbage thresholds
+    Auto,
+    /// Compaction of every block with dead bytes, replied when completed
+    Manual(Sender<Result<()>>),
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Spawn the thread running compactions triggered by the writer
+fn spawn_compaction_thread(
+    writer: Weak<Mutex<KvStoreWriter>>,
+    reader: KvStoreReader,
+    path: PathBuf,
+    logger: Logger,
+) -> Result<Compactor> {
+    let (sender, receiver) = mpsc::channel();
+    let closed = Arc::new(AtomicBool::new(false));
+    let thread_closed = Arc::clone(&closed);
+    let handle = thread::Builder::new()
+        .name("kvs-compaction".to_owned())
+        .spawn(move || {
+            while let Ok(request) = receiver.recv() {
+                let (all, reply) = match request {
+                    CompactionRequest::Auto => (false, None),
+                    CompactionRequest::Manual(reply) => (true, Some(reply)),
+                };
+                let result = compact(&writer, &reader, &path, &thread_closed, all);
+                if result.is_err() {
+                    if let Some(writer) = writer.upgrade() {
+                        writer.lock().unwrap().compacting = false;
+                    }
+                }
+                match (result, reply) {
+                    (result, Some(reply)) => {
+                        let _ = reply.send(result);
+                    }
+                    (Err(e), None) => warn!(logger, "Compaction failed: {}", e),
+                    (Ok(()), None) => {}
+                }
             }
-            fs::create_dir_all(&new_generation_path)?;
+        })?;
+
+    Ok(Compactor {
+        sender: Some(sender),
+        handle: Some(handle),
+        closed,
+    })
+}
 
-            // reset some "pointer"
-            self.uncompacted = 0;
-            self.current_block = 0;
-            self.writer = BufWriter::new(File::create(
-                self.path.join(get_file_path(self.gen, self.current_block)),
-            )?);
+/// Compaction steps:
+/// 1. pick blocks with most garbage and seal the active block
+/// 2. copy live records and needed tombstones of picked blocks into the
+///    reserved blocks, and write their hint files
+/// 3. rename copied blocks, switch index to them and delete picked blocks
+///
+/// Only step 1 and step 3 hold the writer lock.
+fn compact(
+    writer: &Weak<Mutex<KvStoreWriter>>,
+    reader: &KvStoreReader,
+    path: &Path,
+    closed: &AtomicBool,
+    all: bool,
+) -> Result<()> {
+    let sealed = match writer.upgrade() {
+        Some(writer) => {
+            let mut writer = writer.lock().unwrap();
+            match writer.seal(all)? {
+                Some(sealed) => {
+                    writer.compacting = true;
+                    sealed
+                }
+                None => {
+                    writer.compacting = false;
+                    return Ok(());
+                }
+            }
+        }
+        None => return Ok(()),
+    };
 
-            // read from old index and write them into new generation store
-            let index_reader = self.reader.clone();
-            for (key, value) in index_reader.read().unwrap().iter() {
-                let pos = value.get_one().unwrap();
-                let buf = read_string_from(
-                    self.path.join(get_file_path(pos.gen, pos.file)),
-                    pos.position,
-                    pos.size,
-                )?;
-                let new_position = self.write_string_to(buf)?;
-                self.index.update(key.clone(), new_position);
+    let dir = path.join(get_store_dir_by(sealed.gen));
+    let result =
+        copy_live_records(reader, &dir, &sealed, closed).and_then(|compacted| {
+            match writer.upgrade() {
+                Some(writer) if !closed.load(Ordering::SeqCst) => writer
+                    .lock()
+                    .unwrap()
+                    .install_compaction(compacted, &sealed),
+                _ => Ok(()),
             }
+        });
 
-            self.index.refresh();
+    for block in &sealed.outputs {
+        let compacting_path = get_compacting_path(&dir, *block);
+        if compacting_path.exists() {
+            fs::remove_file(compacting_path)?;
+        }
+    }
+    result
+}
 
-            if self.gen > 1 && self.path.join(get_store_dir_by(self.gen - 2)).exists() {
-                remove_dir_all(self.path.join(get_store_dir_by(self.gen - 2)))?;
+/// Copy live records of sealed blocks into the reserved blocks
+///
+/// A tombstone is kept only if its key is still removed and older blocks
+/// which may hold the key are not compacted. Expired records are dropped,
+/// leaving a tombstone under the same rule.
+fn copy_live_records(
+    reader: &KvStoreReader,
+    dir: &Path,
+    sealed: &Sealed,
+    closed: &AtomicBool,
+) -> Result<Compacted> {
+    let gen = sealed.gen;
+    let mut output = CompactionWriter {
+        dir,
+        gen,
+        outputs: &sealed.outputs,
+        block_threshold: sealed.block_threshold,
+        current: None,
+        blocks: Vec::new(),
+    };
+    let mut positions = Vec::new();
+    let mut expired = Vec::new();
+    let mut tombstones = HashSet::new();
+    let now = now_millis();
+    for &block in &sealed.victims {
+        let file_path = dir.join(format!("{}.log", block));
+        let mut block_reader = BufReader::new(File::open(&file_path)?);
+        let mut position = 0u64;
+        loop {
+            if closed.load(Ordering::SeqCst) {
+                return Err(KvsError::Io(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "store closed",
+                )));
             }
+            let (cmd, size) = match record::read_record(&mut block_reader)? {
+                ReadRecord::Record(cmd, size) => (cmd, size),
+                ReadRecord::Eof => break,
+                ReadRecord::Corrupt => {
+                    return Err(KvsError::CorruptedRecord {
+                        path: file_path,
+                        offset: position,
+                    })
+                }
+            };
+            // records of a batch are copied on their own, since the whole
+            // batch is already applied
+            for (cmd, offset, size) in cmd.into_records(position, size) {
+                let older_kept = sealed.oldest_kept.is_some_and(|kept| kept < block);
+                match &cmd {
+                    Command::Set { key, expire_at, .. } => {
+                        let current = reader.position(key);
+                        let pos = Position {
+                            gen,
+                            file: block,
+                            position: offset,
+                            size,
+                            expire_at: *expire_at,
+                            version: current.map_or(0, |current| current.version),
+                        };
+                        let live = current == Some(pos);
+                        if pos.is_expired(now) {
+                            if (live || !reader.contains_key(key)) && older_kept {
+                                tombstones.insert(key.clone());
+                            }
+                            if live {
+                                expired.push((key.clone(), pos));
+                            }
+                        } else if live {
+                            let new_pos =
+                                output.append(key, false, *expire_at, &record::encode(&cmd)?)?;
+                            positions.push((key.clone(), pos, new_pos));
+                        }
+                    }
+                    Command::Rm { key } => {
+                        if !reader.contains_key(key) && older_kept {
+                            tombstones.insert(key.clone());
+                        }
+                    }
+                    Command::Batch(_) => unreachable!(),
+                }
+            }
+            position += size;
+        }
+    }
+    for key in tombstones {
+        let buf = record::encode(&Command::Rm { key: key.clone() })?;
+        output.append(&key, true, None, &buf)?;
+    }
+
+    Ok(Compacted {
+        blocks: output.finish()?,
+        positions,
+        expired,
+    })
+}
+
+/// Writer of the blocks reserved for a compaction
+///
+/// Records are appended to a reserved block until it reaches the block
+/// threshold. The last reserved block takes the rest, which never happens
+/// unless records are larger than the threshold.
+struct CompactionWriter<'a> {
+    dir: &'a Path,
+    gen: u64,
+    outputs: &'a [u64],
+    block_threshold: u64,
+    current: Option<(BufWriter<File>, CompactedBlock)>,
+    blocks: Vec<CompactedBlock>,
+}
+
+impl CompactionWriter<'_> {
+    fn append(
+        &mut self,
+        key: &[u8],
+        removed: bool,
+        expire_at: Option<u64>,
+        buf: &[u8],
+    ) -> Result<Position> {
+        let len = buf.len() as u64;
+        let full = match &self.current {
+            Some((_, block)) => {
+                block.size > 0
+                    && block.size + len > self.block_threshold
+                    && self.blocks.len() + 1 < self.outputs.len()
+            }
+            None => true,
+        };
+        if full {
+            self.finish_block()?;
+            let number = self.outputs[self.blocks.len()];
+            let file = File::create(get_compacting_path(self.dir, number))?;
+            let block = CompactedBlock {
+                number,
+                size: 0,
+                entries: Vec::new(),
+            };
+            self.current = Some((BufWriter::new(file), block));
+        }
+
+        let (writer, block) = self.current.as_mut().unwrap();
+        writer.write_all(buf)?;
+        let position = Position {
+            gen: self.gen,
+            file: block.number,
+            position: block.size,
+            size: len,
+            expire_at,
+            version: 0,
+        };
+        block.entries.push(HintEntry {
+            key: key.to_owned(),
+            removed,
+            position: block.size,
+            size: len,
+            expire_at,
+        });
+        block.size += len;
+        Ok(position)
+    }
+
+    /// Sync the block being written and write its hint file
+    fn finish_block(&mut self) -> Result<()> {
+        if let Some((writer, block)) = self.current.take() {
+            writer
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+            hint::write_hint(self.dir, block.number, &block.entries)?;
+            self.blocks.push(block);
         }
         Ok(())
     }
+
+    fn finish(mut self) -> Result<Vec<CompactedBlock>> {
+        self.finish_block()?;
+        Ok(self.blocks)
+    }
 }
 
 impl KvsEngine for KvStore {
-    /// Set the value of a string key to a string.
+    type Snapshot = KvStoreSnapshot;
+    type Transaction = KvStoreTransaction;
+
+    /// Set the value of a key.
     ///
     /// If the key exists, the value will be overwritten.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        self.set_expiring(key.into(), value.into(), None)
+    }
+
+    /// Set the value of a key which expires after `ttl`.
+    ///
+    /// The expiry is stored in the log, and the key is dropped by compaction
+    /// once expired.
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let expire_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.set_expiring(key.into(), value.into(), Some(expire_at))
+    }
+
+    /// Get the remaining time to live of a key.
+    ///
+    /// Return `None` if the key never expires.
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>> {
+        let pos = self
+            .reader
+            .live_position(&key.into())
+            .ok_or(KvsError::KeyNotFound)?;
+        Ok(pos
+            .expire_at
+            .map(|expire_at| Duration::from_millis(expire_at.saturating_sub(now_millis()))))
+    }
+
+    /// Remove the expiry of a key, by rewriting its value under the writer lock.
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        self.writer.lock().unwrap().set_expiry(key.into(), None)
     }
 
-    /// Get the string value of a given string key
+    /// Set the expiry of a key, by rewriting its value under the writer lock.
+    fn expire(&self, key: impl Into<Vec<u8>>, ttl: Duration) -> Result<()> {
+        let expire_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.writer
+            .lock()
+            .unwrap()
+            .set_expiry(key.into(), Some(expire_at))
+    }
+
+    /// Get the value of a given key
     ///
     /// Return `None` if the key doesn't exist.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        self.reader.get(key, &self.path)
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        self.reader.get(&key.into(), &self.path)
     }
 
     /// Remove a given key.
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        match &self.group {
+            Some(group) => self.commit(group, Command::Rm { key }),
+            None => self.writer.lock().unwrap().remove(key),
+        }
+    }
+
+    /// Swap the value of a key if it matches, checked under the writer lock.
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .write_if(key.into(), new, |current| *current == expected)
+    }
+
+    /// Set the value of a key if it doesn't exist, checked under the writer lock.
+    fn set_if_absent(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .write_if(key.into(), Some(value.into()), Option::is_none)
+    }
+
+    /// Set the value of a key if it exists, checked under the writer lock.
+    fn set_if_present(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .write_if(key.into(), Some(value.into()), Option::is_some)
+    }
+
+    /// Get the value of a key with the version of its position in the index.
+    fn get_versioned(&self, key: impl Into<Vec<u8>>) -> Result<Option<(Vec<u8>, u64)>> {
+        let found = self.reader.get_with_position(&key.into(), &self.path)?;
+        Ok(found.map(|(pos, value)| (value, pos.version)))
+    }
+
+    /// Set the value of a key if its version matches, checked under the writer lock.
+    fn set_if_version(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Option<Duration>,
+        expected: Option<u64>,
+    ) -> Result<()> {
+        let expire_at = ttl.map(|ttl| now_millis().saturating_add(ttl.as_millis() as u64));
+        self.writer
+            .lock()
+            .unwrap()
+            .write_if_version(key.into(), value.into(), expire_at, expected)
+    }
+
+    /// Apply writes of a batch atomically, as one record in the log.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let cmds = batch
+            .into_ops()
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => Command::Set {
+                    key,
+                    value,
+                    expire_at: None,
+                },
+                BatchOp::Rm { key } => Command::Rm { key },
+            })
+            .collect();
+        match &self.group {
+            Some(group) => self.commit(group, Command::Batch(cmds)),
+            None => self.writer.lock().unwrap().write_batch(cmds),
+        }
+    }
+
+    /// Scan key-value pairs with keys in `range`, in key order.
+    ///
+    /// Values are read lazily, so the iterator sees writes made during the scan.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter> {
+        Ok(Box::new(KvStoreScan {
+            reader: self.reader.clone(),
+            path: self.path.clone(),
+            next: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            prefix: None,
+        }))
+    }
+
+    /// Scan key-value pairs with keys starting with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<ScanIter> {
+        let prefix = prefix.into();
+        Ok(Box::new(KvStoreScan {
+            reader: self.reader.clone(),
+            path: self.path.clone(),
+            next: Bound::Included(prefix.clone()),
+            end: Bound::Unbounded,
+            prefix: Some(prefix),
+        }))
+    }
+
+    /// Scan keys in `range` from the index alone, in key order.
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KeyIter> {
+        Ok(Box::new(KvStoreKeys(KvStoreScan {
+            reader: self.reader.clone(),
+            path: self.path.clone(),
+            next: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            prefix: None,
+        })))
+    }
+
+    /// Compact every block with dead bytes now.
+    fn compact(&self) -> Result<()> {
+        KvStore::compact(self)
+    }
+
+    /// Get statistics of the engine from `KvStore::stats`.
+    fn engine_stats(&self) -> Result<KvsStats> {
+        let stats = self.stats();
+        Ok(KvsStats {
+            keys: stats.live_keys,
+            disk_bytes: stats.live_bytes + stats.dead_bytes,
+        })
+    }
+
+    /// Take a snapshot by copying the index, with the writer lock held
+    /// only at the start and the end of the copy.
+    ///
+    /// Writes meanwhile keep the positions they replace for the copy, which
+    /// are put back over it at the end.
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        let (id, pin, now) = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.pins.lock().unwrap().snapshots += 1;
+            let pin = SnapshotPin {
+                pins: Arc::clone(&writer.pins),
+            };
+            let id = writer.next_copy;
+            writer.next_copy += 1;
+            writer.copies.insert(id, Undo::new());
+            (id, pin, now_millis())
+        };
+        let mut index: BTreeMap<_, _> = self
+            .reader
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        let undo = self.writer.lock().unwrap().copies.remove(&id);
+        for (key, pos) in undo.unwrap_or_default() {
+            match pos {
+                Some(pos) => index.insert(key, pos),
+                None => index.remove(&key),
+            };
+        }
+        Ok(KvStoreSnapshot {
+            index: Arc::new(index),
+            path: self.path.clone(),
+            now,
+            _pin: Arc::new(pin),
+        })
+    }
+
+    fn begin(&self) -> Result<KvStoreTransaction> {
+        Ok(KvStoreTransaction {
+            store: self.clone(),
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        })
+    }
+
+    /// Register a watcher the writer sends events to as it updates the
+    /// index, which is dropped at the first event after the iterator is.
+    ///
+    /// A watcher more than `WATCH_CAPACITY` events behind is dropped too,
+    /// which ends its iterator once the events queued are read.
+    fn watch_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<WatchIter> {
+        let (sender, receiver) = mpsc::sync_channel(WATCH_CAPACITY);
+        let mut writer = self.writer.lock().unwrap();
+        writer.watchers.push((prefix.into(), sender));
+        Ok(WatchIter::new(receiver))
+    }
+
+    /// Back up live records of a snapshot into `path/gen_0`, so writes
+    /// and compactions go on meanwhile.
+    ///
+    /// The copy is written aside and renamed in place when completed, and
+    /// is itself a store which could be opened.
+    fn backup_to(&self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        let dir = path.join(get_store_dir_by(0));
+        if dir.exists() {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already holds a backup", path.display()),
+            )));
+        }
+        let snapshot = self.snapshot()?;
+
+        let tmp_dir = path.join(format!("{}.backup", get_store_dir_by(0)));
+        if tmp_dir.exists() {
+            fs::remove_dir_all(&tmp_dir)?;
+        }
+        fs::create_dir_all(&tmp_dir)?;
+        record::write_format_version(&tmp_dir)?;
+        let mut output = CompactionWriter {
+            dir: &tmp_dir,
+            gen: 0,
+            outputs: &[0],
+            block_threshold: u64::MAX,
+            current: None,
+            blocks: Vec::new(),
+        };
+        for (key, pos) in snapshot.entries(..) {
+            let buf = record::encode(&Command::Set {
+                key: key.clone(),
+                value: snapshot.read_value(&pos)?,
+                expire_at: pos.expire_at,
+            })?;
+            output.append(&key, false, pos.expire_at, &buf)?;
+        }
+        for block in output.finish()? {
+            fs::rename(
+                get_compacting_path(&tmp_dir, block.number),
+                tmp_dir.join(format!("{}.log", block.number)),
+            )?;
+        }
+        fs::rename(tmp_dir, dir)?;
+        Ok(())
+    }
+
+    /// Restore a backup, which removes keys missing in it.
+    ///
+    /// The backup is first copied into the store, where the copy marks the
+    /// restore until every batch is synced, so a restore interrupted by a
+    /// crash is done again when the store is opened, even if the backup is
+    /// gone by then. It is written in batches of at most a block each,
+    /// whose values are read from the copy batch by batch, and writes wait
+    /// meanwhile.
+    fn restore_from(&self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        let dir = path.join(get_store_dir_by(0));
+        if !dir.is_dir() {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} holds no backup", path.display()),
+            )));
+        }
+        match record::read_format_version(&dir)? {
+            record::FORMAT_VERSION => {}
+            version => return Err(KvsError::UnsupportedFormat(version.to_string())),
+        }
+
+        let mut writer = self.writer.lock().unwrap();
+        let marker = self.path.join(RESTORE_MARKER);
+        if path != marker {
+            copy_restored(&dir, &self.path)?;
+        }
+        let dir = marker.join(get_store_dir_by(0));
+        let files = get_log_files(&dir)?;
+        let logger = Logger::root(slog::Discard, o!());
+        let (positions, _) = load_index_from_files(&dir, &files, 0, &logger)?;
+        let removed: Vec<Result<_>> = self
+            .reader
+            .iter()
+            .filter(|entry| !positions.contains_key(entry.key()))
+            .map(|entry| {
+                Ok(Command::Rm {
+                    key: entry.key().clone(),
+                })
+            })
+            .collect();
+        let sets = positions.into_iter().map(|(key, pos)| {
+            let file_path = dir.join(format!("{}.log", pos.file));
+            let buf = read_bytes_from(&file_path, pos.position, pos.size)?;
+            Ok(Command::Set {
+                key,
+                value: decode_value(&buf, file_path, &pos)?,
+                expire_at: pos.expire_at,
+            })
+        });
+
+        let mut batch = Vec::new();
+        let mut size = record::BATCH_HEADER_SIZE;
+        for cmd in removed.into_iter().chain(sets) {
+            let cmd = cmd?;
+            if !batch.is_empty() && size + cmd.encoded_len() > writer.block_threshold {
+                writer.write_restored(mem::take(&mut batch))?;
+                size = record::BATCH_HEADER_SIZE;
+            }
+            size += cmd.encoded_len();
+            batch.push(cmd);
+        }
+        if !batch.is_empty() {
+            writer.write_restored(batch)?;
+        }
+        fs::remove_dir_all(marker)?;
+        Ok(())
     }
 
     /// Open or create a `KvStore`
@@ -229,97 +1657,236 @@ impl KvsEngine for KvStore {
 }
 
 impl KvStore {
-    /// Open a `KvStore` with the given path.
+    fn set_expiring(&self, key: Vec<u8>, value: Vec<u8>, expire_at: Option<u64>) -> Result<()> {
+        match &self.group {
+            Some(group) => {
+                let cmd = Command::Set {
+                    key,
+                    value,
+                    expire_at,
+                };
+                self.commit(group, cmd)
+            }
+            None => self.writer.lock().unwrap().set(key, value, expire_at),
+        }
+    }
+
+    /// Write a command by group commit with other concurrent writers
+    fn commit(&self, group: &GroupCommit<Command, Result<Position>>, cmd: Command) -> Result<()> {
+        group
+            .commit(cmd, |cmds| self.writer.lock().unwrap().write_group(cmds))
+            .unwrap_or_else(|| Err(io::Error::other("leader of the group commit panicked").into()))
+            .map(|_| ())
+    }
+
+    /// Compact every block with dead bytes now, regardless of the thresholds
+    ///
+    /// It waits for a running background compaction first, and returns
+    /// when the compaction is completed.
+    pub fn compact(&self) -> Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        match &self.writer.lock().unwrap().compaction {
+            Some(compaction) => {
+                let _ = compaction.send(CompactionRequest::Manual(sender));
+            }
+            None => return Ok(()),
+        }
+        receiver.recv().unwrap_or(Ok(()))
+    }
+
+    /// Get the space usage of the store
+    pub fn stats(&self) -> KvStoreStats {
+        let writer = self.writer.lock().unwrap();
+        let now = now_millis();
+        KvStoreStats {
+            live_keys: self
+                .reader
+                .iter()
+                .filter(|entry| !entry.value().is_expired(now))
+                .count() as u64,
+            live_bytes: writer.total - writer.uncompacted,
+            dead_bytes: writer.uncompacted,
+            generation: writer.gen,
+            blocks: writer.blocks.len() as u64,
+        }
+    }
+
+    /// Open a `KvStore` with the given path and default options.
     ///
     /// If the given path doesn't exist, it will create one.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Open a `KvStore` with the given path and options.
     ///
-    /// # Errors
+    /// If the given path doesn't exist, it will create one.
+    /// A generation written in the legacy JSON format is upgraded
+    /// into a new generation of the binary format first.
+    /// A torn record at the tail of the newest block, left by a crash
+    /// during write, is truncated.
     ///
+    /// # Errors
     ///
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let (index_r, mut index) = evmap::new();
-        let mut uncompacted = 0u64;
+    /// Return `KvsError::CorruptedRecord` if any other log record fails the checksum.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let index = SkipMap::new();
         let path = path.into();
         // get store generation of given path
-        let gen = get_generation(&path)?;
-        // get block num of current generation
-        let files = get_log_files(&path.join(get_store_dir_by(gen)))?;
-        let current_block = if files.is_empty() {
-            0
-        } else {
-            (files.len() - 1) as u64
-        };
+        let mut gen = get_generation(&path)?;
+        match record::read_format_version(&path.join(get_store_dir_by(gen)))? {
+            record::FORMAT_VERSION => {}
+            record::LEGACY_FORMAT_VERSION => {
+                gen = upgrade_legacy_generation(&path, gen, options.block_threshold)?
+            }
+            version => return Err(KvsError::UnsupportedFormat(version.to_string())),
+        }
+        // get blocks of current generation, dropping files of an interrupted compaction
+        let dir = path.join(get_store_dir_by(gen));
+        remove_stale_files(&dir)?;
+        let files = get_log_files(&dir)?;
+        let current_block = files.last().map_or(0, |(block, _)| *block);
 
-        // build index from files
-        if !files.is_empty() {
-            uncompacted += load_index_from_files(&files, &mut index, gen)?;
+        // build index from hint files and log files
+        let (positions, mut blocks) = load_index_from_files(&dir, &files, gen, &options.logger)?;
+        // versions start from the clock, so versions seen before the store
+        // is reopened are not given to keys again
+        let version = now_millis() << 20;
+        for (key, pos) in positions {
+            index.insert(key, Position { version, ..pos });
         }
+        blocks.entry(current_block).or_default();
+        let uncompacted = blocks.values().map(|stat| stat.dead).sum();
+        let total = blocks.values().map(|stat| stat.size).sum();
 
         // create BufWriter
-        let mut writer = if files.is_empty() {
-            BufWriter::new(
-                File::options()
-                    .create(true)
-                    .write(true)
-                    .open(path.join(get_file_path(gen, 0)))?,
-            )
-        } else {
-            BufWriter::new(
-                File::options()
-                    .write(true)
-                    .open(&files[current_block as usize])?,
-            )
-        };
+        let mut writer = BufWriter::new(
+            File::options()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path.join(get_file_path(gen, current_block)))?,
+        );
 
         writer.seek(SeekFrom::End(0))?;
 
-        let reader = KvStoreReader(index_r);
-
-        Ok(KvStore {
-            writer: Arc::new(Mutex::new(KvStoreWriter {
-                writer,
-                current_block,
-                uncompacted,
-                gen,
-                index,
-                path: path.to_owned(),
-                reader: reader.clone(),
-            })),
+        let reader = KvStoreReader {
+            index: Arc::new(index),
+            batch: Arc::new(RwLock::new(())),
+        };
+
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
+            writer,
+            current_block,
+            uncompacted,
+            total,
+            blocks,
+            gen,
+            path: path.to_owned(),
+            reader: reader.clone(),
+            sync: options.sync,
+            unsynced: false,
+            compaction_threshold: options.compaction_threshold,
+            compaction_ratio: options.compaction_ratio,
+            compaction_interval: options.compaction_interval,
+            block_threshold: options.block_threshold,
+            last_compaction: None,
+            compacting: false,
+            compaction: None,
+            pins: Arc::new(Mutex::new(Pins::default())),
+            version,
+            watchers: Vec::new(),
+            copies: HashMap::new(),
+            next_copy: 0,
+        }));
+
+        let compactor = spawn_compaction_thread(
+            Arc::downgrade(&writer),
+            reader.clone(),
+            path.clone(),
+            options.logger.clone(),
+        )?;
+        writer.lock().unwrap().compaction = compactor.sender.clone();
+
+        if let SyncPolicy::Interval(ms) = options.sync {
+            spawn_sync_thread(
+                Arc::downgrade(&writer),
+                Duration::from_millis(ms),
+                options.logger.clone(),
+            )?;
+        }
+
+        let group = match options.sync {
+            SyncPolicy::GroupCommit => Some(Arc::new(GroupCommit::new())),
+            _ => None,
+        };
+
+        let store = KvStore {
+            writer,
             reader,
             path,
-        })
+            group,
+            _compactor: Arc::new(compactor),
+        };
+        // a crash while a backup was copied left a part of the copy only
+        let tmp_marker = store.path.join(format!("{}.tmp", RESTORE_MARKER));
+        if tmp_marker.is_dir() {
+            fs::remove_dir_all(tmp_marker)?;
+        }
+        // a restore interrupted by a crash left a part of the backup only
+        let marker = store.path.join(RESTORE_MARKER);
+        if marker.is_dir() {
+            warn!(
+                options.logger,
+                "Restoring {} again after an interrupted restore",
+                marker.display()
+            );
+            store.restore_from(marker)?;
+        }
+        Ok(store)
     }
 }
 
-/// Command log object for store
-#[derive(Debug, Serialize, Deserialize)]
-enum Command {
-    /// Set the value of a string key to a string
-    Set {
-        /// A string key
-        key: String,
-        /// A string value of the key
-        value: String,
-    },
-    /// Remove a given key
-    Rm {
-        /// A string key
-        key: String,
-    },
+/// Sync the active block periodically until the store is dropped
+fn spawn_sync_thread(
+    writer: Weak<Mutex<KvStoreWriter>>,
+    interval: Duration,
+    logger: Logger,
+) -> Result<()> {
+    thread::Builder::new()
+        .name("kvs-sync".to_owned())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let writer = match writer.upgrade() {
+                Some(writer) => writer,
+                None => break,
+            };
+            let result = writer.lock().unwrap().sync();
+            if let Err(e) = result {
+                warn!(logger, "Sync logs failed: {}", e);
+            }
+        })?;
+    Ok(())
 }
 
 /// Log entry's position in files
-#[derive(PartialEq, Eq, Hash, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
 struct Position {
     gen: u64,
+    /// Block number
     file: u64,
     position: u64,
     size: u64,
+    /// When the key of a `Set` record expires, in milliseconds since the unix epoch
+    expire_at: Option<u64>,
+    /// Version of the key, which is new for every write of the key and kept
+    /// by compaction
+    version: u64,
 }
 
-impl ShallowCopy for Position {
-    unsafe fn shallow_copy(&self) -> ManuallyDrop<Self> {
-        ManuallyDrop::new(*self)
+impl Position {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expire_at.is_some_and(|expire_at| expire_at <= now)
     }
 }
 
@@ -331,34 +1898,117 @@ fn get_store_dir_by(gen: u64) -> String {
     format!("gen_{}", gen)
 }
 
-fn read_string_from(file_path: PathBuf, position: u64, size: u64) -> Result<String> {
-    let mut buf = String::new();
+/// Decode the value of a `Set` record read from `file_path` at `pos`
+fn decode_value(buf: &[u8], file_path: PathBuf, pos: &Position) -> Result<Vec<u8>> {
+    match record::decode(buf) {
+        Some(Command::Set { value, .. }) => Ok(value),
+        Some(Command::Rm { .. } | Command::Batch(_)) => unreachable!(),
+        None => Err(KvsError::CorruptedRecord {
+            path: file_path,
+            offset: pos.position,
+        }),
+    }
+}
+
+fn read_bytes_from(file_path: &Path, position: u64, size: u64) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(size as usize);
     let mut reader = File::open(file_path)?;
 
     reader.seek(SeekFrom::Start(position))?;
-    reader.take(size).read_to_string(&mut buf)?;
+    reader.take(size).read_to_end(&mut buf)?;
 
     Ok(buf)
 }
 
-fn get_log_files(path: &Path) -> Result<Vec<PathBuf>> {
+/// Create an empty generation directory tagged with current format version
+fn create_generation_dir(path: &Path, gen: u64) -> Result<()> {
+    let dir = path.join(get_store_dir_by(gen));
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+    record::write_format_version(&dir)
+}
+
+/// Get log blocks of a generation directory, sorted by block number
+fn get_log_files(path: &Path) -> Result<Vec<(u64, PathBuf)>> {
     let mut log_files = fs::read_dir(path)?
-        .filter(|p| p.is_ok())
-        .map(|res| res.unwrap().path())
-        .filter(|p| p.is_file() && p.extension() == Some("log".as_ref()) && p.file_stem().is_some())
-        .collect::<Vec<PathBuf>>();
-    log_files.sort();
+        .filter_map(|res| res.ok())
+        .map(|entry| entry.path())
+        .filter(|p| p.is_file() && p.extension() == Some("log".as_ref()))
+        .filter_map(|p| {
+            let block = p.file_stem()?.to_str()?.parse::<u64>().ok()?;
+            Some((block, p))
+        })
+        .collect::<Vec<(u64, PathBuf)>>();
+    log_files.sort_unstable();
     Ok(log_files)
 }
 
+/// Remove files left by an interrupted compaction, and hint files whose
+/// blocks are gone
+fn remove_stale_files(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let stale = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("compacting") | Some("tmp") | Some("pinned") => true,
+            Some("hint") => !path.with_extension("log").exists(),
+            _ => false,
+        };
+        if stale && path.is_file() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy the backup generation `dir` into the store at `path` as the marker
+/// of a restore, which replaces the copy of an earlier restore
+fn copy_restored(dir: &Path, path: &Path) -> Result<()> {
+    let tmp_path = path.join(format!("{}.tmp", RESTORE_MARKER));
+    if tmp_path.exists() {
+        fs::remove_dir_all(&tmp_path)?;
+    }
+    let tmp_dir = tmp_path.join(get_store_dir_by(0));
+    fs::create_dir_all(&tmp_dir)?;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            let copy = tmp_dir.join(entry.file_name());
+            fs::copy(entry.path(), &copy)?;
+            File::open(copy)?.sync_all()?;
+        }
+    }
+    let marker = path.join(RESTORE_MARKER);
+    if marker.exists() {
+        fs::remove_dir_all(&marker)?;
+    }
+    fs::rename(tmp_path, marker)?;
+    Ok(())
+}
+
+/// Get the path of a compacted block kept for open snapshots
+fn get_pinned_path(dir: &Path, block: u64) -> PathBuf {
+    dir.join(format!("{}.log.pinned", block))
+}
+
+/// Get the path of a block being written by compaction
+fn get_compacting_path(dir: &Path, block: u64) -> PathBuf {
+    dir.join(format!("{}.log.compacting", block))
+}
+
 /// Get generation from existent store path
 /// - case 1: 0 generation dir, a new store
-/// - case 2: 1 generation dir, no compaction happened
-/// - case 3: 2 generation dirs, compaction happend and succeed
-/// - case 4: 3 generation dirs, compaction happend but not completed
+/// - case 2: 1 generation dir, the store is not upgraded
+/// - case 3: 2 or more generation dirs, an upgrade or compaction happend and succeed
+///
+/// Blocks are compacted in place, and a generation dir only appears by
+/// renaming a completed upgrade, so the newest one is used. Legacy JSON
+/// stores compacted into new generations instead, where 3 generation dirs
+/// mean the compaction was not completed.
 fn get_generation(path: &Path) -> Result<u64> {
     if !path.exists() {
-        fs::create_dir_all(path.join(get_store_dir_by(0)))?;
+        create_generation_dir(path, 0)?;
         return Ok(0);
     }
     let mut gens = fs::read_dir(path)?
@@ -371,23 +2021,178 @@ fn get_generation(path: &Path) -> Result<u64> {
 
     match gens.len() {
         0 => {
-            fs::create_dir_all(path.join(get_store_dir_by(0)))?;
+            create_generation_dir(path, 0)?;
             Ok(0)
         }
-        1 => Ok(gens[0]),
-        2 => Ok(gens[1]),
-        _ => Ok(gens[gens.len() - 2]),
+        1 | 2 => Ok(gens[gens.len() - 1]),
+        n => {
+            let newest = path.join(get_store_dir_by(gens[n - 1]));
+            if record::read_format_version(&newest)? == record::LEGACY_FORMAT_VERSION {
+                Ok(gens[n - 2])
+            } else {
+                Ok(gens[n - 1])
+            }
+        }
     }
 }
 
-/// Traverse log files and execute the command, build the index in memory
+/// Copy an error shared by a group of writers
+fn shared_io_error(e: &KvsError) -> io::Error {
+    match e {
+        KvsError::Io(e) => io::Error::new(e.kind(), e.to_string()),
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+/// Load records of every block, from its hint file if it has a valid one,
+/// and build the index and the statistics of blocks
 fn load_index_from_files(
-    files: &[PathBuf],
-    index: &mut WriteHandle<String, Position>,
+    dir: &Path,
+    files: &[(u64, PathBuf)],
     gen: u64,
-) -> Result<u64> {
-    let mut uncompacted = 0u64;
-    for (i, file) in files.iter().enumerate() {
+    logger: &Logger,
+) -> Result<(Index, BTreeMap<u64, BlockStat>)> {
+    let mut index = HashMap::new();
+    let mut blocks = BTreeMap::new();
+    for (i, (block, file)) in files.iter().enumerate() {
+        let len = fs::metadata(file)?.len();
+        let entries = match hint::read_hint(dir, *block)? {
+            Some(entries) if entries.iter().map(|entry| entry.size).sum::<u64>() == len => entries,
+            _ => read_block_entries(file, i == files.len() - 1, logger)?,
+        };
+
+        // a torn tail may be truncated, and headers of batches are garbage
+        let size = fs::metadata(file)?.len();
+        let dead = size - entries.iter().map(|entry| entry.size).sum::<u64>();
+        blocks.insert(*block, BlockStat { size, dead });
+        for entry in entries {
+            let old = if entry.removed {
+                blocks.get_mut(block).unwrap().dead += entry.size;
+                index.remove(&entry.key)
+            } else {
+                let pos = Position {
+                    gen,
+                    file: *block,
+                    position: entry.position,
+                    size: entry.size,
+                    expire_at: entry.expire_at,
+                    version: 0,
+                };
+                index.insert(entry.key, pos)
+            };
+            if let Some(old) = old {
+                blocks.get_mut(&old.file).unwrap().dead += old.size;
+            }
+        }
+    }
+
+    // records of expired keys are garbage, and dropped by compaction
+    let now = now_millis();
+    index.retain(|_, pos| {
+        if pos.is_expired(now) {
+            blocks.get_mut(&pos.file).unwrap().dead += pos.size;
+        }
+        !pos.is_expired(now)
+    });
+
+    Ok((index, blocks))
+}
+
+/// Read all records of a block
+///
+/// A corrupted tail of the newest block is truncated to the last good record.
+fn read_block_entries(file: &Path, newest: bool, logger: &Logger) -> Result<Vec<HintEntry>> {
+    let mut reader = BufReader::new(File::open(file)?);
+    let mut entries = Vec::new();
+    let mut position = 0u64;
+    loop {
+        let (cmd, size) = match record::read_record(&mut reader)? {
+            ReadRecord::Record(cmd, size) => (cmd, size),
+            ReadRecord::Eof => break,
+            ReadRecord::Corrupt if newest && is_torn_tail(file, position)? => {
+                let len = fs::metadata(file)?.len();
+                File::options().write(true).open(file)?.set_len(position)?;
+                warn!(
+                    logger,
+                    "Truncated torn tail of {:?} at offset {}, dropped {} bytes",
+                    file,
+                    position,
+                    len - position
+                );
+                break;
+            }
+            ReadRecord::Corrupt => {
+                return Err(KvsError::CorruptedRecord {
+                    path: file.to_owned(),
+                    offset: position,
+                })
+            }
+        };
+        // a batch is read as a whole, so it is replayed atomically
+        for (cmd, offset, size) in cmd.into_records(position, size) {
+            let (key, removed, expire_at) = match cmd {
+                Command::Set { key, expire_at, .. } => (key, false, expire_at),
+                Command::Rm { key } => (key, true, None),
+                Command::Batch(_) => unreachable!(),
+            };
+            entries.push(HintEntry {
+                key,
+                removed,
+                position: offset,
+                size,
+                expire_at,
+            });
+        }
+        position += size;
+    }
+
+    Ok(entries)
+}
+
+/// Check whether the corrupted record at `offset` is a torn tail
+///
+/// It is if no valid record follows it, which is what an interrupted
+/// append leaves behind, whether the file ends within it or zeros follow.
+/// A record whose length is corrupted may claim to reach the end of file,
+/// so the rest of the file is searched for a valid record, except the
+/// records framed in a torn batch, which are valid on their own.
+fn is_torn_tail(file: &Path, offset: u64) -> Result<bool> {
+    let mut reader = File::open(file)?;
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut tail = Vec::new();
+    reader.read_to_end(&mut tail)?;
+
+    let mut start = 1;
+    if tail.get(record::HEADER_SIZE) == Some(&record::TAG_BATCH) {
+        start = record::BATCH_HEADER_SIZE as usize;
+        while let Some(size) = valid_record_size(&tail[start..]) {
+            start += size;
+        }
+    }
+    Ok((start..tail.len()).all(|start| valid_record_size(&tail[start..]).is_none()))
+}
+
+/// Get the size of the valid record at the start of `buf`, if there is one
+fn valid_record_size(buf: &[u8]) -> Option<usize> {
+    let header = buf.get(..record::HEADER_SIZE)?.try_into().unwrap();
+    let size = usize::try_from(record::record_size(header)).ok()?;
+    match record::read_record(&mut buf.get(..size)?) {
+        Ok(ReadRecord::Record(_, _)) => Some(size),
+        _ => None,
+    }
+}
+
+/// Rewrite a generation of legacy JSON logs into a new binary generation
+///
+/// The new generation is written into a temporary directory and renamed
+/// when completed, so a crash during upgrade leaves the legacy one intact.
+/// Return the new generation.
+fn upgrade_legacy_generation(path: &Path, gen: u64, block_threshold: u64) -> Result<u64> {
+    let files = get_log_files(&path.join(get_store_dir_by(gen)))?;
+
+    // replay JSON lines to find the position of every live key
+    let mut live = HashMap::new();
+    for (i, (_, file)) in files.iter().enumerate() {
         let mut reader = BufReader::new(File::open(file)?);
         loop {
             let mut buf = String::new();
@@ -396,35 +2201,54 @@ fn load_index_from_files(
             if size == 0 {
                 break;
             }
-            match serde_json::from_str::<Command>(buf.trim_end())? {
-                Command::Set { key, value: _ } => {
-                    index.update(
-                        key.clone(),
-                        Position {
-                            gen,
-                            file: i as u64,
-                            position,
-                            size: size as u64,
-                        },
-                    );
-
-                    if let Some(pos) = index.get_one(&key) {
-                        uncompacted += pos.size;
-                    }
+            match serde_json::from_str::<LegacyCommand>(buf.trim_end())? {
+                LegacyCommand::Set { key, value: _ } => {
+                    live.insert(key, (i, position, size as u64));
                 }
-                Command::Rm { key } => {
-                    let exist_size = if let Some(pos) = index.get_one(&key) {
-                        pos.size
-                    } else {
-                        0
-                    };
-                    index.empty(key);
-                    uncompacted += size as u64 + exist_size;
+                LegacyCommand::Rm { key } => {
+                    live.remove(&key);
                 }
             }
-            index.refresh();
         }
     }
 
-    Ok(uncompacted)
+    let new_gen = gen + 1;
+    let tmp_dir = path.join(format!("{}.upgrade", get_store_dir_by(new_gen)));
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir)?;
+    }
+    fs::create_dir_all(&tmp_dir)?;
+    record::write_format_version(&tmp_dir)?;
+
+    let mut block = 0u64;
+    let mut writer = BufWriter::new(File::create(tmp_dir.join(format!("{}.log", block)))?);
+    let mut written = 0u64;
+    for (file, position, size) in live.into_values() {
+        let buf = read_bytes_from(&files[file].1, position, size)?;
+        let cmd = serde_json::from_slice::<LegacyCommand>(buf.trim_ascii_end())?;
+        let buf = record::encode(&cmd.into())?;
+        if written > 0 && written + buf.len() as u64 > block_threshold {
+            writer
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+            block += 1;
+            written = 0;
+            writer = BufWriter::new(File::create(tmp_dir.join(format!("{}.log", block)))?);
+        }
+        writer.write_all(&buf)?;
+        written += buf.len() as u64;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+
+    // keep only the legacy generation as backup, like compaction does
+    if gen > 0 && path.join(get_store_dir_by(gen - 1)).exists() {
+        remove_dir_all(path.join(get_store_dir_by(gen - 1)))?;
+    }
+    fs::rename(&tmp_dir, path.join(get_store_dir_by(new_gen)))?;
+
+    Ok(new_gen)
 }
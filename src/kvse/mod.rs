@@ -1,28 +1,363 @@
+use serde::{Deserialize, Serialize};
+use std::ops::RangeBounds;
 use std::path::PathBuf;
+use std::result::Result as StdResult;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::Result;
 
+mod group_commit;
+mod hint;
 mod kvs;
+mod record;
 mod sled;
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+pub use self::kvs::{KvStore, KvStoreOptions, KvStoreSnapshot, KvStoreStats, KvStoreTransaction};
+pub use self::sled::{SledKvsEngine, SledSnapshot, SledTransaction};
+
+/// Policy of syncing written data to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Never sync explicitly, leave it to the operating system
+    Never,
+    /// Sync after every write
+    Always,
+    /// Sync by a background thread every given milliseconds
+    Interval(u64),
+    /// Sync after every write, batching concurrent writes into one sync
+    GroupCommit,
+}
+
+/// A write of a `WriteBatch`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// Set the value of a key
+    Set {
+        /// A byte string key
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// A byte string value of the key
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    /// Remove a key, which is not an error if the key does not exist
+    Rm {
+        /// A byte string key
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+}
+
+/// Writes of several keys applied atomically by `KvsEngine::write_batch`
+///
+/// Writes are applied in the order they are added, so a later write of
+/// a key overrides an earlier one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// Create an empty batch
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Add setting the value of a key
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Add removing a key
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Rm { key: key.into() });
+        self
+    }
+
+    /// Get the number of writes
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether the batch has no writes
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Get the writes in order
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// Take the writes in order
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
+
+impl From<Vec<BatchOp>> for WriteBatch {
+    fn from(ops: Vec<BatchOp>) -> Self {
+        WriteBatch { ops }
+    }
+}
+
+/// Iterator of key-value pairs returned by scans, in key order
+pub type ScanIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
+
+/// Iterator of keys returned by `KvsEngine::scan_keys`, in key order
+pub type KeyIter = Box<dyn Iterator<Item = Result<Vec<u8>>> + Send>;
+
+/// A write of a watched key, sent after the write is applied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchEvent {
+    /// The value of a key is set
+    Set {
+        /// A byte string key
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// A byte string value of the key
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    /// A key is removed
+    Rm {
+        /// A byte string key
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+}
+
+impl WatchEvent {
+    /// Get the key written
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WatchEvent::Set { key, .. } | WatchEvent::Rm { key } => key,
+        }
+    }
+}
+
+/// Blocking iterator of events returned by `KvsEngine::watch_prefix`, in
+/// the order of writes, which ends when the engine is closed
+pub struct WatchIter(Box<dyn WatchSource>);
+
+impl WatchIter {
+    pub(crate) fn new(source: impl WatchSource + 'static) -> Self {
+        WatchIter(Box::new(source))
+    }
+
+    /// Wait for the next event at most `timeout`
+    ///
+    /// Return `RecvTimeoutError::Disconnected` once the events have ended.
+    pub fn next_timeout(&mut self, timeout: Duration) -> StdResult<WatchEvent, RecvTimeoutError> {
+        self.0.next_timeout(timeout)
+    }
+}
+
+impl Iterator for WatchIter {
+    type Item = WatchEvent;
+
+    fn next(&mut self) -> Option<WatchEvent> {
+        loop {
+            match self.0.next_timeout(Duration::from_secs(60)) {
+                Ok(event) => return Some(event),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+/// Where a `WatchIter` receives its events from
+pub(crate) trait WatchSource: Send {
+    fn next_timeout(&mut self, timeout: Duration) -> StdResult<WatchEvent, RecvTimeoutError>;
+}
+
+impl WatchSource for Receiver<WatchEvent> {
+    fn next_timeout(&mut self, timeout: Duration) -> StdResult<WatchEvent, RecvTimeoutError> {
+        self.recv_timeout(timeout)
+    }
+}
+
+/// Statistics every `KvsEngine` reports
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KvsStats {
+    /// Number of live keys
+    pub keys: u64,
+    /// Bytes the engine takes on disk
+    pub disk_bytes: u64,
+}
+
+/// Read-only view of a `KvsEngine` at the time it is taken
+///
+/// Later writes are not visible to it, and keys live at that time never
+/// expire in it.
+pub trait KvsSnapshot: Clone + Send + 'static {
+    /// Get the value of a given key
+    /// Return `None` if the key does not exist
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>>;
+
+    /// Scan key-value pairs with keys in `range`, in key order
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter>;
+
+    /// Scan key-value pairs with keys starting with `prefix`, in key order
+    fn scan_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<ScanIter>;
+}
+
+/// Optimistic transaction of a `KvsEngine`, returned by `KvsEngine::begin`
+///
+/// Writes are buffered until `commit`, which applies them atomically, and
+/// dropping the transaction aborts it.
+pub trait KvsTransaction: Send + 'static {
+    /// Get the value of a given key, seeing writes of the transaction
+    /// Return `None` if the key does not exist
+    fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>>;
+
+    /// Set the value of a given key when the transaction commits
+    fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()>;
+
+    /// Remove a given key when the transaction commits,
+    /// which is not an error if the key does not exist
+    fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()>;
+
+    /// Apply the writes atomically
+    /// Return `KvsError::TransactionConflict` if any key read has changed since
+    fn commit(self) -> Result<()>;
+}
 
 /// Trait for a key value storage engine
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Read-only view returned by `snapshot`
+    type Snapshot: KvsSnapshot;
+
+    /// Optimistic transaction returned by `begin`
+    type Transaction: KvsTransaction;
+
     /// Get the value of a given key
     /// Return `None` if the key does not exist
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>>;
 
     /// Set the value of a given key
-    /// If the key exists, the value will be overwritten
-    fn set(&self, key: String, value: String) -> Result<()>;
+    /// If the key exists, the value and its expiry will be overwritten
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()>;
 
     /// Remove the value of a given key
     /// Return `KvsError::KeyNotFound` if the key does not exist
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()>;
+
+    /// Set the value of a given key, which expires after `ttl`
+    /// An expired key is gone as if it is removed
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()>;
+
+    /// Get the remaining time to live of a given key, `None` if it never expires
+    /// Return `KvsError::KeyNotFound` if the key does not exist
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>>;
+
+    /// Remove the expiry of a given key, so it never expires
+    /// Return `KvsError::KeyNotFound` if the key does not exist
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()>;
+
+    /// Set the expiry of a given key, keeping its value, so it expires after `ttl`
+    /// Return `KvsError::KeyNotFound` if the key does not exist
+    fn expire(&self, key: impl Into<Vec<u8>>, ttl: Duration) -> Result<()>;
+
+    /// Set the value of `key` to `new`, or remove it if `new` is `None`,
+    /// only if its current value is `expected`, where `None` means absent
+    /// Return `KvsError::CompareFailed` with the current value otherwise
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()>;
+
+    /// Set the value of `key` only if it does not exist
+    /// Return `KvsError::CompareFailed` with the current value otherwise
+    fn set_if_absent(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()>;
+
+    /// Set the value of `key` only if it exists
+    /// Return `KvsError::CompareFailed` otherwise
+    fn set_if_present(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()>;
+
+    /// Get the value of a given key with its version
+    /// Return `None` if the key does not exist
+    /// The version of a key differs after any write changing its value or expiry
+    fn get_versioned(&self, key: impl Into<Vec<u8>>) -> Result<Option<(Vec<u8>, u64)>>;
+
+    /// Set the value of `key`, expiring after `ttl` if given, only if its
+    /// version is `expected`, where `None` means absent
+    /// Return `KvsError::CompareFailed` with the current value otherwise
+    fn set_if_version(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Option<Duration>,
+        expected: Option<u64>,
+    ) -> Result<()>;
+
+    /// Apply writes of a batch atomically
+    /// Readers and a reopened store see all of the writes or none of them
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Scan key-value pairs with keys in `range`
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter>;
+
+    /// Scan key-value pairs with keys starting with `prefix`
+    fn scan_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<ScanIter>;
+
+    /// Scan keys in `range` without reading their values
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KeyIter>;
+
+    /// Reclaim space of overwritten and removed values now
+    fn compact(&self) -> Result<()>;
+
+    /// Get statistics of the engine
+    fn engine_stats(&self) -> Result<KvsStats>;
+
+    /// Take a read-only view of the engine, consistent across keys
+    ///
+    /// It is not free: taking it is linear in the number of keys in time and
+    /// memory. `KvStore` copies its index while writes go on, and
+    /// `SledKvsEngine` copies every value with writes blocked meanwhile.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// Begin an optimistic transaction of several keys
+    fn begin(&self) -> Result<Self::Transaction>;
+
+    /// Watch writes of keys starting with `prefix` from now on
+    /// Removing a key which does not exist may still send an event, and
+    /// keys gone by expiry may not
+    fn watch_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<WatchIter>;
+
+    /// Write a consistent, self-contained copy of the engine into `path`
+    /// Return `KvsError::Io` if `path` already holds a copy
+    fn backup_to(&self, path: impl Into<PathBuf>) -> Result<()>;
+
+    /// Replace every key of the engine by a copy written by `backup_to`
+    ///
+    /// Writes wait for the restore either way, but reads may not: a
+    /// `SledKvsEngine` swaps every key in one transaction, while a `KvStore`
+    /// writes the copy in batches of a block each, so reads meanwhile may
+    /// see it partly restored.
+    fn restore_from(&self, path: impl Into<PathBuf>) -> Result<()>;
 
     /// Open or create a store engine from given path
     /// Return a `KvsEngine` with `Result` wrapper
     fn open(path: impl Into<PathBuf>) -> Result<Self>;
 }
+
+/// Current unix time in milliseconds, the clock of key expiry
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
@@ -0,0 +1,346 @@
+//! On-disk record format of `KvStore` log files
+//!
+//! Every record is framed as:
+//!
+//! ```text
+//! +-----------+-----------+-------------------+
+//! | crc32 u32 | len u32   | payload (len)     |
+//! +-----------+-----------+-------------------+
+//! ```
+//!
+//! All integers are little endian, and the checksum covers `len` and `payload`.
+//! The payload of a batch record is a tag followed by framed `Set` and `Rm`
+//! records, so a batch is replayed as a whole or not at all, while each
+//! record inside it could be read on its own.
+//! Generations written before the binary format have no `VERSION` file and
+//! store one JSON encoded `Command` per line.
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::{KvsError, Result};
+
+/// Format version of newline separated JSON logs
+pub(super) const LEGACY_FORMAT_VERSION: u32 = 1;
+/// Format version of length-prefixed binary logs with CRC32
+pub(super) const FORMAT_VERSION: u32 = 2;
+
+const VERSION_FILE: &str = "VERSION";
+pub(super) const HEADER_SIZE: usize = 8;
+
+const TAG_SET: u8 = 1;
+const TAG_RM: u8 = 2;
+pub(super) const TAG_BATCH: u8 = 3;
+const TAG_SET_EXPIRING: u8 = 4;
+
+/// Size of the frame and tag of a batch record before its records
+pub(super) const BATCH_HEADER_SIZE: u64 = HEADER_SIZE as u64 + 1;
+
+/// Command log object for store
+#[derive(Debug)]
+pub(super) enum Command {
+    /// Set the value of a key
+    Set {
+        /// A binary key
+        key: Vec<u8>,
+        /// A binary value of the key
+        value: Vec<u8>,
+        /// When the key expires, in milliseconds since the unix epoch
+        expire_at: Option<u64>,
+    },
+    /// Remove a given key
+    Rm {
+        /// A binary key
+        key: Vec<u8>,
+    },
+    /// `Set` and `Rm` commands applied atomically
+    Batch(Vec<Command>),
+}
+
+/// Command log object of legacy JSON logs, which only hold strings
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum LegacyCommand {
+    /// Set the value of a string key to a string
+    Set {
+        /// A string key
+        key: String,
+        /// A string value of the key
+        value: String,
+    },
+    /// Remove a given key
+    Rm {
+        /// A string key
+        key: String,
+    },
+}
+
+impl From<LegacyCommand> for Command {
+    fn from(cmd: LegacyCommand) -> Self {
+        match cmd {
+            LegacyCommand::Set { key, value } => Command::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                expire_at: None,
+            },
+            LegacyCommand::Rm { key } => Command::Rm {
+                key: key.into_bytes(),
+            },
+        }
+    }
+}
+
+/// Result of reading one record from a log file
+pub(super) enum ReadRecord {
+    /// A valid record and its size in bytes, header included
+    Record(Command, u64),
+    /// The end of file is reached at a record boundary
+    Eof,
+    /// The record is truncated or fails the checksum
+    Corrupt,
+}
+
+impl Command {
+    /// Get the size of the framed record of the command
+    pub(super) fn encoded_len(&self) -> u64 {
+        let payload = match self {
+            Command::Set {
+                key,
+                value,
+                expire_at,
+            } => 1 + 4 + key.len() + 4 + value.len() + if expire_at.is_some() { 8 } else { 0 },
+            Command::Rm { key } => 1 + 4 + key.len(),
+            Command::Batch(cmds) => {
+                return BATCH_HEADER_SIZE + cmds.iter().map(Command::encoded_len).sum::<u64>()
+            }
+        };
+        (HEADER_SIZE + payload) as u64
+    }
+
+    /// Split a record at `position` into its commands with their positions
+    /// and sizes, where a batch yields the records framed inside it
+    pub(super) fn into_records(self, position: u64, size: u64) -> Vec<(Command, u64, u64)> {
+        match self {
+            Command::Batch(cmds) => {
+                let mut offset = position + BATCH_HEADER_SIZE;
+                cmds.into_iter()
+                    .map(|cmd| {
+                        let size = cmd.encoded_len();
+                        offset += size;
+                        (cmd, offset - size, size)
+                    })
+                    .collect()
+            }
+            cmd => vec![(cmd, position, size)],
+        }
+    }
+
+    fn encode_payload(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        match self {
+            Command::Set {
+                key,
+                value,
+                expire_at,
+            } => {
+                buf.push(if expire_at.is_some() {
+                    TAG_SET_EXPIRING
+                } else {
+                    TAG_SET
+                });
+                put_bytes(&mut buf, key)?;
+                put_bytes(&mut buf, value)?;
+                if let Some(expire_at) = expire_at {
+                    buf.extend_from_slice(&expire_at.to_le_bytes());
+                }
+            }
+            Command::Rm { key } => {
+                buf.push(TAG_RM);
+                put_bytes(&mut buf, key)?;
+            }
+            Command::Batch(cmds) => {
+                buf.push(TAG_BATCH);
+                for cmd in cmds {
+                    buf.extend_from_slice(&encode(cmd)?);
+                }
+            }
+        }
+        Ok(buf)
+    }
+
+    fn decode_payload(mut buf: &[u8]) -> Option<Command> {
+        let tag = *buf.first()?;
+        buf = &buf[1..];
+        if tag == TAG_BATCH {
+            return Command::decode_batch(buf);
+        }
+        let key = get_bytes(&mut buf)?.to_vec();
+        let cmd = match tag {
+            TAG_SET => {
+                let value = get_bytes(&mut buf)?.to_vec();
+                Command::Set {
+                    key,
+                    value,
+                    expire_at: None,
+                }
+            }
+            TAG_SET_EXPIRING => {
+                let value = get_bytes(&mut buf)?.to_vec();
+                let expire_at = u64::from_le_bytes(buf.get(..8)?.try_into().unwrap());
+                buf = &buf[8..];
+                Command::Set {
+                    key,
+                    value,
+                    expire_at: Some(expire_at),
+                }
+            }
+            TAG_RM => Command::Rm { key },
+            _ => return None,
+        };
+        if buf.is_empty() {
+            Some(cmd)
+        } else {
+            None
+        }
+    }
+
+    /// Decode the records of a batch, which are covered by its checksum
+    fn decode_batch(mut buf: &[u8]) -> Option<Command> {
+        let mut cmds = Vec::new();
+        while !buf.is_empty() {
+            let header: &[u8; HEADER_SIZE] = buf.get(..HEADER_SIZE)?.try_into().unwrap();
+            let size = record_size(header) as usize;
+            match decode(buf.get(..size)?)? {
+                Command::Batch(_) => return None,
+                cmd => cmds.push(cmd),
+            }
+            buf = &buf[size..];
+        }
+        Some(Command::Batch(cmds))
+    }
+}
+
+/// Encode a command into a framed record
+///
+/// Fail if a key, a value or the whole record is too long for its `u32`
+/// length.
+pub(super) fn encode(cmd: &Command) -> Result<Vec<u8>> {
+    let payload = cmd.encode_payload()?;
+    let len = encode_len(payload.len())?;
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&len);
+    hasher.update(&payload);
+
+    let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+    buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+    buf.extend_from_slice(&len);
+    buf.extend_from_slice(&payload);
+    Ok(buf)
+}
+
+/// Decode a whole framed record, verifying its checksum
+pub(super) fn decode(buf: &[u8]) -> Option<Command> {
+    match read_record(&mut &buf[..]) {
+        Ok(ReadRecord::Record(cmd, size)) if size == buf.len() as u64 => Some(cmd),
+        _ => None,
+    }
+}
+
+/// Get the size of a framed record, header included, from its header
+pub(super) fn record_size(header: &[u8; HEADER_SIZE]) -> u64 {
+    HEADER_SIZE as u64 + u32::from_le_bytes(header[4..].try_into().unwrap()) as u64
+}
+
+/// Read the next framed record from `reader`
+pub(super) fn read_record<R: Read>(reader: &mut R) -> io::Result<ReadRecord> {
+    let mut header = [0u8; HEADER_SIZE];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(ReadRecord::Eof),
+        HEADER_SIZE => {}
+        _ => return Ok(ReadRecord::Corrupt),
+    }
+
+    let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as u64;
+
+    let mut payload = Vec::new();
+    if reader.take(len).read_to_end(&mut payload)? as u64 != len {
+        return Ok(ReadRecord::Corrupt);
+    }
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[4..]);
+    hasher.update(&payload);
+    if hasher.finalize() != crc {
+        return Ok(ReadRecord::Corrupt);
+    }
+
+    Ok(match Command::decode_payload(&payload) {
+        Some(cmd) => ReadRecord::Record(cmd, HEADER_SIZE as u64 + len),
+        None => ReadRecord::Corrupt,
+    })
+}
+
+/// Read the format version of a generation directory
+///
+/// A directory without `VERSION` file is written by the legacy JSON format.
+pub(super) fn read_format_version(dir: &Path) -> Result<u32> {
+    let path = dir.join(VERSION_FILE);
+    if !path.exists() {
+        return Ok(LEGACY_FORMAT_VERSION);
+    }
+    let version = fs::read_to_string(path)?;
+    version
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| KvsError::UnsupportedFormat(version.trim().to_owned()))
+}
+
+/// Write current format version into a generation directory
+pub(super) fn write_format_version(dir: &Path) -> Result<()> {
+    let mut file = File::create(dir.join(VERSION_FILE))?;
+    writeln!(file, "{}", FORMAT_VERSION)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    buf.extend_from_slice(&encode_len(bytes.len())?);
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn encode_len(len: usize) -> Result<[u8; 4]> {
+    u32::try_from(len)
+        .map(u32::to_le_bytes)
+        .map_err(|_| KvsError::Serialize("Too long for a record".to_owned()))
+}
+
+fn get_bytes<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let len = u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize;
+    if buf.len() < 4 + len {
+        return None;
+    }
+    let bytes = &buf[4..4 + len];
+    *buf = &buf[4 + len..];
+    Some(bytes)
+}
+
+/// Fill `buf` as much as possible, return the number of bytes read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
@@ -1,44 +1,723 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::ops::RangeBounds;
 use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use crate::{KvsEngine, KvsError, Result};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
+use sled::{IVec, Transactional, Tree};
+
+use crate::kvse::{now_millis, BatchOp, KeyIter, KvsStats, WatchEvent, WatchSource, WriteBatch};
+use crate::{
+    KvsEngine, KvsError, KvsSnapshot, KvsTransaction, Result, ScanIter, SyncPolicy, WatchIter,
+};
+
+/// Interval of the background sweeper removing expired keys
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Result of a transaction over values, their expiry and versions
+type TxResult<T> = ConflictableTransactionResult<T, KvsError>;
 
 /// SledKvsEngine by `sled::Db`
+///
+/// Expiry of keys is kept in a separate `expiry` tree, as unix milliseconds
+/// in little endian, and written in the same transaction as values. So are
+/// versions of keys in a `versions` tree, where every write of a key gives
+/// it a new id of `generate_id`, never given to any key before.
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: sled::Db,
+    expiry: Tree,
+    versions: Tree,
+    sync: SyncPolicy,
+    /// Held shared by writes and exclusively while a snapshot is copied
+    gate: Arc<RwLock<()>>,
+    _sweeper: Arc<Sweeper>,
 }
 
 #[allow(dead_code)]
 impl SledKvsEngine {
-    /// Create a SledKvsEngine by `sled::open`
+    /// Create a SledKvsEngine by `sled::open`, which flushes every write
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        SledKvsEngine::open_with(path, SyncPolicy::Always)
+    }
+
+    /// Create a SledKvsEngine with the given sync policy
+    ///
+    /// `SyncPolicy::Interval` is mapped to the periodic flush of sled.
+    /// `SyncPolicy::GroupCommit` flushes every write like `SyncPolicy::Always`,
+    /// since sled already merges concurrent flushes.
+    pub fn open_with(path: impl Into<PathBuf>, sync: SyncPolicy) -> Result<SledKvsEngine> {
+        let flush_every_ms = match sync {
+            SyncPolicy::Interval(ms) => Some(ms),
+            SyncPolicy::Never | SyncPolicy::Always | SyncPolicy::GroupCommit => None,
+        };
+        let db = sled::Config::new()
+            .path(path.into())
+            .flush_every_ms(flush_every_ms)
+            .open()?;
+        let expiry = db.open_tree("expiry")?;
+        let versions = db.open_tree("versions")?;
+        let gate = Arc::new(RwLock::new(()));
+        let sweeper = spawn_sweeper_thread(
+            db.clone(),
+            expiry.clone(),
+            versions.clone(),
+            Arc::clone(&gate),
+        )?;
         Ok(SledKvsEngine {
-            db: sled::open(path.into())?,
+            db,
+            expiry,
+            versions,
+            sync,
+            gate,
+            _sweeper: Arc::new(sweeper),
         })
     }
+
+    fn flush(&self) -> Result<()> {
+        if let SyncPolicy::Always | SyncPolicy::GroupCommit = self.sync {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Run a transaction over values, their expiry and versions, then flush it
+    fn transaction<T>(
+        &self,
+        f: impl Fn(&TransactionalTree, &TransactionalTree, &TransactionalTree) -> TxResult<T>,
+    ) -> Result<T> {
+        let _gate = self.gate.read().unwrap();
+        let result = (&*self.db, &self.expiry, &self.versions)
+            .transaction(|(db, expiry, versions)| f(db, expiry, versions))
+            .map_err(transaction_error)?;
+        self.flush()?;
+        Ok(result)
+    }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn get(&self, key: String) -> Result<Option<String>> {
-        Ok(self
-            .db
-            .get(&key)?
-            .map(|val| String::from_utf8_lossy(val.as_ref()).to_string()))
+    type Snapshot = SledSnapshot;
+    type Transaction = SledTransaction;
+
+    /// Read the expiry of the key, then its value, without a transaction.
+    /// A write in between reads as the expired old value or as the new one.
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        if is_expired(self.expiry.get(&key)?, now_millis()) {
+            return Ok(None);
+        }
+        Ok(self.db.get(&key)?.map(|val| val.to_vec()))
+    }
+
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        self.transaction(|db, expiry, versions| {
+            db.insert(key.as_slice(), value.as_slice())?;
+            expiry.remove(key.as_slice())?;
+            new_version(versions, &key)
+        })
+    }
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        let now = now_millis();
+        self.transaction(|db, expiry, versions| {
+            if live_value(db, expiry, &key, now)?.is_none() {
+                return Err(ConflictableTransactionError::Abort(KvsError::KeyNotFound));
+            }
+            db.remove(key.as_slice())?;
+            expiry.remove(key.as_slice())?;
+            versions.remove(key.as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let expire_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.transaction(|db, expiry, versions| {
+            db.insert(key.as_slice(), value.as_slice())?;
+            expiry.insert(key.as_slice(), &expire_at.to_le_bytes())?;
+            new_version(versions, &key)
+        })
+    }
+
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>> {
+        let key = key.into();
+        let now = now_millis();
+        let expire_at = self.transaction(|db, expiry, _| {
+            if live_value(db, expiry, &key, now)?.is_none() {
+                return Err(ConflictableTransactionError::Abort(KvsError::KeyNotFound));
+            }
+            Ok(expiry.get(key.as_slice())?.map(|at| decode_u64(&at)))
+        })?;
+        Ok(expire_at.map(|expire_at| Duration::from_millis(expire_at.saturating_sub(now))))
+    }
+
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        let now = now_millis();
+        self.transaction(|db, expiry, versions| {
+            if live_value(db, expiry, &key, now)?.is_none() {
+                return Err(ConflictableTransactionError::Abort(KvsError::KeyNotFound));
+            }
+            expiry.remove(key.as_slice())?;
+            new_version(versions, &key)
+        })
+    }
+
+    fn expire(&self, key: impl Into<Vec<u8>>, ttl: Duration) -> Result<()> {
+        let key = key.into();
+        let now = now_millis();
+        let expire_at = now.saturating_add(ttl.as_millis() as u64);
+        self.transaction(|db, expiry, versions| {
+            if live_value(db, expiry, &key, now)?.is_none() {
+                return Err(ConflictableTransactionError::Abort(KvsError::KeyNotFound));
+            }
+            expiry.insert(key.as_slice(), &expire_at.to_le_bytes())?;
+            new_version(versions, &key)
+        })
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let key = key.into();
+        let now = now_millis();
+        self.transaction(|db, expiry, versions| {
+            let current = live_value(db, expiry, &key, now)?;
+            if current.as_deref() != expected.as_deref() {
+                return Err(ConflictableTransactionError::Abort(
+                    KvsError::CompareFailed {
+                        current: current.map(|val| val.to_vec()),
+                    },
+                ));
+            }
+            expiry.remove(key.as_slice())?;
+            match &new {
+                Some(new) => {
+                    db.insert(key.as_slice(), new.as_slice())?;
+                    new_version(versions, &key)
+                }
+                None => {
+                    db.remove(key.as_slice())?;
+                    versions.remove(key.as_slice())?;
+                    Ok(())
+                }
+            }
+        })
     }
 
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.insert(&key, &*value)?;
+    fn set_if_absent(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        self.compare_and_swap(key, None, Some(value.into()))
+    }
+
+    fn set_if_present(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let now = now_millis();
+        self.transaction(|db, expiry, versions| {
+            if live_value(db, expiry, &key, now)?.is_none() {
+                return Err(ConflictableTransactionError::Abort(
+                    KvsError::CompareFailed { current: None },
+                ));
+            }
+            db.insert(key.as_slice(), value.as_slice())?;
+            expiry.remove(key.as_slice())?;
+            new_version(versions, &key)
+        })
+    }
+
+    fn get_versioned(&self, key: impl Into<Vec<u8>>) -> Result<Option<(Vec<u8>, u64)>> {
+        let key = key.into();
+        let now = now_millis();
+        (&*self.db, &self.expiry, &self.versions)
+            .transaction(|(db, expiry, versions)| live_version(db, expiry, versions, &key, now))
+            .map_err(transaction_error)
+    }
+
+    fn set_if_version(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Option<Duration>,
+        expected: Option<u64>,
+    ) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let now = now_millis();
+        let expire_at = ttl.map(|ttl| now.saturating_add(ttl.as_millis() as u64));
+        self.transaction(|db, expiry, versions| {
+            let current = live_version(db, expiry, versions, &key, now)?;
+            if current.as_ref().map(|(_, version)| *version) != expected {
+                return Err(ConflictableTransactionError::Abort(
+                    KvsError::CompareFailed {
+                        current: current.map(|(value, _)| value),
+                    },
+                ));
+            }
+            db.insert(key.as_slice(), value.as_slice())?;
+            match expire_at {
+                Some(expire_at) => expiry.insert(key.as_slice(), &expire_at.to_le_bytes())?,
+                None => expiry.remove(key.as_slice())?,
+            };
+            new_version(versions, &key)
+        })
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.transaction(|db, expiry, versions| {
+            for op in batch.ops() {
+                match op {
+                    BatchOp::Set { key, value } => {
+                        db.insert(key.as_slice(), value.as_slice())?;
+                        expiry.remove(key.as_slice())?;
+                        new_version(versions, key)?;
+                    }
+                    BatchOp::Rm { key } => {
+                        db.remove(key.as_slice())?;
+                        expiry.remove(key.as_slice())?;
+                        versions.remove(key.as_slice())?;
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let (expiry, now) = (self.expiry.clone(), now_millis());
+        Ok(Box::new(
+            self.db
+                .range(range)
+                .map(to_pair)
+                .filter(move |pair| live_pair(&expiry, pair, now)),
+        ))
+    }
+
+    fn scan_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<ScanIter> {
+        let (expiry, now) = (self.expiry.clone(), now_millis());
+        Ok(Box::new(
+            self.db
+                .scan_prefix(prefix.into())
+                .map(to_pair)
+                .filter(move |pair| live_pair(&expiry, pair, now)),
+        ))
+    }
+
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KeyIter> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let (expiry, now) = (self.expiry.clone(), now_millis());
+        Ok(Box::new(
+            self.db
+                .range(range)
+                .keys()
+                .map(|key| Ok(key?.to_vec()))
+                .filter(move |key: &Result<Vec<u8>>| match key {
+                    Ok(key) => !is_expired(expiry.get(key).ok().flatten(), now),
+                    Err(_) => true,
+                }),
+        ))
+    }
+
+    /// Sled reclaims space of its log by itself, so only flush it
+    fn compact(&self) -> Result<()> {
         self.db.flush()?;
         Ok(())
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
-        self.db.flush()?;
+    fn engine_stats(&self) -> Result<KvsStats> {
+        let now = now_millis();
+        let mut keys = 0;
+        for key in self.db.iter().keys() {
+            if !is_expired(self.expiry.get(key?)?, now) {
+                keys += 1;
+            }
+        }
+        Ok(KvsStats {
+            keys,
+            disk_bytes: self.db.size_on_disk()?,
+        })
+    }
+
+    /// Take a snapshot by copying every live key-value pair, with writes
+    /// blocked meanwhile since sled has no snapshot of its own.
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let _gate = self.gate.write().unwrap();
+        let now = now_millis();
+        let mut data = BTreeMap::new();
+        for item in self.db.iter() {
+            let (key, value) = item?;
+            if !is_expired(self.expiry.get(&key)?, now) {
+                data.insert(key.to_vec(), value.to_vec());
+            }
+        }
+        Ok(SledSnapshot {
+            data: Arc::new(data),
+        })
+    }
+
+    fn begin(&self) -> Result<SledTransaction> {
+        Ok(SledTransaction {
+            engine: self.clone(),
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        })
+    }
+
+    /// Watch by `Tree::watch_prefix`, so keys removed by the sweeper
+    /// send events too.
+    fn watch_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<WatchIter> {
+        Ok(WatchIter::new(self.db.watch_prefix(prefix.into())))
+    }
+
+    /// Back up live keys into a new sled database at `path`, with writes
+    /// blocked meanwhile.
+    ///
+    /// The copy is written aside and renamed in place when completed.
+    fn backup_to(&self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        if path.exists() {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already holds a backup", path.display()),
+            )));
+        }
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".backup");
+        let tmp_path = PathBuf::from(tmp_path);
+        if tmp_path.exists() {
+            fs::remove_dir_all(&tmp_path)?;
+        }
+
+        let backup = sled::open(&tmp_path)?;
+        let backup_expiry = backup.open_tree("expiry")?;
+        {
+            let _gate = self.gate.write().unwrap();
+            let now = now_millis();
+            for item in self.db.iter() {
+                let (key, value) = item?;
+                let expire_at = self.expiry.get(&key)?;
+                if is_expired(expire_at.clone(), now) {
+                    continue;
+                }
+                backup.insert(&key, value)?;
+                if let Some(expire_at) = expire_at {
+                    backup_expiry.insert(&key, expire_at)?;
+                }
+            }
+        }
+        backup.flush()?;
+        // the database is closed with the last handle of it
+        drop(backup_expiry);
+        drop(backup);
+        fs::rename(tmp_path, path)?;
         Ok(())
     }
 
+    /// Restore a backup by one transaction, which removes keys missing in it.
+    ///
+    /// The whole backup is read into memory first.
+    fn restore_from(&self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        if !path.is_dir() {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} holds no backup", path.display()),
+            )));
+        }
+        let backup = sled::open(&path)?;
+        let pairs = backup.iter().collect::<sled::Result<Vec<_>>>()?;
+        let expiries = backup
+            .open_tree("expiry")?
+            .iter()
+            .collect::<sled::Result<Vec<_>>>()?;
+
+        let _gate = self.gate.write().unwrap();
+        let keys = self.db.iter().keys().collect::<sled::Result<Vec<_>>>()?;
+        let expiry_keys = self
+            .expiry
+            .iter()
+            .keys()
+            .collect::<sled::Result<Vec<_>>>()?;
+        (&*self.db, &self.expiry, &self.versions)
+            .transaction(|(db, expiry, versions)| {
+                for key in &keys {
+                    db.remove(key)?;
+                    versions.remove(key)?;
+                }
+                for key in &expiry_keys {
+                    expiry.remove(key)?;
+                }
+                for (key, value) in &pairs {
+                    db.insert(key, value)?;
+                    new_version(versions, key)?;
+                }
+                for (key, expire_at) in &expiries {
+                    expiry.insert(key, expire_at)?;
+                }
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        self.flush()
+    }
+
     fn open(path: impl Into<PathBuf>) -> Result<Self> {
         SledKvsEngine::open(path)
     }
 }
+
+impl WatchSource for sled::Subscriber {
+    fn next_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> std::result::Result<WatchEvent, RecvTimeoutError> {
+        Ok(match sled::Subscriber::next_timeout(self, timeout)? {
+            sled::Event::Insert { key, value } => WatchEvent::Set {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            },
+            sled::Event::Remove { key } => WatchEvent::Rm { key: key.to_vec() },
+        })
+    }
+}
+
+/// Read-only view of a `SledKvsEngine`, holding a copy of its data
+#[derive(Clone)]
+pub struct SledSnapshot {
+    data: Arc<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        Ok(self.data.get(&key.into()).cloned())
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter> {
+        let pairs: Vec<_> = self
+            .data
+            .range(range)
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        Ok(Box::new(pairs.into_iter()))
+    }
+
+    fn scan_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<ScanIter> {
+        let prefix = prefix.into();
+        let pairs: Vec<_> = self
+            .data
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        Ok(Box::new(pairs.into_iter()))
+    }
+}
+
+/// Optimistic transaction of a `SledKvsEngine`
+///
+/// Reads remember the values seen, which `commit` compares again in the
+/// `Tree::transaction` applying the writes.
+pub struct SledTransaction {
+    engine: SledKvsEngine,
+    /// Value of every key read, `None` if it did not exist
+    reads: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Buffered writes in key order, `None` to remove the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl KvsTransaction for SledTransaction {
+    fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let value = self.engine.get(key.as_slice())?;
+        self.reads.entry(key).or_insert_with(|| value.clone());
+        Ok(value)
+    }
+
+    fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        self.writes.insert(key.into(), Some(value.into()));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        self.writes.insert(key.into(), None);
+        Ok(())
+    }
+
+    fn commit(self) -> Result<()> {
+        let now = now_millis();
+        self.engine.transaction(|db, expiry, versions| {
+            for (key, seen) in &self.reads {
+                if live_value(db, expiry, key, now)?.as_deref() != seen.as_deref() {
+                    return Err(ConflictableTransactionError::Abort(
+                        KvsError::TransactionConflict,
+                    ));
+                }
+            }
+            for (key, value) in &self.writes {
+                match value {
+                    Some(value) => {
+                        db.insert(key.as_slice(), value.as_slice())?;
+                        new_version(versions, key)?;
+                    }
+                    None => {
+                        db.remove(key.as_slice())?;
+                        versions.remove(key.as_slice())?;
+                    }
+                };
+                expiry.remove(key.as_slice())?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Handle of the background thread removing expired keys
+///
+/// The thread is stopped when the last clone of the engine is dropped,
+/// so the database is closed with it.
+struct Sweeper {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Spawn the thread sweeping expired keys every `SWEEP_INTERVAL`
+fn spawn_sweeper_thread(
+    db: sled::Db,
+    expiry: Tree,
+    versions: Tree,
+    gate: Arc<RwLock<()>>,
+) -> Result<Sweeper> {
+    let (sender, receiver) = mpsc::channel::<()>();
+    let handle = thread::Builder::new()
+        .name("kvs-sled-sweeper".to_owned())
+        .spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(SWEEP_INTERVAL) {
+                // a failed sweep is retried in the next round
+                let _gate = gate.read().unwrap();
+                let _ = sweep(&db, &expiry, &versions);
+            }
+        })?;
+    Ok(Sweeper {
+        sender: Some(sender),
+        handle: Some(handle),
+    })
+}
+
+/// Remove every expired key with its expiry and version
+fn sweep(db: &sled::Db, expiry: &Tree, versions: &Tree) -> Result<()> {
+    let now = now_millis();
+    for item in expiry.iter() {
+        let (key, expire_at) = item?;
+        if decode_u64(&expire_at) > now {
+            continue;
+        }
+        // the key may be set again since it is read
+        (&**db, expiry, versions)
+            .transaction(|(db, expiry, versions)| {
+                if is_expired(expiry.get(&key)?, now) {
+                    db.remove(&key)?;
+                    expiry.remove(&key)?;
+                    versions.remove(&key)?;
+                }
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+    }
+    Ok(())
+}
+
+/// Read the value of a key in a transaction, `None` if it is expired
+fn live_value(
+    db: &TransactionalTree,
+    expiry: &TransactionalTree,
+    key: &[u8],
+    now: u64,
+) -> TxResult<Option<IVec>> {
+    if is_expired(expiry.get(key)?, now) {
+        return Ok(None);
+    }
+    Ok(db.get(key)?)
+}
+
+/// Get the value of `key` unless it is expired, with its version
+///
+/// A key written before versions were kept has version 0 until it is
+/// written again.
+fn live_version(
+    db: &TransactionalTree,
+    expiry: &TransactionalTree,
+    versions: &TransactionalTree,
+    key: &[u8],
+    now: u64,
+) -> TxResult<Option<(Vec<u8>, u64)>> {
+    let value = match live_value(db, expiry, key, now)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let version = versions.get(key)?.map_or(0, |version| decode_u64(&version));
+    Ok(Some((value.to_vec(), version)))
+}
+
+/// Give `key` a new version, which no key had before
+fn new_version(versions: &TransactionalTree, key: &[u8]) -> TxResult<()> {
+    versions.insert(key, &versions.generate_id()?.to_le_bytes())?;
+    Ok(())
+}
+
+/// Keep errors and pairs of keys which are not expired but not swept yet
+fn live_pair(expiry: &Tree, pair: &Result<(Vec<u8>, Vec<u8>)>, now: u64) -> bool {
+    match pair {
+        Ok((key, _)) => !is_expired(expiry.get(key).ok().flatten(), now),
+        Err(_) => true,
+    }
+}
+
+fn is_expired(expire_at: Option<IVec>, now: u64) -> bool {
+    expire_at.is_some_and(|expire_at| decode_u64(&expire_at) <= now)
+}
+
+fn decode_u64(bytes: &[u8]) -> u64 {
+    bytes.try_into().map_or(0, u64::from_le_bytes)
+}
+
+fn transaction_error(e: TransactionError<KvsError>) -> KvsError {
+    match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => KvsError::SledError(e),
+    }
+}
+
+/// Convert a key-value pair of sled into owned bytes
+fn to_pair(item: sled::Result<(sled::IVec, sled::IVec)>) -> Result<(Vec<u8>, Vec<u8>)> {
+    let (key, value) = item?;
+    Ok((key.to_vec(), value.to_vec()))
+}
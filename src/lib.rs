@@ -4,17 +4,39 @@
 
 mod client;
 mod err;
+mod http;
 mod kvse;
+mod memcached;
 mod proto;
+mod resp;
 mod serde;
 mod server;
 pub mod thread_pool;
 
 pub use client::KvsClient;
+pub use client::KvsClientTransaction;
+pub use client::KvsClientWatch;
 pub use err::KvsError;
 pub use err::Result;
+pub use kvse::BatchOp;
+pub use kvse::KeyIter;
 pub use kvse::KvStore;
+pub use kvse::KvStoreOptions;
+pub use kvse::KvStoreSnapshot;
+pub use kvse::KvStoreStats;
+pub use kvse::KvStoreTransaction;
 pub use kvse::KvsEngine;
+pub use kvse::KvsSnapshot;
+pub use kvse::KvsStats;
+pub use kvse::KvsTransaction;
+pub use kvse::ScanIter;
 pub use kvse::SledKvsEngine;
+pub use kvse::SledSnapshot;
+pub use kvse::SledTransaction;
+pub use kvse::SyncPolicy;
+pub use kvse::WatchEvent;
+pub use kvse::WatchIter;
+pub use kvse::WriteBatch;
 pub use proto::*;
 pub use server::KvsServer;
+pub use server::Protocol;
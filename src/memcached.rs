@@ -0,0 +1,453 @@
+//! Memcached text protocol
+//!
+//! The data of an item is stored as the value of its key, unchanged, so
+//! items and values written by other protocols read the same. Nonzero flags
+//! are kept beside it under the key led by `FLAGS_PREFIX`, with the crc32 of
+//! the data they were stored with: flags of a value since written by another
+//! protocol, or lost to a concurrent writer, read as 0. These flags keys are
+//! listed by scans like any other. The cas unique of an item is the version
+//! of its key, given by `KvsEngine::get_versioned` and checked by
+//! `KvsEngine::set_if_version`.
+
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{Shutdown, TcpStream},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use slog::{info, Logger};
+
+use crate::{KvsEngine, KvsError, Result};
+
+/// Longest command line
+const MAX_LINE_SIZE: u64 = 2048;
+/// Longest key, as memcached allows
+const MAX_KEY_SIZE: usize = 250;
+/// Largest data of an item, the default item size of memcached
+const MAX_ITEM_SIZE: usize = 1024 * 1024;
+/// Largest exptime read as seconds from now, larger ones are unix times
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+/// Prefix of the keys holding the flags of items
+const FLAGS_PREFIX: &[u8] = b"\0memcached-flags\0";
+
+const BAD_FORMAT: &str = "CLIENT_ERROR bad command line format";
+
+/// How a storage command writes its item
+#[derive(Debug, Clone, Copy)]
+enum StoreMode {
+    Set,
+    Add,
+    Replace,
+    Cas(u64),
+}
+
+enum Command {
+    Get {
+        keys: Vec<Vec<u8>>,
+        with_cas: bool,
+    },
+    Store {
+        mode: StoreMode,
+        key: Vec<u8>,
+        flags: u32,
+        exptime: i64,
+        data: Vec<u8>,
+        noreply: bool,
+    },
+    Delete {
+        key: Vec<u8>,
+        noreply: bool,
+    },
+    Incr {
+        key: Vec<u8>,
+        delta: u64,
+        decr: bool,
+        noreply: bool,
+    },
+    Version,
+    Quit,
+    /// A command answered by a line without touching the engine
+    Invalid(&'static str),
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Get {
+                with_cas: false, ..
+            } => "get",
+            Command::Get { with_cas: true, .. } => "gets",
+            Command::Store { mode, .. } => match mode {
+                StoreMode::Set => "set",
+                StoreMode::Add => "add",
+                StoreMode::Replace => "replace",
+                StoreMode::Cas(_) => "cas",
+            },
+            Command::Delete { .. } => "delete",
+            Command::Incr { decr: false, .. } => "incr",
+            Command::Incr { decr: true, .. } => "decr",
+            Command::Version => "version",
+            Command::Quit => "quit",
+            Command::Invalid(_) => "invalid",
+        }
+    }
+}
+
+/// Serve memcached commands of a connection until the client closes it
+///
+/// A command line too long or a data block of a wrong size is answered by
+/// a client error, which closes the connection.
+pub(crate) fn handler<E: KvsEngine>(engine: E, stream: TcpStream, logger: &Logger) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    loop {
+        let command = match read_command(&mut reader) {
+            Ok(Some(command)) => command,
+            Ok(None) => break,
+            Err(KvsError::Deserialize(e)) => {
+                write!(writer, "CLIENT_ERROR {}\r\n", e)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        info!(logger, "Recieved memcached: {}", command.name());
+
+        let noreply = match command {
+            Command::Quit => break,
+            Command::Store { noreply, .. }
+            | Command::Delete { noreply, .. }
+            | Command::Incr { noreply, .. } => noreply,
+            _ => false,
+        };
+        let reply = execute(&engine, command)
+            .unwrap_or_else(|e| format!("SERVER_ERROR {}\r\n", e).into_bytes());
+        if !noreply {
+            writer.write_all(&reply)?;
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    stream.shutdown(Shutdown::Write)?;
+    Ok(())
+}
+
+/// Read the next command with its data block, `None` if the client has left
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Command>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_SIZE)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let line = match line.strip_suffix(b"\n") {
+        Some(line) => line.strip_suffix(b"\r").unwrap_or(line),
+        None if line.len() as u64 == MAX_LINE_SIZE => {
+            return Err(KvsError::Deserialize("line too long".to_owned()))
+        }
+        None => return Err(KvsError::Io(io::ErrorKind::UnexpectedEof.into())),
+    };
+    let args: Vec<&[u8]> = line
+        .split(|&b| b == b' ')
+        .filter(|arg| !arg.is_empty())
+        .collect();
+    let (name, args) = match args.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return Ok(Some(Command::Invalid("ERROR"))),
+    };
+    if args.iter().any(|arg| arg.len() > MAX_KEY_SIZE) {
+        return Ok(Some(Command::Invalid(BAD_FORMAT)));
+    }
+
+    let command = match name {
+        b"get" | b"gets" if !args.is_empty() => Command::Get {
+            keys: args.iter().map(|key| key.to_vec()).collect(),
+            with_cas: name == b"gets",
+        },
+        b"set" | b"add" | b"replace" | b"cas" => return read_store(reader, name, args).map(Some),
+        b"delete" => match args {
+            [key] | [key, b"0"] => Command::Delete {
+                key: key.to_vec(),
+                noreply: false,
+            },
+            [key, b"noreply"] | [key, b"0", b"noreply"] => Command::Delete {
+                key: key.to_vec(),
+                noreply: true,
+            },
+            _ => Command::Invalid(BAD_FORMAT),
+        },
+        b"incr" | b"decr" => match args {
+            [key, delta] | [key, delta, b"noreply"] => match parse(delta) {
+                Some(delta) => Command::Incr {
+                    key: key.to_vec(),
+                    delta,
+                    decr: name == b"decr",
+                    noreply: args.len() == 3,
+                },
+                None => Command::Invalid("CLIENT_ERROR invalid numeric delta argument"),
+            },
+            _ => Command::Invalid("ERROR"),
+        },
+        b"version" if args.is_empty() => Command::Version,
+        b"quit" if args.is_empty() => Command::Quit,
+        _ => Command::Invalid("ERROR"),
+    };
+    Ok(Some(command))
+}
+
+/// Read a storage command `<command> <key> <flags> <exptime> <bytes>
+/// [<cas unique>] [noreply]` with its data block
+fn read_store<R: BufRead>(reader: &mut R, name: &[u8], args: &[&[u8]]) -> Result<Command> {
+    let (fields, noreply) = match args.split_last() {
+        Some((&b"noreply", fields)) => (fields, true),
+        _ => (args, false),
+    };
+    let parsed = match (name, fields) {
+        (b"cas", [key, flags, exptime, bytes, unique]) => {
+            parse(unique).map(|unique| (key, flags, exptime, bytes, StoreMode::Cas(unique)))
+        }
+        (b"set", [key, flags, exptime, bytes]) => {
+            Some((key, flags, exptime, bytes, StoreMode::Set))
+        }
+        (b"add", [key, flags, exptime, bytes]) => {
+            Some((key, flags, exptime, bytes, StoreMode::Add))
+        }
+        (b"replace", [key, flags, exptime, bytes]) => {
+            Some((key, flags, exptime, bytes, StoreMode::Replace))
+        }
+        _ => None,
+    };
+    let (key, flags, exptime, bytes, mode) = match parsed {
+        Some(parsed) => parsed,
+        None if fields.len() < 4 => return Ok(Command::Invalid("ERROR")),
+        None => return Ok(Command::Invalid(BAD_FORMAT)),
+    };
+    let (flags, exptime, bytes) = match (parse(flags), parse(exptime), parse::<usize>(bytes)) {
+        (Some(flags), Some(exptime), Some(bytes)) => (flags, exptime, bytes),
+        _ => return Ok(Command::Invalid(BAD_FORMAT)),
+    };
+
+    // the data block is followed by `\r\n`
+    let len = (bytes as u64)
+        .checked_add(2)
+        .ok_or_else(|| KvsError::Deserialize("bad data chunk".to_owned()))?;
+    if bytes > MAX_ITEM_SIZE {
+        // the data block is still sent, and skipped to reach the next command
+        io::copy(&mut reader.take(len), &mut io::sink())?;
+        return Ok(Command::Invalid("SERVER_ERROR object too large for cache"));
+    }
+    // grow with the bytes read rather than trusting the length up front
+    let mut data = Vec::new();
+    if reader.take(len).read_to_end(&mut data)? as u64 != len {
+        return Err(KvsError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    if !data.ends_with(b"\r\n") {
+        return Err(KvsError::Deserialize("bad data chunk".to_owned()));
+    }
+    data.truncate(bytes);
+    Ok(Command::Store {
+        mode,
+        key: key.to_vec(),
+        flags,
+        exptime,
+        data,
+        noreply,
+    })
+}
+
+fn parse<T: std::str::FromStr>(digits: &[u8]) -> Option<T> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// Execute a command on the store engine, returning its reply
+fn execute<E: KvsEngine>(engine: &E, command: Command) -> Result<Vec<u8>> {
+    let reply = match command {
+        Command::Get { keys, with_cas } => {
+            let mut reply = Vec::new();
+            for key in keys {
+                let (data, version) = match engine.get_versioned(key.clone())? {
+                    Some(found) => found,
+                    None => continue,
+                };
+                let flags = read_flags(engine, &key, &data)?;
+                reply.extend_from_slice(b"VALUE ");
+                reply.extend_from_slice(&key);
+                if with_cas {
+                    write!(reply, " {} {} {}\r\n", flags, data.len(), version)?;
+                } else {
+                    write!(reply, " {} {}\r\n", flags, data.len())?;
+                }
+                reply.extend_from_slice(&data);
+                reply.extend_from_slice(b"\r\n");
+            }
+            reply.extend_from_slice(b"END\r\n");
+            return Ok(reply);
+        }
+        Command::Store {
+            mode,
+            key,
+            flags,
+            exptime,
+            data,
+            ..
+        } => store(engine, mode, key, flags, data, ttl(exptime))?,
+        Command::Delete { key, .. } => match engine.remove(key.clone()) {
+            Ok(()) => {
+                write_flags(engine, &key, 0, &[], None)?;
+                "DELETED"
+            }
+            Err(KvsError::KeyNotFound) => "NOT_FOUND",
+            Err(e) => return Err(e),
+        },
+        Command::Incr {
+            key, delta, decr, ..
+        } => return incr(engine, key, delta, decr),
+        Command::Version => {
+            return Ok(format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes())
+        }
+        Command::Quit => unreachable!(),
+        Command::Invalid(reply) => reply,
+    };
+    Ok(format!("{}\r\n", reply).into_bytes())
+}
+
+fn store<E: KvsEngine>(
+    engine: &E,
+    mode: StoreMode,
+    key: Vec<u8>,
+    flags: u32,
+    data: Vec<u8>,
+    ttl: Option<Duration>,
+) -> Result<&'static str> {
+    let item = data.clone();
+    let result = match (mode, ttl) {
+        (StoreMode::Set, None) => engine.set(key.clone(), item),
+        (StoreMode::Set, Some(ttl)) => engine.set_with_ttl(key.clone(), item, ttl),
+        (StoreMode::Add, ttl) => engine.set_if_version(key.clone(), item, ttl, None),
+        (StoreMode::Cas(unique), ttl) => {
+            engine.set_if_version(key.clone(), item, ttl, Some(unique))
+        }
+        (StoreMode::Replace, ttl) => loop {
+            let version = match engine.get_versioned(key.clone())? {
+                Some((_, version)) => version,
+                None => break Err(KvsError::CompareFailed { current: None }),
+            };
+            // retry if the key is written between, unless it is removed
+            match engine.set_if_version(key.clone(), item.clone(), ttl, Some(version)) {
+                Err(KvsError::CompareFailed { current: Some(_) }) => continue,
+                result => break result,
+            }
+        },
+    };
+    match (result, mode) {
+        (Ok(()), _) => {
+            write_flags(engine, &key, flags, &data, ttl)?;
+            Ok("STORED")
+        }
+        (Err(KvsError::CompareFailed { current: None }), StoreMode::Cas(_)) => Ok("NOT_FOUND"),
+        (Err(KvsError::CompareFailed { .. }), StoreMode::Cas(_)) => Ok("EXISTS"),
+        (Err(KvsError::CompareFailed { .. }), _) => Ok("NOT_STORED"),
+        (Err(e), _) => Err(e),
+    }
+}
+
+/// Add `delta` to the decimal item of `key`, or subtract it down to 0,
+/// keeping its flags and expiry
+fn incr<E: KvsEngine>(engine: &E, key: Vec<u8>, delta: u64, decr: bool) -> Result<Vec<u8>> {
+    loop {
+        let (value, version) = match engine.get_versioned(key.clone())? {
+            Some(found) => found,
+            None => return Ok(b"NOT_FOUND\r\n".to_vec()),
+        };
+        let flags = read_flags(engine, &key, &value)?;
+        let n = match std::str::from_utf8(&value).map(|data| data.trim_end().parse::<u64>()) {
+            Ok(Ok(n)) if decr => n.saturating_sub(delta),
+            Ok(Ok(n)) => n.wrapping_add(delta),
+            _ => {
+                return Ok(
+                    b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n".to_vec(),
+                )
+            }
+        };
+        let ttl = match engine.ttl(key.clone()) {
+            Ok(ttl) => ttl,
+            Err(KvsError::KeyNotFound) => continue,
+            Err(e) => return Err(e),
+        };
+        let data = n.to_string().into_bytes();
+        // retry if the key is written between
+        match engine.set_if_version(key.clone(), data.clone(), ttl, Some(version)) {
+            Ok(()) => {
+                write_flags(engine, &key, flags, &data, ttl)?;
+                return Ok(format!("{}\r\n", n).into_bytes());
+            }
+            Err(KvsError::CompareFailed { .. }) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Time to live of an item by its exptime
+///
+/// 0 never expires, a negative exptime has expired, and one over 30 days
+/// is a unix time.
+fn ttl(exptime: i64) -> Option<Duration> {
+    match exptime {
+        0 => None,
+        _ if exptime < 0 => Some(Duration::ZERO),
+        _ if exptime <= MAX_RELATIVE_EXPTIME => Some(Duration::from_secs(exptime as u64)),
+        _ => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            Some(Duration::from_secs(exptime as u64).saturating_sub(now))
+        }
+    }
+}
+
+/// Key holding the flags of the item of `key`
+fn flags_key(key: &[u8]) -> Vec<u8> {
+    [FLAGS_PREFIX, key].concat()
+}
+
+/// Flags of the item of `key` holding `data`, 0 unless they were stored
+/// along with this very data
+fn read_flags<E: KvsEngine>(engine: &E, key: &[u8], data: &[u8]) -> Result<u32> {
+    let entry = match engine.get(flags_key(key))? {
+        Some(entry) => entry,
+        None => return Ok(0),
+    };
+    match entry.split_first_chunk::<4>() {
+        Some((crc, flags)) if *crc == crc32fast::hash(data).to_be_bytes() => {
+            Ok(flags.try_into().map(u32::from_be_bytes).unwrap_or(0))
+        }
+        _ => Ok(0),
+    }
+}
+
+/// Keep the flags of the item of `key` just stored with `data`, expiring
+/// along with it, or drop them if 0
+fn write_flags<E: KvsEngine>(
+    engine: &E,
+    key: &[u8],
+    flags: u32,
+    data: &[u8],
+    ttl: Option<Duration>,
+) -> Result<()> {
+    let flags_key = flags_key(key);
+    if flags == 0 {
+        return match engine.remove(flags_key) {
+            Err(KvsError::KeyNotFound) => Ok(()),
+            result => result,
+        };
+    }
+    let mut entry = crc32fast::hash(data).to_be_bytes().to_vec();
+    entry.extend_from_slice(&flags.to_be_bytes());
+    match ttl {
+        Some(ttl) => engine.set_with_ttl(flags_key, entry, ttl),
+        None => engine.set(flags_key, entry),
+    }
+}
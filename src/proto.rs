@@ -1,38 +1,394 @@
 use serde::{Deserialize, Serialize};
+use std::io;
+
+use crate::{BatchOp, KvsError, WatchEvent};
+
+/// Latest version of the protocol, negotiated by `Hello`
+pub const PROTOCOL_VERSION: u64 = 1;
+
+/// Feature of `Batch` requests
+pub const FEATURE_BATCH: &str = "batch";
+/// Feature of `Scan` and `ScanPrefix` requests
+pub const FEATURE_SCAN: &str = "scan";
+/// Feature of `SetWithTtl`, `Expire`, `Ttl` and `Persist` requests
+pub const FEATURE_TTL: &str = "ttl";
+/// Feature of `CompareAndSwap`, `SetIfAbsent` and `SetIfPresent` requests
+pub const FEATURE_CAS: &str = "cas";
+/// Feature of `Begin`, `Commit` and `Abort` requests
+pub const FEATURE_TRANSACTION: &str = "transaction";
+/// Feature of `Watch` requests
+pub const FEATURE_WATCH: &str = "watch";
+/// Feature of `Backup` and `Restore` requests
+pub const FEATURE_BACKUP: &str = "backup";
+
+/// Every optional feature this crate speaks
+pub const FEATURES: &[&str] = &[
+    FEATURE_BATCH,
+    FEATURE_SCAN,
+    FEATURE_TTL,
+    FEATURE_CAS,
+    FEATURE_TRANSACTION,
+    FEATURE_WATCH,
+    FEATURE_BACKUP,
+];
 
 /// Request from client to server
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum Request {
-    /// Set the value of a string key to a string
+    /// Negotiate the protocol version and optional features of the
+    /// connection, which are all allowed until then
+    Hello {
+        /// Latest protocol version of the client
+        version: u64,
+        /// Optional features the client would use
+        features: Vec<String>,
+    },
+    /// Set the value of a key
     Set {
-        /// A string key
-        key: String,
-        /// A string value of the key
-        value: String,
+        /// A byte string key
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// A byte string value of the key
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
     },
     /// Get value from a given key
     Get {
-        /// A string key
-        key: String,
+        /// A byte string key
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
     },
     /// Remove a given key
     Rm {
-        /// A string key
-        key: String,
+        /// A byte string key
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// Set the value of a key which expires after a time to live
+    SetWithTtl {
+        /// A byte string key
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// A byte string value of the key
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        /// Time to live in milliseconds
+        ttl_ms: u64,
+    },
+    /// Set the time to live of an existing key
+    Expire {
+        /// A byte string key
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// Time to live in milliseconds
+        ttl_ms: u64,
+    },
+    /// Get the remaining time to live of a key
+    Ttl {
+        /// A byte string key
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// Remove the expiry of a key
+    Persist {
+        /// A byte string key
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// Swap the value of a key if its current value is the expected one
+    CompareAndSwap {
+        /// A byte string key
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// Expected current value, `None` if the key should not exist
+        #[serde(with = "serde_bytes")]
+        expected: Option<Vec<u8>>,
+        /// New value, `None` to remove the key
+        #[serde(with = "serde_bytes")]
+        new: Option<Vec<u8>>,
+    },
+    /// Set the value of a key if it does not exist
+    SetIfAbsent {
+        /// A byte string key
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// A byte string value of the key
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    /// Set the value of a key if it exists
+    SetIfPresent {
+        /// A byte string key
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// A byte string value of the key
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    /// Apply writes of several keys atomically
+    Batch {
+        /// Writes in order
+        ops: Vec<BatchOp>,
+    },
+    /// Scan key-value pairs with keys in a range, in key order
+    Scan {
+        /// Inclusive start key
+        #[serde(with = "serde_bytes")]
+        start: Vec<u8>,
+        /// Exclusive end key, `None` for no upper bound
+        #[serde(with = "serde_bytes")]
+        end: Option<Vec<u8>>,
+    },
+    /// Scan key-value pairs with keys starting with a prefix, in key order
+    ScanPrefix {
+        /// A byte string prefix
+        #[serde(with = "serde_bytes")]
+        prefix: Vec<u8>,
+    },
+    /// Reclaim space of overwritten and removed values
+    Compact,
+    /// Write a consistent copy of the store into a path of the server
+    Backup {
+        /// Path of the copy, relative to the backup directory of the server
+        path: String,
+    },
+    /// Replace every key of the store by a copy in a path of the server
+    Restore {
+        /// Path of the copy, relative to the backup directory of the server
+        path: String,
+    },
+    /// Begin an optimistic transaction, which takes the `Get`, `Set` and `Rm`
+    /// requests after it on the same connection until `Commit` or `Abort`
+    Begin,
+    /// Commit the transaction of the connection
+    Commit,
+    /// Abort the transaction of the connection
+    Abort,
+    /// Watch writes of keys starting with a prefix, with an `Event` response
+    /// sent for each of them on the connection until it is closed
+    Watch {
+        /// A byte string prefix
+        #[serde(with = "serde_bytes")]
+        prefix: Vec<u8>,
     },
 }
 
-/// Request from client to server
+impl Request {
+    /// Get the optional feature the request belongs to, `None` for the
+    /// requests every peer speaks
+    pub fn feature(&self) -> Option<&'static str> {
+        match self {
+            Request::Hello { .. }
+            | Request::Set { .. }
+            | Request::Get { .. }
+            | Request::Rm { .. }
+            | Request::Compact => None,
+            Request::Batch { .. } => Some(FEATURE_BATCH),
+            Request::Scan { .. } | Request::ScanPrefix { .. } => Some(FEATURE_SCAN),
+            Request::SetWithTtl { .. }
+            | Request::Expire { .. }
+            | Request::Ttl { .. }
+            | Request::Persist { .. } => Some(FEATURE_TTL),
+            Request::CompareAndSwap { .. }
+            | Request::SetIfAbsent { .. }
+            | Request::SetIfPresent { .. } => Some(FEATURE_CAS),
+            Request::Begin | Request::Commit | Request::Abort => Some(FEATURE_TRANSACTION),
+            Request::Watch { .. } => Some(FEATURE_WATCH),
+            Request::Backup { .. } | Request::Restore { .. } => Some(FEATURE_BACKUP),
+        }
+    }
+}
+
+/// Response from server to client
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum Response {
+    /// Success status of a `Hello` request
+    Hello {
+        /// Protocol version of the connection, the lower of both peers
+        version: u64,
+        /// Optional features of the connection, those both peers speak
+        features: Vec<String>,
+    },
     /// Success status
     Success {
         /// The result of given command
-        result: Option<String>,
+        #[serde(with = "serde_bytes")]
+        result: Option<Vec<u8>>,
+    },
+    /// Success status of a scan
+    Pairs {
+        /// Key-value pairs in key order
+        #[serde(with = "byte_pairs")]
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    /// Success status of a `Ttl` request
+    Ttl {
+        /// Remaining time to live in milliseconds, `None` if the key never expires
+        ttl_ms: Option<u64>,
+    },
+    /// Fail status of a conditional write whose compare failed
+    CompareFailed {
+        /// Current value of the key, `None` if the key does not exist
+        #[serde(with = "serde_bytes")]
+        current: Option<Vec<u8>>,
+    },
+    /// Fail status of a transaction commit whose read keys have changed
+    TransactionConflict,
+    /// Fail status of a request which is not negotiated or not known
+    Unsupported {
+        /// What is unsupported
+        what: String,
+    },
+    /// A write of a key watched by a `Watch` request
+    Event {
+        /// The write
+        event: WatchEvent,
     },
     /// Fail status
     Fail {
+        /// What kind of error it is
+        code: ErrorCode,
         /// Error message
         message: String,
     },
 }
+
+impl Response {
+    /// Fail status of an error, with the message a client needs to rebuild it
+    pub(crate) fn fail(e: KvsError) -> Response {
+        let code = ErrorCode::of(&e);
+        let message = match e {
+            KvsError::Serialize(message)
+            | KvsError::Deserialize(message)
+            | KvsError::UnsupportedFormat(message)
+            | KvsError::Server(message) => message,
+            e => e.to_string(),
+        };
+        Response::Fail { code, message }
+    }
+}
+
+/// Kind of a failed request, which mirrors `KvsError`
+///
+/// Codes are stable numbers on the wire, and a code unknown to a peer is
+/// read as `Other`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "u64", into = "u64")]
+pub enum ErrorCode {
+    /// Any error without a code of its own
+    Other,
+    /// `KvsError::KeyNotFound`
+    KeyNotFound,
+    /// `KvsError::UnexpectedCommand`
+    UnexpectedCommand,
+    /// `KvsError::Io`
+    Io,
+    /// `KvsError::Serialize`
+    Serialize,
+    /// `KvsError::Deserialize`
+    Deserialize,
+    /// `KvsError::Utf8`
+    Utf8,
+    /// `KvsError::CorruptedRecord`
+    CorruptedRecord,
+    /// `KvsError::UnsupportedFormat`
+    UnsupportedFormat,
+    /// `KvsError::SledError`
+    Sled,
+}
+
+impl ErrorCode {
+    /// Get the code of an error
+    pub fn of(e: &KvsError) -> ErrorCode {
+        match e {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::UnexpectedCommand => ErrorCode::UnexpectedCommand,
+            KvsError::Io(_) => ErrorCode::Io,
+            KvsError::Serialize(_) => ErrorCode::Serialize,
+            KvsError::Deserialize(_) => ErrorCode::Deserialize,
+            KvsError::Utf8(_) => ErrorCode::Utf8,
+            KvsError::CorruptedRecord { .. } => ErrorCode::CorruptedRecord,
+            KvsError::UnsupportedFormat(_) => ErrorCode::UnsupportedFormat,
+            KvsError::SledError(_) => ErrorCode::Sled,
+            _ => ErrorCode::Other,
+        }
+    }
+
+    /// Rebuild the error of a `Fail` response
+    ///
+    /// Errors whose details are not on the wire become `KvsError::Server`.
+    pub fn into_error(self, message: String) -> KvsError {
+        match self {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::UnexpectedCommand => KvsError::UnexpectedCommand,
+            ErrorCode::Io => KvsError::Io(io::Error::other(message)),
+            ErrorCode::Serialize => KvsError::Serialize(message),
+            ErrorCode::Deserialize => KvsError::Deserialize(message),
+            ErrorCode::UnsupportedFormat => KvsError::UnsupportedFormat(message),
+            ErrorCode::Other | ErrorCode::Utf8 | ErrorCode::CorruptedRecord | ErrorCode::Sled => {
+                KvsError::Server(message)
+            }
+        }
+    }
+}
+
+impl From<ErrorCode> for u64 {
+    fn from(code: ErrorCode) -> u64 {
+        match code {
+            ErrorCode::Other => 0,
+            ErrorCode::KeyNotFound => 1,
+            ErrorCode::UnexpectedCommand => 2,
+            ErrorCode::Io => 3,
+            ErrorCode::Serialize => 4,
+            ErrorCode::Deserialize => 5,
+            ErrorCode::Utf8 => 6,
+            ErrorCode::CorruptedRecord => 7,
+            ErrorCode::UnsupportedFormat => 8,
+            ErrorCode::Sled => 9,
+        }
+    }
+}
+
+impl From<u64> for ErrorCode {
+    fn from(code: u64) -> ErrorCode {
+        match code {
+            1 => ErrorCode::KeyNotFound,
+            2 => ErrorCode::UnexpectedCommand,
+            3 => ErrorCode::Io,
+            4 => ErrorCode::Serialize,
+            5 => ErrorCode::Deserialize,
+            6 => ErrorCode::Utf8,
+            7 => ErrorCode::CorruptedRecord,
+            8 => ErrorCode::UnsupportedFormat,
+            9 => ErrorCode::Sled,
+            _ => ErrorCode::Other,
+        }
+    }
+}
+
+/// Serialize key-value pairs as byte strings rather than sequences of `u8`
+mod byte_pairs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+    pub fn serialize<S: Serializer>(
+        pairs: &[(Vec<u8>, Vec<u8>)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            pairs
+                .iter()
+                .map(|(key, value)| (Bytes::new(key), Bytes::new(value))),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Pairs, D::Error> {
+        let pairs = Vec::<(ByteBuf, ByteBuf)>::deserialize(deserializer)?;
+        Ok(pairs
+            .into_iter()
+            .map(|(key, value)| (key.into_vec(), value.into_vec()))
+            .collect())
+    }
+}
@@ -0,0 +1,557 @@
+//! Redis serialization protocol RESP2
+//!
+//! Commands arrive as arrays of bulk strings, as sent by `redis-cli` and
+//! Redis clients, or as inline lines of words typed over telnet. Replies
+//! are written in order, so commands may be pipelined, and are flushed
+//! once no more commands are buffered.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{Shutdown, TcpStream},
+    ops::Bound,
+    sync::Mutex,
+    time::Duration,
+};
+
+use slog::{info, Logger};
+
+use crate::{KvsEngine, KvsError, Result};
+
+/// Longest inline command, as Redis allows
+const MAX_INLINE_SIZE: u64 = 64 * 1024;
+/// Most arguments of a command, as Redis allows
+const MAX_ARGS: usize = 1024 * 1024;
+/// Longest bulk string, as Redis allows
+const MAX_BULK_SIZE: usize = 512 * 1024 * 1024;
+/// Keys examined by a `SCAN` without `COUNT`
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// Cursors of `SCAN` kept by a server, older ones are dropped beyond
+const MAX_SCAN_CURSORS: usize = 4096;
+
+/// Reply to a command
+#[derive(Debug)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(writer, "+{}\r\n", status),
+            Reply::Error(message) => write!(writer, "-{}\r\n", message),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(None) => write!(writer, "$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")
+            }
+            Reply::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                replies.iter().try_for_each(|reply| reply.write_to(writer))
+            }
+        }
+    }
+}
+
+/// Cursors handed out by `SCAN`, shared by the connections of a server
+///
+/// Redis clients read cursors as integers, so a cursor is the id of the
+/// last key examined, which an iteration resumes after.
+#[derive(Default)]
+pub(crate) struct ScanCursors {
+    inner: Mutex<(u64, BTreeMap<u64, Vec<u8>>)>,
+}
+
+impl ScanCursors {
+    /// Hand out a cursor resuming after `key`, dropping the oldest cursor
+    /// if there are too many
+    fn insert(&self, key: Vec<u8>) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let (last_id, keys) = &mut *inner;
+        *last_id += 1;
+        keys.insert(*last_id, key);
+        if keys.len() > MAX_SCAN_CURSORS {
+            keys.pop_first();
+        }
+        *last_id
+    }
+
+    /// Get the key a cursor resumes after
+    fn get(&self, id: u64) -> Option<Vec<u8>> {
+        self.inner.lock().unwrap().1.get(&id).cloned()
+    }
+}
+
+/// Serve RESP commands of a connection until the client closes it
+///
+/// A malformed command is answered by a protocol error, which closes the
+/// connection as Redis does.
+pub(crate) fn handler<E: KvsEngine>(
+    engine: E,
+    cursors: &ScanCursors,
+    stream: TcpStream,
+    logger: &Logger,
+) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(KvsError::Deserialize(e)) => {
+                Reply::Error(format!("ERR Protocol error: {}", e)).write_to(&mut writer)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        info!(
+            logger,
+            "Recieved RESP: {:?}",
+            String::from_utf8_lossy(&args[0])
+        );
+
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = if quit {
+            Reply::Status("OK")
+        } else {
+            execute(&engine, cursors, args).unwrap_or_else(|e| Reply::Error(format!("ERR {}", e)))
+        };
+        reply.write_to(&mut writer)?;
+        if quit {
+            break;
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    stream.shutdown(Shutdown::Write)?;
+    Ok(())
+}
+
+/// Read the arguments of the next command, `None` if the client has left
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader, MAX_INLINE_SIZE)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let count = match line.strip_prefix(b"*") {
+        Some(count) => count,
+        None => {
+            let args = line
+                .split(u8::is_ascii_whitespace)
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            return Ok(Some(args));
+        }
+    };
+    let count = match parse_length(count) {
+        Some(count) if count <= MAX_ARGS => count,
+        _ => return Err(protocol_error("invalid multibulk length")),
+    };
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let line = read_line(reader, MAX_INLINE_SIZE)?.ok_or_else(unexpected_eof)?;
+        let len = match line.strip_prefix(b"$").and_then(parse_length) {
+            Some(len) if len <= MAX_BULK_SIZE => len,
+            _ => return Err(protocol_error("invalid bulk length")),
+        };
+        // grow with the bytes read rather than trusting the length up front
+        let mut arg = Vec::new();
+        if reader.by_ref().take(len as u64 + 2).read_to_end(&mut arg)? < len + 2 {
+            return Err(unexpected_eof());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("expected '\\r\\n' after bulk string"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Read a line without its line ending, `None` at the end of the stream
+fn read_line<R: BufRead>(reader: &mut R, limit: u64) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader.by_ref().take(limit).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    match line.strip_suffix(b"\n") {
+        Some(rest) => {
+            let len = rest.strip_suffix(b"\r").unwrap_or(rest).len();
+            line.truncate(len);
+            Ok(Some(line))
+        }
+        None if line.len() as u64 == limit => Err(protocol_error("too big inline request")),
+        None => Err(unexpected_eof()),
+    }
+}
+
+fn parse_length(digits: &[u8]) -> Option<usize> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+fn protocol_error(message: &str) -> KvsError {
+    KvsError::Deserialize(message.to_owned())
+}
+
+fn unexpected_eof() -> KvsError {
+    KvsError::Io(io::Error::from(io::ErrorKind::UnexpectedEof))
+}
+
+/// Execute a command on the store engine
+///
+/// Errors of the engine are left to the caller, while misuse of a command
+/// is answered by the error reply Redis gives.
+fn execute<E: KvsEngine>(
+    engine: &E,
+    cursors: &ScanCursors,
+    mut args: Vec<Vec<u8>>,
+) -> Result<Reply> {
+    let name = String::from_utf8_lossy(&args.remove(0)).to_ascii_lowercase();
+    let arity_ok = match name.as_str() {
+        "ping" => args.len() <= 1,
+        "get" => args.len() == 1,
+        "set" => args.len() >= 2,
+        "del" | "exists" => !args.is_empty(),
+        "keys" => args.len() == 1,
+        "scan" => !args.is_empty(),
+        "expire" => args.len() == 2,
+        _ => {
+            return Ok(Reply::Error(format!(
+                "ERR unknown command '{}'",
+                name.escape_debug()
+            )))
+        }
+    };
+    if !arity_ok {
+        return Ok(Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        )));
+    }
+
+    let mut args = args.into_iter();
+    let mut arg = || args.next().unwrap();
+    match name.as_str() {
+        "ping" => Ok(match args.next() {
+            Some(message) => Reply::Bulk(Some(message)),
+            None => Reply::Status("PONG"),
+        }),
+        "get" => Ok(Reply::Bulk(engine.get(arg())?)),
+        "set" => {
+            let (key, value) = (arg(), arg());
+            set(engine, key, value, args.collect())
+        }
+        "del" => {
+            let mut removed = 0;
+            for key in args {
+                match engine.remove(key) {
+                    Ok(()) => removed += 1,
+                    Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Reply::Integer(removed))
+        }
+        "exists" => {
+            let mut found = 0;
+            for key in args {
+                if engine.get(key)?.is_some() {
+                    found += 1;
+                }
+            }
+            Ok(Reply::Integer(found))
+        }
+        "keys" => {
+            let pattern = arg();
+            let prefix = literal_prefix(&pattern);
+            let mut keys = Vec::new();
+            for key in engine.scan_keys(prefix.clone()..)? {
+                let key = key?;
+                if !key.starts_with(&prefix) {
+                    break;
+                }
+                if glob_match(&pattern, &key) {
+                    keys.push(Reply::Bulk(Some(key)));
+                }
+            }
+            Ok(Reply::Array(keys))
+        }
+        "scan" => {
+            let cursor = arg();
+            scan(engine, cursors, &cursor, args.collect())
+        }
+        "expire" => {
+            let key = arg();
+            let secs = match parse_integer(&arg()) {
+                Some(secs) => secs,
+                None => return Ok(not_integer()),
+            };
+            // a key expiring now is removed, as Redis does
+            let result = match u64::try_from(secs) {
+                Ok(secs) if secs > 0 => engine.expire(key, Duration::from_secs(secs)),
+                _ => engine.remove(key),
+            };
+            match result {
+                Ok(()) => Ok(Reply::Integer(1)),
+                Err(KvsError::KeyNotFound) => Ok(Reply::Integer(0)),
+                Err(e) => Err(e),
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// `SET key value [EX seconds|PX milliseconds] [NX|XX]`
+///
+/// A conditional set with an expiry is checked by the version of the key,
+/// so that the expiry is written along with the value.
+fn set<E: KvsEngine>(
+    engine: &E,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    options: Vec<Vec<u8>>,
+) -> Result<Reply> {
+    let mut ttl = None;
+    let mut condition = None;
+    let mut options = options.into_iter();
+    while let Some(option) = options.next() {
+        let option = option.to_ascii_uppercase();
+        match option.as_slice() {
+            b"EX" | b"PX" if ttl.is_none() => {
+                let n = match options.next().map(|n| parse_integer(&n)) {
+                    Some(Some(n)) => n,
+                    Some(None) => return Ok(not_integer()),
+                    None => return Ok(syntax_error()),
+                };
+                let n = match u64::try_from(n) {
+                    Ok(n) if n > 0 => n,
+                    _ => {
+                        return Ok(Reply::Error(
+                            "ERR invalid expire time in 'set' command".to_owned(),
+                        ))
+                    }
+                };
+                ttl = Some(match option.as_slice() {
+                    b"EX" => Duration::from_secs(n),
+                    _ => Duration::from_millis(n),
+                });
+            }
+            b"NX" | b"XX" if condition.is_none() => condition = Some(option),
+            _ => return Ok(syntax_error()),
+        }
+    }
+
+    let result = match (condition.as_deref(), ttl) {
+        (None, None) => engine.set(key, value),
+        (None, Some(ttl)) => engine.set_with_ttl(key, value, ttl),
+        (Some(b"NX"), None) => engine.set_if_absent(key, value),
+        (Some(b"NX"), ttl) => engine.set_if_version(key, value, ttl, None),
+        (Some(_), None) => engine.set_if_present(key, value),
+        (Some(_), ttl) => loop {
+            let version = match engine.get_versioned(key.clone())? {
+                Some((_, version)) => version,
+                None => break Err(KvsError::CompareFailed { current: None }),
+            };
+            // retry if the key is written between, unless it is removed
+            match engine.set_if_version(key.clone(), value.clone(), ttl, Some(version)) {
+                Err(KvsError::CompareFailed { current: Some(_) }) => continue,
+                result => break result,
+            }
+        },
+    };
+    match result {
+        Ok(()) => Ok(Reply::Status("OK")),
+        Err(KvsError::CompareFailed { .. }) => Ok(Reply::Bulk(None)),
+        Err(e) => Err(e),
+    }
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`
+///
+/// Keys with the literal prefix of the pattern are examined in key order,
+/// from the one after the key of the cursor, so keys present during a whole
+/// iteration are returned once, whatever is written meanwhile. A cursor
+/// dropped by the server is invalid.
+fn scan<E: KvsEngine>(
+    engine: &E,
+    cursors: &ScanCursors,
+    cursor: &[u8],
+    options: Vec<Vec<u8>>,
+) -> Result<Reply> {
+    let after = match parse_length(cursor) {
+        Some(0) => None,
+        Some(id) => match cursors.get(id as u64) {
+            Some(key) => Some(key),
+            None => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+        },
+        None => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+    };
+    let mut pattern = b"*".to_vec();
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut options = options.into_iter();
+    while let Some(option) = options.next() {
+        let value = match options.next() {
+            Some(value) => value,
+            None => return Ok(syntax_error()),
+        };
+        match option.to_ascii_uppercase().as_slice() {
+            b"MATCH" => pattern = value,
+            b"COUNT" => match parse_length(&value) {
+                Some(n) if n > 0 => count = n,
+                Some(_) => return Ok(syntax_error()),
+                None => return Ok(not_integer()),
+            },
+            _ => return Ok(syntax_error()),
+        }
+    }
+
+    let prefix = literal_prefix(&pattern);
+    let start = match after {
+        Some(key) => Bound::Excluded(key),
+        None => Bound::Included(prefix.clone()),
+    };
+    let mut keys = Vec::new();
+    let mut next = 0;
+    let mut last = None;
+    for (examined, key) in engine.scan_keys((start, Bound::Unbounded))?.enumerate() {
+        let key = key?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        // a key left over makes the iteration go on after the last one
+        if examined == count {
+            next = last.map_or(0, |last| cursors.insert(last));
+            break;
+        }
+        if glob_match(&pattern, &key) {
+            keys.push(Reply::Bulk(Some(key.clone())));
+        }
+        last = Some(key);
+    }
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next.to_string().into_bytes())),
+        Reply::Array(keys),
+    ]))
+}
+
+fn parse_integer(digits: &[u8]) -> Option<i64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+fn not_integer() -> Reply {
+    Reply::Error("ERR value is not an integer or out of range".to_owned())
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_owned())
+}
+
+/// Literal bytes a glob-style `pattern` starts with, which every matching
+/// key starts with too
+fn literal_prefix(pattern: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::new();
+    let mut pattern = pattern.iter();
+    while let Some(&c) = pattern.next() {
+        match c {
+            b'*' | b'?' | b'[' => break,
+            b'\\' => match pattern.next() {
+                Some(&c) => prefix.push(c),
+                None => break,
+            },
+            c => prefix.push(c),
+        }
+    }
+    prefix
+}
+
+/// Whether `key` matches a glob-style `pattern` of Redis
+///
+/// `*` matches any bytes, `?` any one byte, `[abc]`, `[^abc]` and `[a-z]`
+/// one byte of a class, and `\` escapes the byte after it.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // pattern after the last `*`, and where in the key it is tried from;
+    // an earlier `*` never needs to match more, as the last one covers it
+    let mut star = None;
+    while k < key.len() {
+        let next = match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, k));
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => {
+                let (matched, rest) = match_class(&pattern[p + 1..], key[k]);
+                matched.then(|| pattern.len() - rest.len())
+            }
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == key[k]).then_some(p + 2),
+            Some(&c) => (c == key[k]).then_some(p + 1),
+            None => None,
+        };
+        match (next, star) {
+            (Some(next), _) => {
+                p = next;
+                k += 1;
+            }
+            // let the last `*` match one more byte
+            (None, Some((after, from))) => {
+                p = after;
+                k = from + 1;
+                star = Some((after, k));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Match byte `c` against a class following `[` in `pattern`
+///
+/// Return whether it matches and the pattern after the closing `]`.
+fn match_class(mut pattern: &[u8], c: u8) -> (bool, &[u8]) {
+    let negated = match pattern.split_first() {
+        Some((b'^', rest)) => {
+            pattern = rest;
+            true
+        }
+        _ => false,
+    };
+    let mut matched = false;
+    loop {
+        match pattern {
+            [] => break,
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            }
+            [b'\\', x, rest @ ..] => {
+                matched |= *x == c;
+                pattern = rest;
+            }
+            [start, b'-', end, rest @ ..] if *end != b']' => {
+                let (low, high) = ((*start).min(*end), (*start).max(*end));
+                matched |= (low..=high).contains(&c);
+                pattern = rest;
+            }
+            [x, rest @ ..] => {
+                matched |= *x == c;
+                pattern = rest;
+            }
+        }
+    }
+    (matched != negated, pattern)
+}
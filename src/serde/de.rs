@@ -1,23 +1,28 @@
-use serde::de;
+use serde::de::{self, DeserializeOwned};
 use serde::Deserialize;
+use std::io::{self, Read};
 
+use super::{HEADER_SIZE, MAGIC, VERSION};
 use crate::{KvsError, Result};
 
+/// Message of the error when the input ends within a message or frame
+const EOF: &str = "EOF";
+
 pub struct Deserializer<'de> {
-    input: &'de str,
+    input: &'de [u8],
 }
 
 impl<'de> Deserializer<'de> {
-    pub fn from_str(input: &'de str) -> Self {
+    pub fn from_slice(input: &'de [u8]) -> Self {
         Deserializer { input }
     }
 }
 
-pub fn from_str<'a, T>(s: &'a str) -> Result<T>
+pub fn from_slice<'a, T>(s: &'a [u8]) -> Result<T>
 where
     T: Deserialize<'a>,
 {
-    let mut deserializer = Deserializer::from_str(s);
+    let mut deserializer = Deserializer::from_slice(s);
     let t = T::deserialize(&mut deserializer)?;
     if deserializer.input.is_empty() {
         Ok(t)
@@ -26,33 +31,85 @@ where
     }
 }
 
+/// Read the next frame written by `to_writer` and deserialize its message
+///
+/// Return `None` if the stream ends between frames.
+pub fn from_reader<R, T>(reader: &mut R) -> Result<Option<T>>
+where
+    R: Read,
+    T: DeserializeOwned,
+{
+    match read_frame(reader)? {
+        Some(message) => from_slice(&message).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Read the message of the next frame written by `to_writer`, so a
+/// message failing to deserialize doesn't break the stream
+///
+/// Return `None` if the stream ends between frames.
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; HEADER_SIZE];
+    let mut read = 0;
+    while read < HEADER_SIZE {
+        match reader.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(KvsError::Deserialize(EOF.to_owned())),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if header[..2] != MAGIC {
+        return Err(KvsError::Deserialize("Invalid frame magic".to_owned()));
+    }
+    if header[2] != VERSION {
+        return Err(KvsError::Deserialize(format!(
+            "Unsupported frame version {}",
+            header[2]
+        )));
+    }
+    let len = u32::from_be_bytes(header[3..].try_into().unwrap()) as usize;
+
+    // grow with the bytes read rather than trusting the length up front
+    let mut message = Vec::new();
+    reader.take(len as u64).read_to_end(&mut message)?;
+    if message.len() < len {
+        return Err(KvsError::Deserialize(EOF.to_owned()));
+    }
+    Ok(Some(message))
+}
+
 impl<'de> Deserializer<'de> {
-    fn peek_char(&mut self) -> Result<char> {
+    fn peek_byte(&mut self) -> Result<u8> {
         self.input
-            .chars()
-            .next()
-            .ok_or_else(|| KvsError::Deserialize("EOF".to_owned()))
+            .first()
+            .copied()
+            .ok_or_else(|| KvsError::Deserialize(EOF.to_owned()))
     }
 
-    fn next_char(&mut self) -> Result<char> {
-        let ch = self.peek_char()?;
-        self.input = &self.input[ch.len_utf8()..];
-        Ok(ch)
+    fn next_byte(&mut self) -> Result<u8> {
+        let b = self.peek_byte()?;
+        self.input = &self.input[1..];
+        Ok(b)
     }
 
-    fn parse_number(&mut self) -> Result<usize> {
-        let mut int = match self.next_char()? {
-            ch @ '0'..='9' => usize::from(ch as u8 - b'0'),
+    fn parse_number(&mut self) -> Result<u64> {
+        let mut int = match self.next_byte()? {
+            b @ b'0'..=b'9' => u64::from(b - b'0'),
             _ => {
                 return Err(KvsError::Deserialize("Excepted Integer".to_owned()));
             }
         };
         loop {
-            match self.input.chars().next() {
-                Some(ch @ '0'..='9') => {
+            match self.input.first() {
+                Some(b @ b'0'..=b'9') => {
                     self.input = &self.input[1..];
-                    int *= usize::from(10u8);
-                    int += usize::from(ch as u8 - b'0');
+                    int = int
+                        .checked_mul(10)
+                        .and_then(|int| int.checked_add(u64::from(b - b'0')))
+                        .ok_or_else(|| KvsError::Deserialize("Integer Overflow".to_owned()))?;
                 }
                 _ => return Ok(int),
             }
@@ -60,41 +117,73 @@ impl<'de> Deserializer<'de> {
     }
 
     fn parse_token(&mut self) -> Result<&'de str> {
-        if !self.peek_char()?.is_ascii_alphabetic() && self.peek_char()? != '_' {
+        let b = self.peek_byte()?;
+        if !b.is_ascii_alphabetic() && b != b'_' {
             return Err(KvsError::Deserialize("Excepted Token".to_owned()));
         }
-        let mut len: usize = 0;
-        let mut input_chars = self.input.chars();
-        while let Some('a'..='z' | 'A'..='Z' | '_') = input_chars.next() {
-            len += 1;
+        let len = self
+            .input
+            .iter()
+            .take_while(|b| b.is_ascii_alphabetic() || **b == b'_')
+            .count();
+        let (token, rest) = self.input.split_at(len);
+        self.input = rest;
+        // a token only holds ascii letters
+        Ok(std::str::from_utf8(token).unwrap())
+    }
+
+    fn parse_seq_len(&mut self) -> Result<usize> {
+        if self.next_byte()? != b'*' {
+            return Err(KvsError::Deserialize("Exceped Seq".to_owned()));
+        }
+        let len = self.parse_number()? as usize;
+        if self.next_byte()? != b'*' {
+            return Err(KvsError::Deserialize("Exceped Seq".to_owned()));
         }
-        let s = &self.input[..len];
-        self.input = &self.input[len..];
-        Ok(s)
+        Ok(len)
     }
 
-    fn parse_string(&mut self) -> Result<&'de str> {
-        if self.next_char()? != '+' {
+    fn parse_bytes(&mut self) -> Result<&'de [u8]> {
+        if self.next_byte()? != b'+' {
             return Err(KvsError::Deserialize("Exceped String".to_owned()));
         }
-        let len = self.parse_number()?;
-        if self.next_char()? != '+' {
+        let len = self.parse_number()? as usize;
+        if self.next_byte()? != b'+' {
             return Err(KvsError::Deserialize("Exceped String".to_owned()));
         }
-        let s = &self.input[..len];
-        self.input = &self.input[len..];
-        Ok(s)
+        if self.input.len() < len {
+            return Err(KvsError::Deserialize(EOF.to_owned()));
+        }
+        let (bytes, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(bytes)
+    }
+
+    fn parse_u64(&mut self) -> Result<u64> {
+        if self.next_byte()? != b'=' {
+            return Err(KvsError::Deserialize("Exceped Integer".to_owned()));
+        }
+        let int = self.parse_number()?;
+        if self.next_byte()? != b'=' {
+            return Err(KvsError::Deserialize("Exceped Integer".to_owned()));
+        }
+        Ok(int)
+    }
+
+    fn parse_string(&mut self) -> Result<&'de str> {
+        std::str::from_utf8(self.parse_bytes()?)
+            .map_err(|_| KvsError::Deserialize("Invalid UTF-8 String".to_owned()))
     }
 }
 
-impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
     type Error = KvsError;
 
     fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        unimplemented!()
+        Err(KvsError::Deserialize("Untyped value".to_owned()))
     }
 
     fn deserialize_bool<V>(self, _visitor: V) -> Result<V::Value>
@@ -153,11 +242,11 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         unimplemented!()
     }
 
-    fn deserialize_u64<V>(self, _visitor: V) -> Result<V::Value>
+    fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        unimplemented!()
+        visitor.visit_u64(self.parse_u64()?)
     }
 
     fn deserialize_f32<V>(self, _visitor: V) -> Result<V::Value>
@@ -195,26 +284,27 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         visitor.visit_string(self.parse_string()?.to_owned())
     }
 
-    fn deserialize_bytes<V>(self, _visitor: V) -> Result<V::Value>
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        unimplemented!()
+        visitor.visit_borrowed_bytes(self.parse_bytes()?)
     }
 
-    fn deserialize_byte_buf<V>(self, _visitor: V) -> Result<V::Value>
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        unimplemented!()
+        visitor.visit_byte_buf(self.parse_bytes()?.to_vec())
     }
 
     fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        if self.input.starts_with("+0+") {
-            self.input = &self.input[3..];
+        // `None` is told apart from an empty string by a negative length
+        if self.input.starts_with(b"+-1+") {
+            self.input = &self.input[4..];
             visitor.visit_none()
         } else {
             visitor.visit_some(self)
@@ -242,18 +332,19 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         unimplemented!()
     }
 
-    fn deserialize_seq<V>(self, _visitor: V) -> Result<V::Value>
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        unimplemented!()
+        let len = self.parse_seq_len()?;
+        visitor.visit_seq(Counted::new(self, len))
     }
 
-    fn deserialize_tuple<V>(self, _len: usize, _visitor: V) -> Result<V::Value>
+    fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        unimplemented!()
+        visitor.visit_seq(Counted::new(self, len))
     }
 
     fn deserialize_tuple_struct<V>(
@@ -310,7 +401,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     where
         V: de::Visitor<'de>,
     {
-        unimplemented!()
+        Err(KvsError::Deserialize("Unknown field".to_owned()))
     }
 }
 
@@ -336,7 +427,7 @@ impl<'de, 'a> de::MapAccess<'de> for NewLineSeparated<'a, 'de> {
             Ok(None)
         } else {
             let val = seed.deserialize(&mut *self.de)?;
-            if self.de.next_char()? == ':' {
+            if self.de.next_byte()? == b':' {
                 Ok(Some(val))
             } else {
                 Err(KvsError::Deserialize("Exceped Separared `:`".to_owned()))
@@ -350,7 +441,7 @@ impl<'de, 'a> de::MapAccess<'de> for NewLineSeparated<'a, 'de> {
     {
         self.len -= 1;
         let val = seed.deserialize(&mut *self.de)?;
-        if self.de.next_char()? == '\r' && self.de.next_char()? == '\n' {
+        if self.de.next_byte()? == b'\r' && self.de.next_byte()? == b'\n' {
             Ok(val)
         } else {
             Err(KvsError::Deserialize(
@@ -360,6 +451,36 @@ impl<'de, 'a> de::MapAccess<'de> for NewLineSeparated<'a, 'de> {
     }
 }
 
+struct Counted<'a, 'de: 'a> {
+    de: &'a mut Deserializer<'de>,
+    len: usize,
+}
+
+impl<'a, 'de> Counted<'a, 'de> {
+    fn new(de: &'a mut Deserializer<'de>, len: usize) -> Self {
+        Counted { de, len }
+    }
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for Counted<'a, 'de> {
+    type Error = KvsError;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
 struct Enum<'a, 'de: 'a> {
     de: &'a mut Deserializer<'de>,
 }
@@ -379,9 +500,9 @@ impl<'de, 'a> de::EnumAccess<'de> for Enum<'a, 'de> {
         V: de::DeserializeSeed<'de>,
     {
         let val = seed.deserialize(&mut *self.de)?;
-        if self.de.next_char()? == '#'
-            && self.de.next_char()? == '\r'
-            && self.de.next_char()? == '\n'
+        if self.de.next_byte()? == b'#'
+            && self.de.next_byte()? == b'\r'
+            && self.de.next_byte()? == b'\n'
         {
             Ok((val, self))
         } else {
@@ -396,7 +517,13 @@ impl<'de, 'a> de::VariantAccess<'de> for Enum<'a, 'de> {
     type Error = KvsError;
 
     fn unit_variant(self) -> Result<()> {
-        unimplemented!()
+        if self.de.next_byte()? == b'\r' && self.de.next_byte()? == b'\n' {
+            Ok(())
+        } else {
+            Err(KvsError::Deserialize(
+                "Exceped NewLine after enum variant".to_owned(),
+            ))
+        }
     }
 
     fn newtype_variant_seed<T>(self, _seed: T) -> Result<T::Value>
@@ -417,9 +544,9 @@ impl<'de, 'a> de::VariantAccess<'de> for Enum<'a, 'de> {
     where
         V: de::Visitor<'de>,
     {
-        let val = visitor.visit_map(NewLineSeparated::new(self.de, fields.len()));
-        if self.de.next_char()? == '\r' && self.de.next_char()? == '\n' {
-            val
+        let val = visitor.visit_map(NewLineSeparated::new(self.de, fields.len()))?;
+        if self.de.next_byte()? == b'\r' && self.de.next_byte()? == b'\n' {
+            Ok(val)
         } else {
             Err(KvsError::Deserialize(
                 "Exceped NewLine after enum variant".to_owned(),
@@ -433,14 +560,12 @@ fn test_request() {
     use crate::Request;
 
     let r = Request::Set {
-        key: "hello".to_owned(),
-        value: "world".to_owned(),
+        key: b"hello".to_vec(),
+        value: b"world".to_vec(),
     };
-    let s = "Set#\r\nkey:+5+hello\r\nvalue:+5+world\r\n\r\n";
-    println!("{:?}", r);
-    println!("{:?}", from_str::<Request>(s));
+    let s = b"Set#\r\nkey:+5+hello\r\nvalue:+5+world\r\n\r\n";
 
-    assert_eq!(r, from_str::<Request>(s).unwrap());
+    assert_eq!(r, from_slice::<Request>(s).unwrap());
 }
 
 #[test]
@@ -448,32 +573,307 @@ fn test_request2() {
     use crate::Request;
 
     let r = Request::Get {
-        key: "hello".to_owned(),
+        key: b"hello".to_vec(),
     };
-    let s = "Get#\r\nkey:+5+hello\r\n\r\n";
-    println!("{:?}", r);
-    println!("{:?}", from_str::<Request>(s));
+    let s = b"Get#\r\nkey:+5+hello\r\n\r\n";
 
-    assert_eq!(r, from_str::<Request>(s).unwrap());
+    assert_eq!(r, from_slice::<Request>(s).unwrap());
 }
 
 #[test]
 fn test_serde() {
     let r = crate::Request::Get {
-        key: "hello".to_owned(),
+        key: b"hello".to_vec(),
     };
 
-    assert_eq!(
-        r,
-        from_str(crate::serde::to_string(&r).unwrap().as_ref()).unwrap()
-    )
+    assert_eq!(r, from_slice(&super::ser::to_vec(&r).unwrap()).unwrap())
 }
 
 #[test]
 fn test_response() {
-    let s = "Success#\r\nresult:+0+\r\n\r\n";
+    let s = b"Success#\r\nresult:+-1+\r\n\r\n";
 
     let r = crate::Response::Success { result: None };
 
-    assert_eq!(r, from_str::<crate::Response>(s).unwrap())
+    assert_eq!(r, from_slice::<crate::Response>(s).unwrap())
+}
+
+#[test]
+fn test_empty_option() {
+    let r = crate::Response::Success {
+        result: Some(Vec::new()),
+    };
+    let s = b"Success#\r\nresult:+0+\r\n\r\n";
+
+    assert_eq!(super::ser::to_vec(&r).unwrap(), s.to_vec());
+    assert_eq!(r, from_slice::<crate::Response>(s).unwrap())
+}
+
+#[test]
+fn test_unit_variant() {
+    let r = crate::Request::Compact;
+    let s = b"Compact#\r\n\r\n";
+
+    assert_eq!(super::ser::to_vec(&r).unwrap(), s.to_vec());
+    assert_eq!(r, from_slice::<crate::Request>(s).unwrap())
+}
+
+#[test]
+fn test_seq() {
+    let r = crate::Response::Pairs {
+        pairs: vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"bc".to_vec(), b"".to_vec()),
+        ],
+    };
+    let s = b"Pairs#\r\npairs:*2*+1+a+1+1+2+bc+0+\r\n\r\n";
+
+    assert_eq!(super::ser::to_vec(&r).unwrap(), s.to_vec());
+    assert_eq!(r, from_slice::<crate::Response>(s).unwrap())
+}
+
+#[test]
+fn test_bytes() {
+    let r = crate::Request::Set {
+        key: vec![0, 0xff, b'\r', b'\n'],
+        value: vec![b'+', 0x80],
+    };
+    let s = b"Set#\r\nkey:+4+\x00\xff\r\n\r\nvalue:+2++\x80\r\n\r\n";
+
+    assert_eq!(super::ser::to_vec(&r).unwrap(), s.to_vec());
+    assert_eq!(r, from_slice::<crate::Request>(s).unwrap())
+}
+
+#[test]
+fn test_truncated_bytes() {
+    let s = b"Get#\r\nkey:+5+hel";
+
+    assert!(from_slice::<crate::Request>(s).is_err())
+}
+
+#[test]
+fn test_batch() {
+    let r = crate::Request::Batch {
+        ops: vec![
+            crate::BatchOp::Set {
+                key: b"a".to_vec(),
+                value: b"1".to_vec(),
+            },
+            crate::BatchOp::Rm { key: b"b".to_vec() },
+        ],
+    };
+    let s =
+        b"Batch#\r\nops:*2*Set#\r\nkey:+1+a\r\nvalue:+1+1\r\n\r\nRm#\r\nkey:+1+b\r\n\r\n\r\n\r\n";
+
+    assert_eq!(super::ser::to_vec(&r).unwrap(), s.to_vec());
+    assert_eq!(r, from_slice::<crate::Request>(s).unwrap())
+}
+
+#[test]
+fn test_compare_failed() {
+    let r = crate::Response::CompareFailed {
+        current: Some(b"a".to_vec()),
+    };
+    let s = b"CompareFailed#\r\ncurrent:+1+a\r\n\r\n";
+
+    assert_eq!(super::ser::to_vec(&r).unwrap(), s.to_vec());
+    assert_eq!(r, from_slice::<crate::Response>(s).unwrap())
+}
+
+#[test]
+fn test_watch_event() {
+    let r = crate::Response::Event {
+        event: crate::WatchEvent::Rm { key: b"a".to_vec() },
+    };
+    let s = b"Event#\r\nevent:Rm#\r\nkey:+1+a\r\n\r\n\r\n\r\n";
+
+    assert_eq!(super::ser::to_vec(&r).unwrap(), s.to_vec());
+    assert_eq!(r, from_slice::<crate::Response>(s).unwrap())
+}
+
+#[test]
+fn test_hello() {
+    let r = crate::Request::Hello {
+        version: 1,
+        features: vec!["scan".to_owned(), "ttl".to_owned()],
+    };
+    let s = b"Hello#\r\nversion:=1=\r\nfeatures:*2*+4+scan+3+ttl\r\n\r\n";
+
+    assert_eq!(super::ser::to_vec(&r).unwrap(), s.to_vec());
+    assert_eq!(r, from_slice::<crate::Request>(s).unwrap())
+}
+
+#[test]
+fn test_fail() {
+    let r = crate::Response::fail(crate::KvsError::KeyNotFound);
+    let s = b"Fail#\r\ncode:=1=\r\nmessage:+13+Key not found\r\n\r\n";
+
+    assert_eq!(super::ser::to_vec(&r).unwrap(), s.to_vec());
+    assert_eq!(r, from_slice::<crate::Response>(s).unwrap());
+
+    // a code added by a newer peer
+    let s = b"Fail#\r\ncode:=1000=\r\nmessage:+1+a\r\n\r\n";
+    let r = crate::Response::Fail {
+        code: crate::ErrorCode::Other,
+        message: "a".to_owned(),
+    };
+    assert_eq!(r, from_slice::<crate::Response>(s).unwrap());
+}
+
+#[test]
+fn test_u64() {
+    let r = crate::Request::SetWithTtl {
+        key: b"a".to_vec(),
+        value: b"b".to_vec(),
+        ttl_ms: u64::MAX,
+    };
+    let s = b"SetWithTtl#\r\nkey:+1+a\r\nvalue:+1+b\r\nttl_ms:=18446744073709551615=\r\n\r\n";
+
+    assert_eq!(super::ser::to_vec(&r).unwrap(), s.to_vec());
+    assert_eq!(r, from_slice::<crate::Request>(s).unwrap());
+
+    let r = crate::Response::Ttl { ttl_ms: None };
+    let s = b"Ttl#\r\nttl_ms:+-1+\r\n\r\n";
+
+    assert_eq!(super::ser::to_vec(&r).unwrap(), s.to_vec());
+    assert_eq!(r, from_slice::<crate::Response>(s).unwrap());
+
+    let s = b"Ttl#\r\nttl_ms:=18446744073709551616=\r\n\r\n";
+    assert!(from_slice::<crate::Response>(s).is_err());
+}
+
+#[test]
+fn test_frame() {
+    let messages = [
+        crate::Request::Begin,
+        crate::Request::SetWithTtl {
+            key: b"a".to_vec(),
+            value: b"bc".to_vec(),
+            ttl_ms: 1000,
+        },
+        crate::Request::Scan {
+            start: b"a".to_vec(),
+            end: None,
+        },
+    ];
+    let mut s = Vec::new();
+    for r in &messages {
+        super::ser::to_writer(&mut s, r).unwrap();
+    }
+    assert_eq!(&s[..12], b"KV\x01\x00\x00\x00\x0aBegin");
+
+    let mut reader = &s[..];
+    for r in &messages {
+        assert_eq!(
+            &from_reader::<_, crate::Request>(&mut reader)
+                .unwrap()
+                .unwrap(),
+            r
+        );
+    }
+    assert!(from_reader::<_, crate::Request>(&mut reader)
+        .unwrap()
+        .is_none());
+
+    // a stream ending within a frame
+    for len in [1, HEADER_SIZE, 10] {
+        assert!(from_reader::<_, crate::Request>(&mut &s[..len]).is_err());
+    }
+    s[2] = VERSION + 1;
+    assert!(from_reader::<_, crate::Request>(&mut &s[..]).is_err());
+    s[0] = b'k';
+    assert!(from_reader::<_, crate::Request>(&mut &s[..]).is_err());
+}
+
+#[cfg(test)]
+fn frames(messages: &[crate::Request]) -> Vec<u8> {
+    let mut s = Vec::new();
+    for r in messages {
+        super::ser::to_writer(&mut s, r).unwrap();
+    }
+    s
+}
+
+#[test]
+fn test_frame_two_on_one_stream() {
+    // a reader giving a byte at a time, as a socket may
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    let get = || crate::Request::Get { key: b"a".to_vec() };
+    let s = frames(&[crate::Request::Compact, get()]);
+    let mut reader = Trickle(&s);
+    assert_eq!(
+        from_reader::<_, crate::Request>(&mut reader).unwrap(),
+        Some(crate::Request::Compact)
+    );
+    assert_eq!(
+        from_reader::<_, crate::Request>(&mut reader).unwrap(),
+        Some(get())
+    );
+    assert_eq!(from_reader::<_, crate::Request>(&mut reader).unwrap(), None);
+}
+
+#[test]
+fn test_frame_bad_magic() {
+    let mut s = frames(&[crate::Request::Compact]);
+    s[1] = b'X';
+    assert!(matches!(
+        read_frame(&mut &s[..]),
+        Err(KvsError::Deserialize(message)) if message == "Invalid frame magic"
+    ));
+}
+
+#[test]
+fn test_frame_bad_version() {
+    let mut s = frames(&[crate::Request::Compact]);
+    s[2] = VERSION + 1;
+    assert!(matches!(
+        read_frame(&mut &s[..]),
+        Err(KvsError::Deserialize(message)) if message == format!("Unsupported frame version {}", VERSION + 1)
+    ));
+}
+
+#[test]
+fn test_frame_header_cut_off() {
+    let s = frames(&[crate::Request::Compact]);
+    for len in 1..HEADER_SIZE {
+        assert!(matches!(
+            read_frame(&mut &s[..len]),
+            Err(KvsError::Deserialize(message)) if message == EOF
+        ));
+    }
+}
+
+#[test]
+fn test_frame_body_cut_off() {
+    let s = frames(&[crate::Request::Compact]);
+    for len in HEADER_SIZE..s.len() {
+        assert!(matches!(
+            read_frame(&mut &s[..len]),
+            Err(KvsError::Deserialize(message)) if message == EOF
+        ));
+    }
+}
+
+#[test]
+fn test_frame_clean_eof() {
+    assert!(matches!(read_frame(&mut &b""[..]), Ok(None)));
+
+    let s = frames(&[crate::Request::Compact]);
+    let mut reader = &s[..];
+    assert!(matches!(read_frame(&mut reader), Ok(Some(_))));
+    assert!(matches!(read_frame(&mut reader), Ok(None)));
+    assert!(matches!(
+        from_reader::<_, crate::Request>(&mut reader),
+        Ok(None)
+    ));
 }
@@ -1,5 +1,15 @@
 mod de;
 mod ser;
 
-pub use de::from_str;
-pub use ser::to_string;
+pub use de::{from_reader, from_slice, read_frame};
+pub use ser::to_writer;
+
+/// Magic bytes starting every frame
+const MAGIC: [u8; 2] = *b"KV";
+
+/// Version of the frame format
+const VERSION: u8 = 1;
+
+/// Size of a frame header: magic, version, then the length of the message
+/// as a big endian `u32`
+const HEADER_SIZE: usize = 7;
@@ -1,24 +1,42 @@
 use serde::{ser, Serialize};
+use std::io::Write;
 
+use super::{HEADER_SIZE, MAGIC, VERSION};
 use crate::{KvsError, Result};
 
 pub struct Serializer {
-    /// Serialized string
-    output: String,
+    /// Serialized bytes
+    output: Vec<u8>,
 }
 
-pub fn to_string<T>(value: &T) -> Result<String>
+pub fn to_vec<T>(value: &T) -> Result<Vec<u8>>
 where
     T: Serialize,
 {
-    let mut serializer = Serializer {
-        output: String::new(),
-    };
+    let mut serializer = Serializer { output: Vec::new() };
     value.serialize(&mut serializer)?;
     Ok(serializer.output)
 }
 
-impl<'ser> ser::Serializer for &'ser mut Serializer {
+/// Write `value` as one frame, so several messages could share a stream
+pub fn to_writer<W, T>(writer: &mut W, value: &T) -> Result<()>
+where
+    W: Write,
+    T: Serialize,
+{
+    let message = to_vec(value)?;
+    let len = u32::try_from(message.len())
+        .map_err(|_| KvsError::Serialize("Message too long for a frame".to_owned()))?;
+    let mut frame = Vec::with_capacity(HEADER_SIZE + message.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.push(VERSION);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&message);
+    writer.write_all(&frame)?;
+    Ok(())
+}
+
+impl ser::Serializer for &mut Serializer {
     type Ok = ();
 
     type Error = KvsError;
@@ -69,8 +87,9 @@ impl<'ser> ser::Serializer for &'ser mut Serializer {
         unimplemented!("Unsupported type `u32`")
     }
 
-    fn serialize_u64(self, _v: u64) -> Result<Self::Ok> {
-        unimplemented!("Unsupported type `u64`")
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok> {
+        self.output.extend_from_slice(format!("={}=", v).as_bytes());
+        Ok(())
     }
 
     fn serialize_f32(self, _v: f32) -> Result<Self::Ok> {
@@ -86,22 +105,24 @@ impl<'ser> ser::Serializer for &'ser mut Serializer {
     }
 
     fn serialize_str(self, v: &str) -> Result<Self::Ok> {
-        self.output += format!("+{}+{}", v.len(), v).as_ref();
-        Ok(())
+        self.serialize_bytes(v.as_bytes())
     }
 
-    fn serialize_bytes(self, _v: &[u8]) -> Result<Self::Ok> {
-        unimplemented!("Unsupported type `&[u8]`")
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok> {
+        self.output
+            .extend_from_slice(format!("+{}+", v.len()).as_bytes());
+        self.output.extend_from_slice(v);
+        Ok(())
     }
 
     fn serialize_none(self) -> Result<Self::Ok> {
-        self.output += "+0+";
+        self.output.extend_from_slice(b"+-1+");
         Ok(())
     }
 
-    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<Self::Ok>
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(self)
     }
@@ -120,20 +141,22 @@ impl<'ser> ser::Serializer for &'ser mut Serializer {
         _variant_index: u32,
         variant: &'static str,
     ) -> Result<Self::Ok> {
-        self.output += variant;
+        // framed like a struct variant without fields
+        self.output.extend_from_slice(variant.as_bytes());
+        self.output.extend_from_slice(b"#\r\n\r\n");
         Ok(())
     }
 
-    fn serialize_newtype_struct<T: ?Sized>(self, name: &'static str, value: &T) -> Result<Self::Ok>
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<Self::Ok>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
-        self.output += name;
-        self.output += ":";
+        self.output.extend_from_slice(name.as_bytes());
+        self.output.extend_from_slice(b":");
         value.serialize(self)
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(
+    fn serialize_newtype_variant<T>(
         self,
         _name: &'static str,
         _variant_index: u32,
@@ -141,19 +164,22 @@ impl<'ser> ser::Serializer for &'ser mut Serializer {
         value: &T,
     ) -> Result<Self::Ok>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
-        self.output += variant;
-        self.output += ":";
+        self.output.extend_from_slice(variant.as_bytes());
+        self.output.extend_from_slice(b":");
         value.serialize(self)
     }
 
-    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
-        unimplemented!("Unsupported type `seq`")
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq> {
+        let len = len.ok_or_else(|| KvsError::Serialize("Unknown length of seq".to_owned()))?;
+        self.output
+            .extend_from_slice(format!("*{}*", len).as_bytes());
+        Ok(self)
     }
 
     fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
-        unimplemented!("Unsupported type `tuple`")
+        Ok(self)
     }
 
     fn serialize_tuple_struct(
@@ -189,54 +215,54 @@ impl<'ser> ser::Serializer for &'ser mut Serializer {
         variant: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeStructVariant> {
-        self.output += variant;
-        self.output += "#\r\n";
+        self.output.extend_from_slice(variant.as_bytes());
+        self.output.extend_from_slice(b"#\r\n");
         Ok(self)
     }
 }
 
-impl<'ser> ser::SerializeSeq for &'ser mut Serializer {
+impl ser::SerializeSeq for &mut Serializer {
     type Ok = ();
 
     type Error = KvsError;
 
-    fn serialize_element<T: ?Sized>(&mut self, _value: &T) -> Result<()>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
-        unimplemented!("Unsupported type `seq`")
+        value.serialize(&mut **self)
     }
 
     fn end(self) -> Result<Self::Ok> {
-        unimplemented!("Unsupported type `seq`")
+        Ok(())
     }
 }
 
-impl<'ser> ser::SerializeTuple for &'ser mut Serializer {
+impl ser::SerializeTuple for &mut Serializer {
     type Ok = ();
 
     type Error = KvsError;
 
-    fn serialize_element<T: ?Sized>(&mut self, _value: &T) -> Result<()>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
-        unimplemented!("Unsupported type `tuple`")
+        value.serialize(&mut **self)
     }
 
     fn end(self) -> Result<Self::Ok> {
-        unimplemented!("Unsupported type `tuple`")
+        Ok(())
     }
 }
 
-impl<'ser> ser::SerializeTupleStruct for &'ser mut Serializer {
+impl ser::SerializeTupleStruct for &mut Serializer {
     type Ok = ();
 
     type Error = KvsError;
 
-    fn serialize_field<T: ?Sized>(&mut self, _value: &T) -> Result<()>
+    fn serialize_field<T>(&mut self, _value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         unimplemented!("Unsupported type `tuple_struct`")
     }
@@ -246,14 +272,14 @@ impl<'ser> ser::SerializeTupleStruct for &'ser mut Serializer {
     }
 }
 
-impl<'ser> ser::SerializeTupleVariant for &'ser mut Serializer {
+impl ser::SerializeTupleVariant for &mut Serializer {
     type Ok = ();
 
     type Error = KvsError;
 
-    fn serialize_field<T: ?Sized>(&mut self, _value: &T) -> Result<()>
+    fn serialize_field<T>(&mut self, _value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         unimplemented!("Unsupported type `tuple_variant`")
     }
@@ -263,21 +289,21 @@ impl<'ser> ser::SerializeTupleVariant for &'ser mut Serializer {
     }
 }
 
-impl<'ser> ser::SerializeMap for &'ser mut Serializer {
+impl ser::SerializeMap for &mut Serializer {
     type Ok = ();
 
     type Error = KvsError;
 
-    fn serialize_key<T: ?Sized>(&mut self, _key: &T) -> Result<()>
+    fn serialize_key<T>(&mut self, _key: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         unimplemented!("Unsupported type `map`")
     }
 
-    fn serialize_value<T: ?Sized>(&mut self, _value: &T) -> Result<()>
+    fn serialize_value<T>(&mut self, _value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         unimplemented!("Unsupported type `map`")
     }
@@ -287,46 +313,46 @@ impl<'ser> ser::SerializeMap for &'ser mut Serializer {
     }
 }
 
-impl<'ser> ser::SerializeStruct for &'ser mut Serializer {
+impl ser::SerializeStruct for &mut Serializer {
     type Ok = ();
 
     type Error = KvsError;
 
-    fn serialize_field<T: ?Sized>(&mut self, key: &'static str, value: &T) -> Result<()>
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
-        self.output += key;
-        self.output += ":";
+        self.output.extend_from_slice(key.as_bytes());
+        self.output.extend_from_slice(b":");
         value.serialize(&mut **self)?;
-        self.output += "\r\n";
+        self.output.extend_from_slice(b"\r\n");
         Ok(())
     }
 
     fn end(self) -> Result<Self::Ok> {
-        self.output += "\r\n";
+        self.output.extend_from_slice(b"\r\n");
         Ok(())
     }
 }
 
-impl<'ser> ser::SerializeStructVariant for &'ser mut Serializer {
+impl ser::SerializeStructVariant for &mut Serializer {
     type Ok = ();
 
     type Error = KvsError;
 
-    fn serialize_field<T: ?Sized>(&mut self, key: &'static str, value: &T) -> Result<()>
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
-        self.output += key;
-        self.output += ":";
+        self.output.extend_from_slice(key.as_bytes());
+        self.output.extend_from_slice(b":");
         value.serialize(&mut **self)?;
-        self.output += "\r\n";
+        self.output.extend_from_slice(b"\r\n");
         Ok(())
     }
 
     fn end(self) -> Result<Self::Ok> {
-        self.output += "\r\n";
+        self.output.extend_from_slice(b"\r\n");
         Ok(())
     }
 }
@@ -336,13 +362,12 @@ fn test_request() {
     use crate::Request;
 
     let r = Request::Set {
-        key: "hello".to_owned(),
-        value: "world".to_owned(),
+        key: b"hello".to_vec(),
+        value: b"world".to_vec(),
     };
-    let s = "Set#\r\nkey:+5+hello\r\nvalue:+5+world\r\n\r\n";
-    println!("{}", to_string(&r).unwrap());
+    let s = b"Set#\r\nkey:+5+hello\r\nvalue:+5+world\r\n\r\n";
 
-    assert_eq!(to_string(&r).unwrap(), s.to_owned());
+    assert_eq!(to_vec(&r).unwrap(), s.to_vec());
 }
 
 #[test]
@@ -350,10 +375,9 @@ fn test_request2() {
     use crate::Request;
 
     let r = Request::Get {
-        key: "hello".to_owned(),
+        key: b"hello".to_vec(),
     };
-    let s = "Get#\r\nkey:+5+hello\r\n\r\n";
-    println!("{}", to_string(&r).unwrap());
+    let s = b"Get#\r\nkey:+5+hello\r\n\r\n";
 
-    assert_eq!(to_string(&r).unwrap(), s.to_owned());
+    assert_eq!(to_vec(&r).unwrap(), s.to_vec());
 }
@@ -1,19 +1,48 @@
 use std::{
-    io::{BufReader, Read, Write},
-    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs, SocketAddr},
+    io::{self, BufReader, Read},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::{Component, Path, PathBuf},
+    sync::{mpsc::RecvTimeoutError, Arc},
+    thread,
+    time::Duration,
 };
 
-use crate::{serde, thread_pool::ThreadPool, KvsEngine, Request, Response};
+use crate::{
+    http, memcached, resp, serde, thread_pool::ThreadPool, KvsEngine, KvsError, KvsTransaction,
+    Request, Response, WatchIter, FEATURES, FEATURE_BACKUP, PROTOCOL_VERSION,
+};
 use slog::{error, info, Logger};
 
 use crate::Result;
 
+/// How long a connection may stay idle before it is closed
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often a quiet watch checks whether its client has left
+const WATCH_POLL: Duration = Duration::from_secs(1);
+
+/// Wire protocol spoken by the listener of a `KvsServer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Framed requests of `KvsClient`
+    Serde,
+    /// Redis serialization protocol RESP2, for `redis-cli` and Redis clients
+    Resp,
+    /// Memcached text protocol, for memcached clients
+    Memcached,
+    /// HTTP/1.1 with JSON bodies, for `curl` and dashboards
+    Http,
+}
+
 /// Key value store server
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     logger: Logger,
     engine: E,
     pool: P,
     listener: TcpListener,
+    protocol: Protocol,
+    idle_timeout: Option<Duration>,
+    backup_dir: Option<PathBuf>,
+    scan_cursors: Arc<resp::ScanCursors>,
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
@@ -25,9 +54,38 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
             engine,
             pool,
             listener,
+            protocol: Protocol::Serde,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            backup_dir: None,
+            scan_cursors: Arc::default(),
         })
     }
 
+    /// Speak `protocol` instead of the native `Protocol::Serde`
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Close connections idle for longer than `timeout`, never if `None`
+    ///
+    /// A connection holds a thread of the pool while it is open, so idle
+    /// clients would otherwise starve the others once they outnumber the
+    /// threads. A client not reading its responses counts as idle too.
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Serve `Backup` and `Restore` requests of paths inside `dir`, which
+    /// are not offered to clients if `None`, as by default
+    ///
+    /// Only `Protocol::Serde` has these requests.
+    pub fn with_backup_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.backup_dir = dir;
+        self
+    }
+
     /// Run the server by listening the `ip-port`
     pub fn run(&mut self) -> Result<()> {
         for stream in self.listener.incoming() {
@@ -38,11 +96,33 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
                         "Accept connection from: {}",
                         &peer.peer_addr().unwrap()
                     );
+                    if let Err(e) = peer
+                        .set_read_timeout(self.idle_timeout)
+                        .and_then(|_| peer.set_write_timeout(self.idle_timeout))
+                    {
+                        error!(self.logger, "Set idle timeout failed: {}", e);
+                        continue;
+                    }
                     let engine = self.engine.clone();
                     let logger = self.logger.clone();
+                    let protocol = self.protocol;
+                    let backup_dir = self.backup_dir.clone();
+                    let scan_cursors = self.scan_cursors.clone();
                     self.pool.spawn(move || {
-                        if let Err(e) = handler(engine, peer, &logger) {
-                            error!(&logger, "Error in TCP handler: {}", e);
+                        let result = match protocol {
+                            Protocol::Serde => {
+                                handler(engine, peer, backup_dir.as_deref(), &logger)
+                            }
+                            Protocol::Resp => resp::handler(engine, &scan_cursors, peer, &logger),
+                            Protocol::Memcached => memcached::handler(engine, peer, &logger),
+                            Protocol::Http => http::handler(engine, peer, &logger),
+                        };
+                        match result {
+                            Ok(()) => {}
+                            Err(KvsError::Io(e)) if is_timeout(&e) => {
+                                info!(&logger, "Closed idle connection");
+                            }
+                            Err(e) => error!(&logger, "Error in TCP handler: {}", e),
                         }
                     })
                 }
@@ -61,37 +141,299 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
     }
 }
 
+/// Whether an error is the timeout of a blocking socket
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
 /// Tcp handle
-fn handler<E: KvsEngine>(engine: E, mut stream: TcpStream, logger: &Logger) -> Result<()> {
+///
+/// A connection carries framed requests until the client closes it, which
+/// are answered in order, so a client may send several before reading any
+/// response. A `Watch` request takes the connection over for its events.
+fn handler<E: KvsEngine>(
+    engine: E,
+    stream: TcpStream,
+    backup_dir: Option<&Path>,
+    logger: &Logger,
+) -> Result<()> {
     let mut reader = BufReader::new(&stream);
-    let mut buf = String::new();
-    reader.read_to_string(&mut buf)?;
+    let mut writer = &stream;
+    let offered: Vec<&str> = FEATURES
+        .iter()
+        .copied()
+        .filter(|&feature| feature != FEATURE_BACKUP || backup_dir.is_some())
+        .collect();
+    // optional features of the connection, every one offered until `Hello`
+    let mut features: Vec<String> = offered.iter().map(|f| f.to_string()).collect();
+    while let Some(request) = read_request(&mut reader)? {
+        info!(logger, "Recieved: {:?}", request);
 
-    info!(logger, "Recieved: {:?}", &buf);
+        let response = match request.and_then(|request| check_feature(&features, request)) {
+            Ok(Request::Hello {
+                version,
+                features: wanted,
+            }) => to_response(hello(version, wanted, &offered, &mut features)),
+            Ok(Request::Begin) => match engine.begin() {
+                Ok(txn) => match transaction(txn, &mut reader, writer, logger)? {
+                    Some(response) => response,
+                    None => break,
+                },
+                Err(e) => to_response(Err(e)),
+            },
+            // a watch lasts until the client leaves, so it gets a thread of its
+            // own rather than holding one of the pool
+            Ok(Request::Watch { prefix }) => match engine.watch_prefix(prefix) {
+                Ok(events) => {
+                    let stream = stream.try_clone()?;
+                    let logger = logger.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = watch(events, stream) {
+                            info!(logger, "Watch closed: {}", e);
+                        }
+                    });
+                    return Ok(());
+                }
+                Err(e) => to_response(Err(e)),
+            },
+            Ok(request @ (Request::Backup { .. } | Request::Restore { .. })) => {
+                to_response(backup(&engine, backup_dir, request))
+            }
+            Ok(request) => to_response(execute(engine.clone(), request)),
+            Err(e) => to_response(Err(e)),
+        };
+        serde::to_writer(&mut writer, &response)?;
+    }
+    stream.shutdown(Shutdown::Write)?;
+    Ok(())
+}
 
-    let response = match execute(engine, serde::from_str(&buf)?) {
-        Ok(result) => Response::Success { result },
-        Err(e) => Response::Fail {
-            message: format!("{}", e),
-        },
-    };
+/// Read the next request of a connection
+///
+/// A request failing to deserialize is unsupported, maybe sent by a newer
+/// client, and the connection goes on since its frame is read whole.
+fn read_request<R: Read>(reader: &mut R) -> Result<Option<Result<Request>>> {
+    Ok(serde::read_frame(reader)?.map(|message| {
+        serde::from_slice(&message).map_err(|e| KvsError::Unsupported(format!("request, {}", e)))
+    }))
+}
+
+/// Negotiate the protocol version and optional features of a connection
+fn hello(
+    version: u64,
+    wanted: Vec<String>,
+    offered: &[&str],
+    features: &mut Vec<String>,
+) -> Result<Response> {
+    if version == 0 {
+        return Err(KvsError::Unsupported(format!(
+            "protocol version {}",
+            version
+        )));
+    }
+    *features = wanted
+        .into_iter()
+        .filter(|feature| offered.contains(&feature.as_str()))
+        .collect();
+    Ok(Response::Hello {
+        version: version.min(PROTOCOL_VERSION),
+        features: features.clone(),
+    })
+}
+
+/// Check the optional feature of a request is negotiated on its connection
+fn check_feature(features: &[String], request: Request) -> Result<Request> {
+    match request.feature() {
+        Some(feature) if !features.iter().any(|f| f == feature) => {
+            Err(KvsError::Unsupported(format!("feature `{}`", feature)))
+        }
+        _ => Ok(request),
+    }
+}
+
+/// Serve requests of a transaction on its connection until it ends
+///
+/// Return the response of `Commit` or `Abort`, or `None` if the connection
+/// is closed before, which aborts the transaction.
+fn transaction<T: KvsTransaction, R: Read>(
+    mut txn: T,
+    reader: &mut R,
+    mut writer: &TcpStream,
+    logger: &Logger,
+) -> Result<Option<Response>> {
+    serde::to_writer(&mut writer, &Response::Success { result: None })?;
+    while let Some(request) = read_request(reader)? {
+        info!(logger, "Recieved in transaction: {:?}", request);
+
+        let result = match request {
+            Ok(Request::Get { key }) => txn.get(key),
+            Ok(Request::Set { key, value }) => txn.set(key, value).map(|_| None),
+            Ok(Request::Rm { key }) => txn.remove(key).map(|_| None),
+            Ok(Request::Commit) => {
+                let result = txn.commit().map(|_| Response::Success { result: None });
+                return Ok(Some(to_response(result)));
+            }
+            Ok(Request::Abort) => return Ok(Some(Response::Success { result: None })),
+            Ok(_) => Err(KvsError::UnexpectedCommand),
+            Err(e) => Err(e),
+        };
+        let response = to_response(result.map(|result| Response::Success { result }));
+        serde::to_writer(&mut writer, &response)?;
+    }
+    Ok(None)
+}
 
-    stream.write_all(serde::to_string(&response)?.as_bytes())?;
+/// Push events of a watch on its connection until either ends
+///
+/// The client is checked for between events at most every `WATCH_POLL`,
+/// so a quiet watch doesn't outlive its connection.
+fn watch(mut events: WatchIter, mut stream: TcpStream) -> Result<()> {
+    serde::to_writer(&mut stream, &Response::Success { result: None })?;
+    loop {
+        match events.next_timeout(WATCH_POLL) {
+            Ok(event) => serde::to_writer(&mut stream, &Response::Event { event })?,
+            Err(RecvTimeoutError::Timeout) if client_left(&stream)? => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
     stream.shutdown(Shutdown::Write)?;
     Ok(())
 }
 
+/// Check whether the client of a watch has closed its connection, without
+/// waiting, and discard anything else it sent
+fn client_left(mut stream: &TcpStream) -> Result<bool> {
+    stream.set_nonblocking(true)?;
+    let mut buf = [0; 64];
+    let left = loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break true,
+            Ok(_) => continue,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break false,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break true,
+        }
+    };
+    stream.set_nonblocking(false)?;
+    Ok(left)
+}
+
+/// Response of the result of a request
+fn to_response(result: Result<Response>) -> Response {
+    match result {
+        Ok(response) => response,
+        Err(KvsError::CompareFailed { current }) => Response::CompareFailed { current },
+        Err(KvsError::TransactionConflict) => Response::TransactionConflict,
+        Err(KvsError::Unsupported(what)) => Response::Unsupported { what },
+        Err(e) => Response::fail(e),
+    }
+}
+
 /// Execute command on store engine
-fn execute<E: KvsEngine>(engine: E, request: Request) -> Result<Option<String>> {
-    match request {
-        Request::Get { key } => Ok(engine.get(key)?),
+fn execute<E: KvsEngine>(engine: E, request: Request) -> Result<Response> {
+    let result = match request {
+        Request::Get { key } => engine.get(key)?,
         Request::Set { key, value } => {
             engine.set(key, value)?;
-            Ok(None)
+            None
         }
         Request::Rm { key } => {
             engine.remove(key)?;
-            Ok(None)
+            None
+        }
+        Request::SetWithTtl { key, value, ttl_ms } => {
+            engine.set_with_ttl(key, value, Duration::from_millis(ttl_ms))?;
+            None
+        }
+        Request::Expire { key, ttl_ms } => {
+            engine.expire(key, Duration::from_millis(ttl_ms))?;
+            None
+        }
+        Request::Ttl { key } => {
+            let ttl = engine.ttl(key)?;
+            return Ok(Response::Ttl {
+                ttl_ms: ttl.map(|ttl| ttl.as_millis() as u64),
+            });
         }
+        Request::Persist { key } => {
+            engine.persist(key)?;
+            None
+        }
+        Request::CompareAndSwap { key, expected, new } => {
+            engine.compare_and_swap(key, expected, new)?;
+            None
+        }
+        Request::SetIfAbsent { key, value } => {
+            engine.set_if_absent(key, value)?;
+            None
+        }
+        Request::SetIfPresent { key, value } => {
+            engine.set_if_present(key, value)?;
+            None
+        }
+        Request::Batch { ops } => {
+            engine.write_batch(ops.into())?;
+            None
+        }
+        Request::Scan { start, end } => {
+            let pairs = match end {
+                Some(end) => engine.scan(start..end)?,
+                None => engine.scan(start..)?,
+            };
+            return Ok(Response::Pairs {
+                pairs: pairs.collect::<Result<_>>()?,
+            });
+        }
+        Request::ScanPrefix { prefix } => {
+            return Ok(Response::Pairs {
+                pairs: engine.scan_prefix(prefix)?.collect::<Result<_>>()?,
+            });
+        }
+        Request::Compact => {
+            engine.compact()?;
+            None
+        }
+        Request::Hello { .. }
+        | Request::Backup { .. }
+        | Request::Restore { .. }
+        | Request::Begin
+        | Request::Commit
+        | Request::Abort
+        | Request::Watch { .. } => return Err(KvsError::UnexpectedCommand),
+    };
+    Ok(Response::Success { result })
+}
+
+/// Execute a `Backup` or `Restore` request in the backup directory `dir`
+fn backup<E: KvsEngine>(engine: &E, dir: Option<&Path>, request: Request) -> Result<Response> {
+    let dir = dir.ok_or_else(|| KvsError::Unsupported(format!("feature `{}`", FEATURE_BACKUP)))?;
+    match request {
+        Request::Backup { path } => engine.backup_to(backup_path(dir, &path)?)?,
+        Request::Restore { path } => engine.restore_from(backup_path(dir, &path)?)?,
+        _ => return Err(KvsError::UnexpectedCommand),
+    }
+    Ok(Response::Success { result: None })
+}
+
+/// Resolve the path of a `Backup` or `Restore` request inside `dir`
+///
+/// Only relative paths down from `dir` are taken, so that a client can't
+/// write or read a store anywhere else on the server.
+fn backup_path(dir: &Path, path: &str) -> Result<PathBuf> {
+    let path = Path::new(path);
+    let mut components = path.components().peekable();
+    let inside = components.peek().is_some()
+        && components.all(|component| matches!(component, Component::Normal(_)));
+    if !inside {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{:?} is not a path inside the backup directory", path),
+        )
+        .into());
     }
+    Ok(dir.join(path))
 }
@@ -15,7 +15,7 @@ enum Task {
     Exit,
 }
 /// A thread pool implemented with a shared queue
-/// 
+///
 /// Note: using `catch_unwind` catch panic in thread
 pub struct SharedQueueThreadPool {
     thread_handles: Vec<JoinHandle<()>>,
@@ -58,8 +58,7 @@ impl Drop for SharedQueueThreadPool {
             self.task_queue.lock().unwrap().push_back(Task::Exit);
         }
 
-        while !self.thread_handles.is_empty() {
-            let handle = self.thread_handles.pop().unwrap();
+        while let Some(handle) = self.thread_handles.pop() {
             handle.join().unwrap();
         }
     }
@@ -80,9 +79,7 @@ fn task_runner(queue: Arc<Mutex<VecDeque<Task>>>) {
             }
             true
         });
-        if result.is_err() {
-            continue;
-        } else if !result.unwrap() {
+        if let Ok(false) = result {
             break;
         }
     }
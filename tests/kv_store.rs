@@ -1,6 +1,16 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    thread_pool::{SharedQueueThreadPool, ThreadPool},
+    KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsError, KvsServer, KvsSnapshot,
+    KvsTransaction, Protocol, Request, Response, Result, SledKvsEngine, SyncPolicy, WatchEvent,
+    WriteBatch, FEATURES, FEATURE_BACKUP, PROTOCOL_VERSION,
+};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -13,14 +23,14 @@ fn get_stored_value() -> Result<()> {
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get("key2".to_owned())?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get("key2".to_owned())?, Some(b"value2".to_vec()));
 
     Ok(())
 }
@@ -32,16 +42,16 @@ fn overwrite_value() -> Result<()> {
     let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value1".to_vec()));
     store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value2".to_vec()));
     store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value3".to_vec()));
 
     Ok(())
 }
@@ -63,68 +73,1876 @@ fn get_non_existent_value() -> Result<()> {
     Ok(())
 }
 
+// Should persist data under every sync policy
+#[test]
+fn sync_policies() -> Result<()> {
+    for sync in [
+        SyncPolicy::Never,
+        SyncPolicy::Always,
+        SyncPolicy::Interval(10),
+        SyncPolicy::GroupCommit,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            sync,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key1".to_owned())?;
+        thread::sleep(Duration::from_millis(50));
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some(b"value2".to_vec()));
+    }
+
+    Ok(())
+}
+
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Should detect a flipped bit in a log record
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("gen_0").join("0.log");
+    let mut content = fs::read(&log)?;
+    let pos = content
+        .windows(6)
+        .position(|w| w == b"value2")
+        .expect("value not found in log");
+    content[pos] ^= 0x01;
+    fs::write(&log, content)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::CorruptedRecord { path, offset: _ }) => assert_eq!(path, log),
+        _ => panic!("corrupted record not detected"),
+    }
+
+    Ok(())
+}
+
+// Should truncate a torn record at the tail of the newest block
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Simulate a crash in the middle of writing the last record
+    let log = temp_dir.path().join("gen_0").join("0.log");
+    let content = fs::read(&log)?;
+    fs::write(&log, &content[..content.len() - 3])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some(b"value3".to_vec()));
+
+    Ok(())
+}
+
+// Should not take a middle record with a corrupted length for a torn tail
+#[test]
+fn corrupted_length_in_middle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // the length of the second record now reaches past the end of file
+    let log = temp_dir.path().join("gen_0").join("0.log");
+    let mut content = fs::read(&log)?;
+    let record_size = content.len() / 3;
+    content[record_size + 4..record_size + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+    fs::write(&log, content)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::CorruptedRecord { path, offset }) => {
+            assert_eq!(path, log);
+            assert_eq!(offset, record_size as u64);
+        }
+        _ => panic!("corrupted record not detected"),
+    }
+    Ok(())
+}
+
+// Should read and upgrade logs written in the legacy JSON format
+#[test]
+fn upgrade_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::create_dir_all(temp_dir.path().join("gen_0"))?;
+    fs::write(
+        temp_dir.path().join("gen_0").join("0.log"),
+        concat!(
+            r#"{"Set":{"key":"key1","value":"value1"}}"#,
+            "\n",
+            r#"{"Set":{"key":"key2","value":"value2"}}"#,
+            "\n",
+            r#"{"Rm":{"key":"key1"}}"#,
+            "\n",
+        ),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some(b"value2".to_vec()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some(b"value2".to_vec()));
+    assert_eq!(store.get("key3".to_owned())?, Some(b"value3".to_vec()));
+
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        // reopen and check content
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter).into_bytes()));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Should load index from hint files after compaction and replay later logs
+#[test]
+fn reopen_with_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    // A completed compaction leaves a hint file beside each copied block
+    let hint_exists = || {
+        fs::read_dir(temp_dir.path().join("gen_0"))
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .any(|e| e.path().extension() == Some("hint".as_ref()))
+    };
+
+    // Cold keys stay live and are copied by compaction
+    for key_id in 0..1000 {
+        store.set(format!("cold{}", key_id), "value".to_owned())?;
+    }
+    let mut iter = 0;
+    while !hint_exists() {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+        assert!(iter < 1000, "No compaction detected");
+    }
+
+    // Logs written after compaction
+    store.set("key0".to_owned(), "new".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.remove("cold0".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, Some(b"new".to_vec()));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("cold0".to_owned())?, None);
+        for key_id in 2..1000 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}", iter - 1).into_bytes())
+            );
+        }
+        for key_id in 1..1000 {
+            assert_eq!(
+                store.get(format!("cold{}", key_id))?,
+                Some(b"value".to_vec())
+            );
+        }
+        Ok(())
+    };
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    // A broken hint file falls back to replaying its block
+    drop(store);
+    for entry in WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        if entry.path().extension() == Some("hint".as_ref()) {
+            fs::write(entry.path(), b"broken")?;
+        }
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Should keep serving reads and writes while compacting in background
+#[test]
+fn concurrent_set_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value =
+        |thread_id: usize, iter: usize| format!("{}-{}-{}", thread_id, iter, "v".repeat(1000));
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for iter in 0..50 {
+                for key_id in 0..100 {
+                    let key = format!("key{}_{}", thread_id, key_id);
+                    store.set(key.clone(), value(thread_id, iter)).unwrap();
+                    assert_eq!(
+                        store.get(key).unwrap(),
+                        Some(value(thread_id, iter).into_bytes())
+                    );
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let compacted = !temp_dir.path().join("gen_0").join("0.log").exists();
+    assert!(compacted, "No compaction detected");
+
+    for thread_id in 0..4 {
+        for key_id in 0..100 {
+            let key = format!("key{}_{}", thread_id, key_id);
+            assert_eq!(store.get(key)?, Some(value(thread_id, 49).into_bytes()));
+        }
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..4 {
+        for key_id in 0..100 {
+            let key = format!("key{}_{}", thread_id, key_id);
+            assert_eq!(store.get(key)?, Some(value(thread_id, 49).into_bytes()));
+        }
+    }
+
+    Ok(())
+}
+
+// Should roll over small blocks and compact across them
+#[test]
+fn small_blocks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: 16 * 1024,
+        block_threshold: 4 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..20 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let blocks = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().extension() == Some("log".as_ref()))
+        .count();
+    assert!(blocks > 1, "No new block created");
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(b"value19".to_vec())
+        );
+    }
+
+    Ok(())
+}
+
+/// Wait for background work until `done`, giving up after a few seconds
+/// since a busy machine may run it late
+fn wait_until(done: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+// Should not compact until the ratio of dead bytes is reached
+#[test]
+fn compaction_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: 0,
+        compaction_ratio: 0.9,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let compacted = || !temp_dir.path().join("gen_0").join("0.log").exists();
+
+    // Half of the data is dead
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert!(!compacted());
+
+    // Most of the data is dead
+    for _ in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), "value".to_owned())?;
+        }
+    }
+    assert!(wait_until(compacted), "No compaction detected");
+
+    Ok(())
+}
+
+// Should only rewrite blocks with enough garbage
+#[test]
+fn compact_garbage_blocks_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: 16 * 1024,
+        block_threshold: 4 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let block = |n: u64| temp_dir.path().join("gen_0").join(format!("{}.log", n));
+
+    // Cold keys written once fill the first blocks
+    for key_id in 0..200 {
+        store.set(format!("cold{}", key_id), "value".to_owned())?;
+    }
+    assert!(block(1).exists(), "No new block created");
+
+    // Hot keys overwritten again and again
+    for iter in 0..100 {
+        for key_id in 0..20 {
+            store.set(format!("hot{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("hot0".to_owned())?;
+
+    let hints = || {
+        fs::read_dir(temp_dir.path().join("gen_0"))
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension() == Some("hint".as_ref()))
+            .count()
+    };
+    assert!(wait_until(|| hints() > 0), "No compaction detected");
+    assert!(block(0).exists(), "Block without garbage is compacted");
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("cold{}", key_id))?,
+            Some(b"value".to_vec())
+        );
+    }
+    assert_eq!(store.get("hot0".to_owned())?, None);
+    for key_id in 1..20 {
+        assert_eq!(
+            store.get(format!("hot{}", key_id))?,
+            Some(b"value99".to_vec())
+        );
+    }
+
+    Ok(())
+}
+
+// Should compact on demand and report reclaimed space
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 99);
+    assert_eq!(stats.generation, 0);
+    assert_eq!(stats.blocks, 1);
+    assert!(stats.dead_bytes > stats.live_bytes);
+
+    store.compact()?;
+    let compacted = store.stats();
+    assert_eq!(compacted.live_keys, 99);
+    assert_eq!(compacted.dead_bytes, 0);
+    assert_eq!(compacted.live_bytes, stats.live_bytes);
+    assert!(!temp_dir.path().join("gen_0").join("0.log").exists());
+
+    // Nothing left to compact
+    store.compact()?;
+    assert_eq!(store.stats(), compacted);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().dead_bytes, 0);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(b"value9".to_vec())
+        );
+    }
+
+    Ok(())
+}
+
+fn scan_engine<E: KvsEngine>(store: E) -> Result<()> {
+    for key in ["b", "a", "ab", "abc", "c", "ba"] {
+        store.set(key.to_owned(), format!("value_{}", key))?;
+    }
+    store.remove("ba".to_owned())?;
+
+    let keys = |pairs: kvs::ScanIter| -> Result<Vec<Vec<u8>>> {
+        pairs.map(|pair| pair.map(|(key, _)| key)).collect()
+    };
+    assert_eq!(
+        keys(store.scan(..)?)?,
+        [&b"a"[..], b"ab", b"abc", b"b", b"c"]
+    );
+    assert_eq!(
+        keys(store.scan(b"ab".to_vec()..b"b".to_vec())?)?,
+        [&b"ab"[..], b"abc"]
+    );
+    assert_eq!(
+        keys(store.scan(b"ab".to_vec()..=b"b".to_vec())?)?,
+        [&b"ab"[..], b"abc", b"b"]
+    );
+    assert_eq!(keys(store.scan(b"b".to_vec()..)?)?, [&b"b"[..], b"c"]);
+    assert_eq!(keys(store.scan_prefix("a")?)?, [&b"a"[..], b"ab", b"abc"]);
+    assert_eq!(keys(store.scan_prefix("b")?)?, [b"b"]);
+    assert!(keys(store.scan_prefix("d")?)?.is_empty());
+
+    let pairs = store.scan(b"c".to_vec()..)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs, [(b"c".to_vec(), b"value_c".to_vec())]);
+
+    // keys alone skip removed and expired keys as well
+    store.set_with_ttl("bb".to_owned(), "gone".to_owned(), Duration::ZERO)?;
+    let keys = store
+        .scan_keys(b"ab".to_vec()..)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, [&b"ab"[..], b"abc", b"b", b"c"]);
+
+    Ok(())
+}
+
+// Should scan keys in order by range and by prefix
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_engine(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(..)?.count(), 5);
+    Ok(())
+}
+
+#[test]
+fn scan_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_engine(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn binary_safe_engine<E: KvsEngine>(store: &E) -> Result<()> {
+    let key = vec![0u8, 0xff, b'\r', b'\n'];
+    let value = vec![b'+', 0x80, 0, b'#'];
+    store.set(key.clone(), value.clone())?;
+    store.set(vec![0xfe], Vec::new())?;
+
+    assert_eq!(store.get(key.clone())?, Some(value.clone()));
+    assert_eq!(store.get(vec![0xfe])?, Some(Vec::new()));
+    assert_eq!(store.get(vec![0u8])?, None);
+    let pairs = store.scan_prefix(vec![0u8])?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs, [(key, value)]);
+    Ok(())
+}
+
+// Should store keys and values which are not valid UTF-8
+#[test]
+fn binary_safe() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_safe_engine(&KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get(vec![0u8, 0xff, b'\r', b'\n'])?,
+        Some(vec![b'+', 0x80, 0, b'#'])
+    );
+    store.compact()?;
+    assert_eq!(store.get(vec![0xfe])?, Some(Vec::new()));
+    Ok(())
+}
+
+#[test]
+fn binary_safe_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_safe_engine(&SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn write_batch_engine<E: KvsEngine>(store: &E) -> Result<()> {
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key3", "value3")
+        .remove("key1")
+        .set("key2", "new2")
+        .set("key3", "new3")
+        .remove("key4");
+    assert_eq!(batch.len(), 5);
+    store.write_batch(batch)?;
+    store.write_batch(WriteBatch::new())?;
+
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some(b"new2".to_vec()));
+    assert_eq!(store.get("key3")?, Some(b"new3".to_vec()));
+    assert_eq!(store.get("key4")?, None);
+    Ok(())
+}
+
+// Should apply writes of a batch together
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batch_engine(&KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some(b"new2".to_vec()));
+    assert_eq!(store.get("key3")?, Some(b"new3".to_vec()));
+
+    // Records of the batch are copied on their own by compaction
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some(b"new2".to_vec()));
+    assert_eq!(store.get("key3")?, Some(b"new3".to_vec()));
+    assert_eq!(store.stats().live_keys, 2);
+    Ok(())
+}
+
+#[test]
+fn write_batch_group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sync: SyncPolicy::GroupCommit,
+        ..KvStoreOptions::default()
+    };
+    write_batch_engine(&KvStore::open_with(temp_dir.path(), options)?)
+}
+
+#[test]
+fn write_batch_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batch_engine(&SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Should drop a torn batch as a whole
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "new1").set("key2", "value2");
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Simulate a crash in the middle of writing the batch
+    let log = temp_dir.path().join("gen_0").join("0.log");
+    let content = fs::read(&log)?;
+    fs::write(&log, &content[..content.len() - 3])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get("key2")?, None);
+    Ok(())
+}
+
+// Should never see a part of a batch
+#[test]
+fn concurrent_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("x", "0")?;
+    store.set("y", "0")?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 1..=1000 {
+                let mut batch = WriteBatch::new();
+                batch.set("x", i.to_string()).set("y", i.to_string());
+                store.write_batch(batch).unwrap();
+            }
+        })
+    };
+    let number = |value: Option<Vec<u8>>| -> u32 {
+        String::from_utf8(value.unwrap()).unwrap().parse().unwrap()
+    };
+    loop {
+        // `x` is written before `y` in every batch
+        let x = number(store.get("x")?);
+        let y = number(store.get("y")?);
+        assert!(y >= x, "saw x = {} before y = {}", x, y);
+        if y == 1000 {
+            break;
+        }
+    }
+    writer.join().unwrap();
+    Ok(())
+}
+
+fn compare_and_swap_engine<E: KvsEngine>(store: &E) -> Result<()> {
+    let compare_failed = |result: Result<()>| match result {
+        Err(KvsError::CompareFailed { current }) => current,
+        other => panic!("expected a failed compare, got {:?}", other),
+    };
+
+    // `None` means the key is absent
+    store.compare_and_swap("key1", None, Some(b"value1".to_vec()))?;
+    assert_eq!(
+        compare_failed(store.compare_and_swap("key1", None, Some(b"x".to_vec()))),
+        Some(b"value1".to_vec())
+    );
+    store.compare_and_swap("key1", Some(b"value1".to_vec()), Some(Vec::new()))?;
+    assert_eq!(store.get("key1")?, Some(Vec::new()));
+
+    // An empty value is not an absent key
+    assert_eq!(
+        compare_failed(store.compare_and_swap("key1", None, None)),
+        Some(Vec::new())
+    );
+    store.compare_and_swap("key1", Some(Vec::new()), None)?;
+    assert_eq!(store.get("key1")?, None);
+    store.compare_and_swap("key1", None, None)?;
+    assert_eq!(
+        compare_failed(store.compare_and_swap("key1", Some(Vec::new()), None)),
+        None
+    );
+
+    store.set_if_absent("key2", "value2")?;
+    assert_eq!(
+        compare_failed(store.set_if_absent("key2", "new2")),
+        Some(b"value2".to_vec())
+    );
+    store.set_if_present("key2", "new2")?;
+    assert_eq!(compare_failed(store.set_if_present("key3", "value3")), None);
+
+    assert_eq!(store.get("key2")?, Some(b"new2".to_vec()));
+    assert_eq!(store.get("key3")?, None);
+    Ok(())
+}
+
+// Should write only if the current value matches
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap_engine(&KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some(b"new2".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap_engine(&SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn concurrent_compare_and_swap_engine<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("counter", "0")?;
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..100 {
+                // read-modify-write until no other thread races
+                loop {
+                    let current = store.get("counter").unwrap();
+                    let n: u32 = String::from_utf8(current.clone().unwrap())
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    let new = (n + 1).to_string().into_bytes();
+                    match store.compare_and_swap("counter", current, Some(new)) {
+                        Ok(()) => break,
+                        Err(KvsError::CompareFailed { .. }) => continue,
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("counter")?, Some(b"800".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sync: SyncPolicy::GroupCommit,
+        ..KvStoreOptions::default()
+    };
+    concurrent_compare_and_swap_engine(KvStore::open_with(temp_dir.path(), options)?)
+}
+
+#[test]
+fn concurrent_compare_and_swap_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_compare_and_swap_engine(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn expiry_engine<E: KvsEngine>(store: &E) -> Result<()> {
+    store.set_with_ttl("session", "token", Duration::from_millis(200))?;
+    store.set_with_ttl("long", "token", Duration::from_secs(3600))?;
+    store.set("plain", "value")?;
+
+    assert_eq!(store.get("session")?, Some(b"token".to_vec()));
+    let ttl = store.ttl("session")?.expect("session should expire");
+    assert!(ttl <= Duration::from_millis(200));
+    assert_eq!(store.ttl("plain")?, None);
+    assert!(matches!(store.ttl("missing"), Err(KvsError::KeyNotFound)));
+    assert!(matches!(
+        store.persist("missing"),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // persist and set both clear the expiry
+    store.persist("long")?;
+    assert_eq!(store.ttl("long")?, None);
+    store.set_with_ttl("plain", "value", Duration::from_secs(3600))?;
+    store.set("plain", "value")?;
+    assert_eq!(store.ttl("plain")?, None);
+
+    // expire keeps the value
+    assert!(matches!(
+        store.expire("missing", Duration::from_secs(1)),
+        Err(KvsError::KeyNotFound)
+    ));
+    store.set("temp", "value")?;
+    store.expire("temp", Duration::from_millis(200))?;
+    assert_eq!(store.get("temp")?, Some(b"value".to_vec()));
+    assert!(store.ttl("temp")?.expect("temp should expire") <= Duration::from_millis(200));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("temp")?, None);
+    assert_eq!(store.get("session")?, None);
+    assert!(matches!(store.ttl("session"), Err(KvsError::KeyNotFound)));
+    assert!(matches!(
+        store.remove("session"),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(
+        store.scan(..)?.collect::<Result<Vec<_>>>()?,
+        vec![
+            (b"long".to_vec(), b"token".to_vec()),
+            (b"plain".to_vec(), b"value".to_vec()),
+        ]
+    );
+    store.set_if_absent("session", "renewed")?;
+    assert_eq!(store.get("session")?, Some(b"renewed".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    expiry_engine(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn expiry_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    expiry_engine(&SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn versioned_engine<E: KvsEngine>(store: &E) -> Result<()> {
+    assert_eq!(store.get_versioned("missing")?, None);
+    store.set_if_version("key", "1", None, None)?;
+    let (value, version) = store.get_versioned("key")?.expect("key should exist");
+    assert_eq!(value, b"1".to_vec());
+
+    // a stale version fails with the current value
+    store.set_if_version("key", "2", None, Some(version))?;
+    assert!(matches!(
+        store.set_if_version("key", "3", None, Some(version)),
+        Err(KvsError::CompareFailed { current: Some(current) }) if current == b"2".to_vec()
+    ));
+    assert!(matches!(
+        store.set_if_version("key", "3", None, None),
+        Err(KvsError::CompareFailed { .. })
+    ));
+    let (_, version) = store.get_versioned("key")?.expect("key should exist");
+
+    // writing a value back doesn't bring its old version back
+    store.set("other", "A")?;
+    let (_, stale) = store.get_versioned("other")?.expect("key should exist");
+    store.set("other", "B")?;
+    store.set("other", "A")?;
+    assert!(matches!(
+        store.set_if_version("other", "C", None, Some(stale)),
+        Err(KvsError::CompareFailed { current: Some(current) }) if current == b"A".to_vec()
+    ));
+
+    // versions change with the expiry too
+    store.expire("key", Duration::from_secs(3600))?;
+    let (_, expiring) = store.get_versioned("key")?.expect("key should exist");
+    assert_ne!(expiring, version);
+    store.set_if_version("key", "4", Some(Duration::from_millis(100)), Some(expiring))?;
+    assert!(store.ttl("key")?.expect("key should expire") <= Duration::from_millis(100));
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get_versioned("key")?, None);
+    assert!(matches!(
+        store.set_if_version("key", "5", None, Some(expiring)),
+        Err(KvsError::CompareFailed { current: None })
+    ));
+    Ok(())
+}
+
+#[test]
+fn versioned() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    versioned_engine(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn versioned_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    versioned_engine(&SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Versions seen before reopening should not match a rewritten key
+#[test]
+fn versions_survive_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key", "1")?;
+    let (_, stale) = store.get_versioned("key")?.expect("key should exist");
+    store.set("key", "2")?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("other", "1")?;
+    assert!(matches!(
+        store.set_if_version("key", "3", None, Some(stale)),
+        Err(KvsError::CompareFailed { .. })
+    ));
+    let (_, version) = store.get_versioned("key")?.expect("key should exist");
+    store.set_if_version("key", "3", None, Some(version))?;
+    assert_eq!(store.get("key")?, Some(b"3".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn expiry_survives_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("short", "value", Duration::from_millis(100))?;
+    store.set_with_ttl("long", "value", Duration::from_secs(3600))?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.ttl("long")?.expect("long should expire") > Duration::from_secs(3000));
+    thread::sleep(Duration::from_millis(200));
+    drop(store);
+
+    // expired while closed
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short")?, None);
+    assert_eq!(store.stats().live_keys, 1);
+
+    // the expiry also survives compaction into hint files
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.ttl("long")?.is_some());
+    Ok(())
+}
+
+#[test]
+fn compaction_drops_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key", "old")?;
+    store.set_with_ttl("key", "new", Duration::from_millis(100))?;
+    store.set("other", "value")?;
+    thread::sleep(Duration::from_millis(200));
+
+    store.compact()?;
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(stats.dead_bytes, 0);
+    drop(store);
+
+    // the older value of the expired key is not resurrected
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key")?, None);
+    assert_eq!(store.get("other")?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn sled_sweeps_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    store.set_with_ttl("key", "value", Duration::from_millis(100))?;
+    store.set("other", "value")?;
+    thread::sleep(Duration::from_millis(1500));
+    drop(store);
+
+    // the sweeper removed the key physically, and closed with the engine
+    let db = sled::open(temp_dir.path())?;
+    assert_eq!(db.get("key")?, None);
+    assert!(db.open_tree("expiry")?.is_empty());
+    assert!(db.get("other")?.is_some());
+    Ok(())
+}
+
+fn snapshot_engine<E: KvsEngine>(store: &E) -> Result<()> {
+    store.set("a", "1")?;
+    store.set("b", "1")?;
+    store.set_with_ttl("c", "1", Duration::from_millis(100))?;
+    let snapshot = store.snapshot()?;
+
+    store.set("a", "2")?;
+    store.remove("b")?;
+    store.set("ab", "2")?;
+    thread::sleep(Duration::from_millis(200));
+
+    assert_eq!(snapshot.get("a")?, Some(b"1".to_vec()));
+    assert_eq!(snapshot.get("b")?, Some(b"1".to_vec()));
+    assert_eq!(snapshot.get("ab")?, None);
+    assert_eq!(snapshot.get("c")?, Some(b"1".to_vec()));
+    assert_eq!(
+        snapshot
+            .scan(b"a".to_vec()..b"c".to_vec())?
+            .collect::<Result<Vec<_>>>()?,
+        vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"1".to_vec()),
+        ]
+    );
+    assert_eq!(
+        snapshot.scan_prefix("a")?.collect::<Result<Vec<_>>>()?,
+        vec![(b"a".to_vec(), b"1".to_vec())]
+    );
+
+    assert_eq!(store.get("a")?, Some(b"2".to_vec()));
+    assert_eq!(store.get("b")?, None);
+    assert_eq!(store.get("c")?, None);
+    Ok(())
+}
+
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshot_engine(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn snapshot_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshot_engine(&SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn snapshot_under_writes_engine<E: KvsEngine>(store: &E) -> Result<()> {
+    let write_round = |store: &E, round: u32| {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..200 {
+            batch.set(format!("key{}", key_id), round.to_string());
+        }
+        batch.remove(format!("extra{}", round.wrapping_sub(1)));
+        batch.set(format!("extra{}", round), round.to_string());
+        store.write_batch(batch)
+    };
+    write_round(store, 0)?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || (1..60).try_for_each(|round| write_round(&store, round)))
+    };
+    // every snapshot sees exactly one round, though the copy of it runs
+    // along with the writes
+    while !writer.is_finished() {
+        let snapshot = store.snapshot()?;
+        let values: Vec<_> = snapshot
+            .scan_prefix("key")?
+            .map(|pair| pair.map(|(_, value)| value))
+            .collect::<Result<_>>()?;
+        assert_eq!(values.len(), 200);
+        assert!(values.iter().all(|value| *value == values[0]));
+        let round = String::from_utf8(values[0].clone()).unwrap();
+        assert_eq!(
+            snapshot.scan_prefix("extra")?.collect::<Result<Vec<_>>>()?,
+            vec![(format!("extra{}", round).into_bytes(), values[0].clone())]
+        );
+    }
+    writer.join().unwrap()
+}
+
+#[test]
+fn snapshot_under_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshot_under_writes_engine(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn snapshot_under_writes_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshot_under_writes_engine(&SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn snapshot_pins_compacted_blocks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pinned_files = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("pinned".as_ref()))
+            .count()
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old")?;
+    }
+    let snapshot = store.snapshot()?;
+    let copy = snapshot.clone();
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "new")?;
+    }
+
+    store.compact()?;
+    assert!(pinned_files() > 0);
+    drop(snapshot);
+    for key_id in 0..100 {
+        assert_eq!(copy.get(format!("key{}", key_id))?, Some(b"old".to_vec()));
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(b"new".to_vec()));
+    }
+
+    // deleted with the last clone of the snapshot
+    drop(copy);
+    assert_eq!(pinned_files(), 0);
+
+    // pinned files left by a crash are deleted on open
+    std::mem::forget(store.snapshot()?);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "newer")?;
+    }
+    store.compact()?;
+    assert!(pinned_files() > 0);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(pinned_files(), 0);
+    assert_eq!(store.get("key0")?, Some(b"newer".to_vec()));
+    Ok(())
+}
+
+fn backup_restore_engine<E: KvsEngine>(store: &E, backup_path: &std::path::Path) -> Result<()> {
+    store.set("a", "1")?;
+    store.set("b", "1")?;
+    store.set_with_ttl("c", "1", Duration::from_secs(3600))?;
+    store.set_with_ttl("d", "1", Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    store.backup_to(backup_path)?;
+    assert!(matches!(store.backup_to(backup_path), Err(KvsError::Io(_))));
+
+    store.set("a", "2")?;
+    store.remove("b")?;
+    store.set("e", "2")?;
+    store.restore_from(backup_path)?;
+
+    assert_eq!(
+        store.scan(..)?.collect::<Result<Vec<_>>>()?,
+        vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"1".to_vec()),
+            (b"c".to_vec(), b"1".to_vec()),
+        ]
+    );
+    assert!(store.ttl("c")?.is_some());
+    assert!(matches!(
+        store.restore_from(backup_path.join("missing")),
+        Err(KvsError::Io(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn backup_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    backup_restore_engine(&store, backup_dir.path())?;
+
+    // a backup is a store itself
+    let backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.get("a")?, Some(b"1".to_vec()));
+    assert_eq!(backup.get("d")?, None);
+    Ok(())
+}
+
+#[test]
+fn backup_restore_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    backup_restore_engine(&store, &backup_dir.path().join("backup"))
+}
+
+#[test]
+fn restore_in_blocks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup = KvStore::open(backup_dir.path())?;
+    for key_id in 0..200 {
+        backup.set(format!("key{}", key_id), "v".repeat(100))?;
+    }
+    drop(backup);
+
+    let options = KvStoreOptions {
+        compaction_threshold: u64::MAX,
+        block_threshold: 4 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("gone", "1")?;
+    store.restore_from(backup_dir.path())?;
+
+    // no batch of the restore is larger than a block
+    let blocks: Vec<_> = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().extension() == Some("log".as_ref()))
+        .map(|e| e.metadata().unwrap().len())
+        .collect();
+    assert!(blocks.len() > 1);
+    assert!(blocks.iter().all(|&len| len <= 4 * 1024), "{:?}", blocks);
+    assert_eq!(store.get("gone")?, None);
+    assert_eq!(store.scan(..)?.count(), 200);
+
+    // an interrupted restore is done again on open from the copy of its
+    // backup kept in the store, here an empty one which clears the store
+    drop(store);
+    KvStore::open(temp_dir.path().join("RESTORE"))?;
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.scan(..)?.count(), 0);
+    assert!(!temp_dir.path().join("RESTORE").exists());
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.scan(..)?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn backup_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+
+    let compactor = {
+        let store = store.clone();
+        thread::spawn(move || store.compact())
+    };
+    store.backup_to(backup_dir.path())?;
+    compactor.join().unwrap()?;
+
+    let backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.stats().live_keys, 100);
+    assert_eq!(backup.stats().dead_bytes, 0);
+    for key_id in 0..100 {
+        assert_eq!(
+            backup.get(format!("key{}", key_id))?,
+            Some(b"value9".to_vec())
+        );
+    }
+    Ok(())
+}
+
+fn transaction_engine<E: KvsEngine>(store: &E) -> Result<()> {
+    store.set("a", "1")?;
+    store.set("b", "1")?;
+
+    // read-your-writes, and writes are invisible until commit
+    let mut txn = store.begin()?;
+    assert_eq!(txn.get("a")?, Some(b"1".to_vec()));
+    txn.set("a", "2")?;
+    txn.remove("b")?;
+    txn.remove("missing")?;
+    assert_eq!(txn.get("a")?, Some(b"2".to_vec()));
+    assert_eq!(txn.get("b")?, None);
+    assert_eq!(store.get("a")?, Some(b"1".to_vec()));
+    txn.commit()?;
+    assert_eq!(store.get("a")?, Some(b"2".to_vec()));
+    assert_eq!(store.get("b")?, None);
+
+    // a key read by the transaction has changed
+    let mut txn = store.begin()?;
+    assert_eq!(txn.get("a")?, Some(b"2".to_vec()));
+    txn.set("c", "1")?;
+    store.set("a", "5")?;
+    assert!(matches!(txn.commit(), Err(KvsError::TransactionConflict)));
+    assert_eq!(store.get("c")?, None);
+
+    // a key read as missing has been created
+    let mut txn = store.begin()?;
+    assert_eq!(txn.get("b")?, None);
+    txn.set("b", "2")?;
+    store.set("b", "1")?;
+    assert!(matches!(txn.commit(), Err(KvsError::TransactionConflict)));
+    assert_eq!(store.get("b")?, Some(b"1".to_vec()));
+
+    // changes to keys only written don't conflict
+    let mut txn = store.begin()?;
+    txn.set("a", "3")?;
+    store.set("a", "4")?;
+    txn.commit()?;
+    assert_eq!(store.get("a")?, Some(b"3".to_vec()));
+
+    // dropping a transaction aborts it
+    let mut txn = store.begin()?;
+    txn.set("d", "1")?;
+    drop(txn);
+    assert_eq!(store.get("d")?, None);
+    Ok(())
+}
+
+fn transaction_counter<E: KvsEngine>(store: &E) -> Result<()> {
+    store.set("counter", "0")?;
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let mut txn = store.begin()?;
+                        let counter: u32 = String::from_utf8(txn.get("counter")?.unwrap())?
+                            .parse()
+                            .unwrap();
+                        txn.set("counter", (counter + 1).to_string())?;
+                        match txn.commit() {
+                            Err(KvsError::TransactionConflict) => continue,
+                            result => break result?,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter")?, Some(b"200".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    transaction_engine(&store)?;
+    transaction_counter(&store)
+}
+
+#[test]
+fn transaction_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    transaction_engine(&store)?;
+    transaction_counter(&store)
+}
+
+// Compaction moves values but must not look like a change to transactions
+#[test]
+fn transaction_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        store.set("key", format!("value{}", iter))?;
+    }
+
+    let mut txn = store.begin()?;
+    assert_eq!(txn.get("key")?, Some(b"value9".to_vec()));
+    store.compact()?;
+    txn.set("key", "value10")?;
+    txn.commit()?;
+    assert_eq!(store.get("key")?, Some(b"value10".to_vec()));
+    Ok(())
+}
+
+/// Start a server of a `KvStore` in `dir` on a free port
+fn spawn_server(dir: &std::path::Path) -> Result<SocketAddr> {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let pool = SharedQueueThreadPool::new(2)?;
+    let mut server = KvsServer::new(logger, KvStore::open(dir)?, pool, "127.0.0.1:0")?;
+    let addr = server.get_address();
+    thread::spawn(move || server.run());
+    Ok(addr)
+}
+
+#[test]
+fn client_pipeline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(temp_dir.path())?;
+
+    // one connection serves every request of a client
+    let mut client = KvsClient::new(addr)?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..100 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert!(matches!(
+        client.remove("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(client.get("key0".to_owned())?, Some("value0".to_owned()));
+
+    // responses of pipelined requests come in order
+    let key = |key: &str| key.as_bytes().to_vec();
+    let responses = client.pipeline(&[
+        Request::Set {
+            key: key("a"),
+            value: key("1"),
+        },
+        Request::Rm {
+            key: key("missing"),
+        },
+        Request::Get { key: key("a") },
+        Request::Get { key: key("key1") },
+    ])?;
+    assert_eq!(responses.len(), 4);
+    assert!(matches!(
+        responses[0],
+        Ok(Response::Success { result: None })
+    ));
+    assert!(matches!(responses[1], Err(KvsError::KeyNotFound)));
+    assert_eq!(
+        responses[2].as_ref().ok(),
+        Some(&Response::Success {
+            result: Some(key("1"))
+        })
+    );
+    assert_eq!(
+        responses[3].as_ref().ok(),
+        Some(&Response::Success {
+            result: Some(key("value1"))
+        })
+    );
+
+    // a transaction goes on the same connection
+    let mut txn = client.begin()?;
+    assert_eq!(txn.get("a")?, Some(b"1".to_vec()));
+    txn.commit()?;
+    Ok(())
+}
+
+#[test]
+fn client_pipeline_large_responses() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(temp_dir.path())?;
+    let mut client = KvsClient::new(addr)?;
+    let key = vec![b'k'; 8 * 1024];
+    client.set_bytes(key.clone(), vec![b'v'; 64 * 1024])?;
+
+    // far more requests and responses than the socket buffers hold
+    let requests: Vec<_> = (0..2000)
+        .map(|_| Request::Get { key: key.clone() })
+        .collect();
+    let responses = client.pipeline(&requests)?;
+    assert_eq!(responses.len(), 2000);
+    for response in responses {
+        assert!(matches!(
+            response?,
+            Response::Success { result: Some(value) } if value.len() == 64 * 1024
+        ));
+    }
+    assert_eq!(client.get("missing".to_owned())?, None);
     Ok(())
 }
 
 #[test]
-fn remove_key() -> Result<()> {
+fn client_hello() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    let addr = spawn_server(temp_dir.path())?;
+
+    let mut client = KvsClient::new(addr)?;
+    assert_eq!(client.version(), PROTOCOL_VERSION);
+    // backup is offered only with a backup directory
+    let offered: Vec<_> = FEATURES
+        .iter()
+        .copied()
+        .filter(|&feature| feature != FEATURE_BACKUP)
+        .collect();
+    assert_eq!(client.features(), offered);
+
+    let hello = |version, features: &[&str]| Request::Hello {
+        version,
+        features: features.iter().map(|f| f.to_string()).collect(),
+    };
+    let responses = client.pipeline(&[
+        hello(0, &[]),
+        hello(PROTOCOL_VERSION + 1, &["scan", "teleport", "backup"]),
+        Request::Batch { ops: vec![] },
+        Request::ScanPrefix {
+            prefix: b"a".to_vec(),
+        },
+        Request::Set {
+            key: b"a".to_vec(),
+            value: b"1".to_vec(),
+        },
+    ])?;
+    assert!(matches!(responses[0], Err(KvsError::Unsupported(_))));
+    assert_eq!(
+        responses[1].as_ref().ok(),
+        Some(&Response::Hello {
+            version: PROTOCOL_VERSION,
+            features: vec!["scan".to_owned()],
+        })
+    );
+    assert!(matches!(responses[2], Err(KvsError::Unsupported(_))));
+    assert!(matches!(responses[3], Ok(Response::Pairs { .. })));
+    assert!(matches!(responses[4], Ok(Response::Success { .. })));
+    assert!(matches!(
+        client.write_batch(WriteBatch::new()),
+        Err(KvsError::Unsupported(_))
+    ));
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// Errors on the server come back as the errors they are
 #[test]
-fn compaction() -> Result<()> {
+fn client_errors() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let addr = spawn_server(temp_dir.path())?;
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
+    let mut client = KvsClient::new(addr)?;
+    assert!(matches!(
+        client.remove("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        client.ttl("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+// Keys expire by the requests of a client
+#[test]
+fn client_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(temp_dir.path())?;
+
+    let mut client = KvsClient::new(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.ttl("key".to_owned())?, None);
+    client.expire("key".to_owned(), Duration::from_secs(100))?;
+    let ttl = client.ttl("key".to_owned())?.unwrap();
+    assert!(ttl > Duration::from_secs(90) && ttl <= Duration::from_secs(100));
+    client.persist("key".to_owned())?;
+    assert_eq!(client.ttl("key".to_owned())?, None);
+
+    client.expire("key".to_owned(), Duration::ZERO)?;
+    assert_eq!(client.get("key".to_owned())?, None);
+    assert!(matches!(
+        client.expire("missing".to_owned(), Duration::from_secs(1)),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // `Expire` needs the ttl feature
+    client.set("key".to_owned(), "value".to_owned())?;
+    client.pipeline(&[Request::Hello {
+        version: PROTOCOL_VERSION,
+        features: Vec::new(),
+    }])?;
+    assert!(matches!(
+        client.expire("key".to_owned(), Duration::from_secs(1)),
+        Err(KvsError::Unsupported(_))
+    ));
+    Ok(())
+}
+
+// Clients back up into and restore from the backup directory of the server only
+#[test]
+fn client_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let pool = SharedQueueThreadPool::new(2)?;
+    let mut server = KvsServer::new(logger, KvStore::open(temp_dir.path())?, pool, "127.0.0.1:0")?
+        .with_backup_dir(Some(backup_dir.path().to_owned()));
+    let addr = server.get_address();
+    thread::spawn(move || server.run());
+
+    let mut client = KvsClient::new(addr)?;
+    assert_eq!(client.features(), FEATURES);
+    client.set("a".to_owned(), "1".to_owned())?;
+    client.backup_to("copy".to_owned())?;
+    assert!(backup_dir.path().join("copy").exists());
+    client.set("a".to_owned(), "2".to_owned())?;
+    client.restore_from("copy".to_owned())?;
+    assert_eq!(client.get("a".to_owned())?, Some("1".to_owned()));
+    assert!(matches!(
+        client.restore_from("missing".to_owned()),
+        Err(KvsError::Io(_))
+    ));
+
+    let outside = TempDir::new().expect("unable to create temporary working directory");
+    let outside = outside.path().join("copy").to_string_lossy().into_owned();
+    for path in [outside.as_str(), "../copy", "copy/../../copy", ""] {
+        assert!(matches!(
+            client.backup_to(path.to_owned()),
+            Err(KvsError::Io(e)) if e.to_string().contains("not a path inside the backup directory")
+        ));
+        assert!(matches!(
+            client.restore_from(path.to_owned()),
+            Err(KvsError::Io(_))
+        ));
+    }
+    assert!(!std::path::Path::new(&outside).exists());
+    Ok(())
+}
+
+// Without a backup directory, backup is refused even before `Hello`
+#[test]
+fn backup_off_by_default() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(temp_dir.path())?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    let message = b"Backup#\r\npath:+4+copy\r\n\r\n";
+    let mut frame = b"KV\x01".to_vec();
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    stream.write_all(&frame)?;
+    let mut header = [0; 7];
+    stream.read_exact(&mut header)?;
+    let mut response = vec![0; u32::from_be_bytes(header[3..].try_into().unwrap()) as usize];
+    stream.read_exact(&mut response)?;
+    assert!(response.starts_with(b"Unsupported#"));
+    assert!(!temp_dir.path().join("copy").exists());
+
+    let mut client = KvsClient::new(addr)?;
+    assert!(matches!(
+        client.backup_to("copy".to_owned()),
+        Err(KvsError::Unsupported(_))
+    ));
+    Ok(())
+}
+
+// A request the server doesn't know, or with a field it doesn't know,
+// fails alone, not the connection
+#[test]
+fn unknown_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(temp_dir.path())?;
+
+    let frame = |message: &[u8]| {
+        let mut frame = b"KV\x01".to_vec();
+        frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        frame.extend_from_slice(message);
+        frame
     };
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&frame(b"Teleport#\r\nkey:+1+a\r\n\r\n"))?;
+    stream.write_all(&frame(b"Get#\r\nkey:+1+a\r\nextra:+1+b\r\n\r\n"))?;
+    stream.write_all(&frame(b"Get#\r\nkey:+1+a\r\n\r\n"))?;
+    let mut read_message = || -> Result<Vec<u8>> {
+        let mut header = [0; 7];
+        stream.read_exact(&mut header)?;
+        let mut message = vec![0; u32::from_be_bytes(header[3..].try_into().unwrap()) as usize];
+        stream.read_exact(&mut message)?;
+        Ok(message)
+    };
+    assert!(read_message()?.starts_with(b"Unsupported#"));
+    assert!(read_message()?.starts_with(b"Unsupported#"));
+    assert!(read_message()?.starts_with(b"Success#"));
+    Ok(())
+}
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
-        }
+#[test]
+fn client_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(temp_dir.path())?;
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
-        }
-        // Compaction triggered
+    KvsClient::new(addr)?.set("a".to_owned(), "1".to_owned())?;
 
-        drop(store);
-        // reopen and check content
-        let store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
-        }
-        return Ok(());
+    let mut txn = KvsClient::new(addr)?.begin()?;
+    assert_eq!(txn.get("a")?, Some(b"1".to_vec()));
+    txn.set("a", "2")?;
+    txn.set("b", "2")?;
+    assert_eq!(txn.get("b")?, Some(b"2".to_vec()));
+    txn.commit()?;
+    assert_eq!(
+        KvsClient::new(addr)?.get("a".to_owned())?,
+        Some("2".to_owned())
+    );
+
+    let mut txn = KvsClient::new(addr)?.begin()?;
+    txn.get("a")?;
+    txn.set("b", "3")?;
+    KvsClient::new(addr)?.set("a".to_owned(), "3".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::TransactionConflict)));
+
+    let mut txn = KvsClient::new(addr)?.begin()?;
+    txn.set("b", "4")?;
+    txn.abort()?;
+    assert_eq!(
+        KvsClient::new(addr)?.get("b".to_owned())?,
+        Some("2".to_owned())
+    );
+    Ok(())
+}
+
+fn watch_engine<E: KvsEngine>(store: &E) -> Result<()> {
+    let mut events = store.watch_prefix("a")?;
+    let mut all = store.watch_prefix("")?;
+    store.set("a1", "1")?;
+    store.set("b1", "1")?;
+    store.remove("a1")?;
+    let mut batch = WriteBatch::new();
+    batch.set("a2", "2").set("b2", "2");
+    store.write_batch(batch)?;
+    store.compare_and_swap("a2", Some(b"2".to_vec()), Some(b"3".to_vec()))?;
+
+    let set = |key: &str, value: &str| WatchEvent::Set {
+        key: key.into(),
+        value: value.into(),
+    };
+    for expected in [
+        set("a1", "1"),
+        WatchEvent::Rm {
+            key: b"a1".to_vec(),
+        },
+        set("a2", "2"),
+        set("a2", "3"),
+    ] {
+        assert_eq!(events.next(), Some(expected));
     }
+    assert_eq!(all.next(), Some(set("a1", "1")));
+    assert_eq!(all.next(), Some(set("b1", "1")));
+    assert_eq!(
+        events.next_timeout(Duration::from_millis(10)),
+        Err(RecvTimeoutError::Timeout)
+    );
 
-    panic!("No compaction detected");
+    // a watcher dropped doesn't keep writes from going on
+    drop(events);
+    drop(all);
+    store.set("a3", "3")?;
+    Ok(())
+}
+
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    watch_engine(&store)?;
+
+    // events end once the store is closed
+    let mut events = store.watch_prefix("")?;
+    store.set("key", "value")?;
+    drop(store);
+    assert_eq!(
+        events.next(),
+        Some(WatchEvent::Set {
+            key: b"key".to_vec(),
+            value: b"value".to_vec()
+        })
+    );
+    assert_eq!(events.next(), None);
+
+    // a watcher too far behind is dropped, which ends its events
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let events = store.watch_prefix("")?;
+    for key_id in 0..2000 {
+        store.set(format!("key{}", key_id), "value")?;
+    }
+    assert!(events.count() < 2000);
+    Ok(())
+}
+
+#[test]
+fn watch_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    watch_engine(&store)
+}
+
+#[test]
+fn client_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let store = KvStore::open(temp_dir.path())?;
+    // watches don't hold threads of the pool
+    let pool = SharedQueueThreadPool::new(1)?;
+    let mut server = KvsServer::new(logger, store, pool, "127.0.0.1:0")?;
+    let addr = server.get_address();
+    thread::spawn(move || server.run());
+
+    let mut events = KvsClient::new(addr)?.watch_prefix("a")?;
+    let mut others = KvsClient::new(addr)?.watch_prefix("b")?;
+    KvsClient::new(addr)?.set("a".to_owned(), "1".to_owned())?;
+    KvsClient::new(addr)?.set("b".to_owned(), "1".to_owned())?;
+    KvsClient::new(addr)?.remove("a".to_owned())?;
+
+    assert_eq!(
+        events.next().transpose()?,
+        Some(WatchEvent::Set {
+            key: b"a".to_vec(),
+            value: b"1".to_vec()
+        })
+    );
+    assert_eq!(
+        events.next().transpose()?,
+        Some(WatchEvent::Rm { key: b"a".to_vec() })
+    );
+    assert_eq!(
+        others.next().transpose()?,
+        Some(WatchEvent::Set {
+            key: b"b".to_vec(),
+            value: b"1".to_vec()
+        })
+    );
+
+    // a quiet watch is closed once its client leaves
+    let mut stream = TcpStream::connect(addr)?;
+    let message = b"Watch#\r\nprefix:+1+q\r\n\r\n";
+    let mut frame = b"KV\x01".to_vec();
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    stream.write_all(&frame)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert!(response[7..].starts_with(b"Success#"));
+    Ok(())
 }
 
 #[test]
@@ -145,14 +1963,63 @@ fn concurrent_set() -> Result<()> {
     barrier.wait();
 
     for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("value{}", i).into_bytes())
+        );
     }
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sync: SyncPolicy::GroupCommit,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..16 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..100 {
+                let key = format!("key{}_{}", thread_id, i);
+                store.set(key.clone(), format!("value{}", i)).unwrap();
+                if i % 2 == 0 {
+                    store.remove(key.clone()).unwrap();
+                    assert!(store.remove(key).is_err());
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for thread_id in 0..16 {
+        for i in 0..100 {
+            let expected = if i % 2 == 0 {
+                None
+            } else {
+                Some(format!("value{}", i).into_bytes())
+            };
+            assert_eq!(store.get(format!("key{}_{}", thread_id, i))?, expected);
+        }
     }
 
     Ok(())
@@ -176,7 +2043,7 @@ fn concurrent_get() -> Result<()> {
                 let key_id = (i + thread_id) % 100;
                 assert_eq!(
                     store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
+                    Some(format!("value{}", key_id).into_bytes())
                 );
             }
         });
@@ -197,7 +2064,7 @@ fn concurrent_get() -> Result<()> {
                 let key_id = (i + thread_id) % 100;
                 assert_eq!(
                     store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
+                    Some(format!("value{}", key_id).into_bytes())
                 );
             }
         });
@@ -209,3 +2076,585 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn idle_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let requests = [
+        (Protocol::Serde, &b""[..], "PONG"),
+        (Protocol::Resp, &b"PING\r\n"[..], "+PONG\r\n"),
+        (Protocol::Memcached, &b"version\r\n"[..], "VERSION"),
+        (
+            Protocol::Http,
+            &b"GET /health HTTP/1.1\r\nConnection: close\r\n\r\n"[..],
+            "HTTP/1.1 200 OK",
+        ),
+    ];
+    for (protocol, request, reply) in requests {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let pool = SharedQueueThreadPool::new(2)?;
+        let mut server = KvsServer::new(logger, store.clone(), pool, "127.0.0.1:0")?
+            .with_protocol(protocol)
+            .with_idle_timeout(Some(Duration::from_millis(200)));
+        let addr = server.get_address();
+        thread::spawn(move || server.run());
+
+        // more idle connections than threads, one of them in a transaction
+        let mut idle = Vec::new();
+        for _ in 0..3 {
+            idle.push(TcpStream::connect(addr)?);
+        }
+        let txn = match protocol {
+            Protocol::Serde => Some(KvsClient::new(addr)?.begin()?),
+            _ => None,
+        };
+
+        if protocol == Protocol::Serde {
+            let mut client = KvsClient::new(addr)?;
+            client.set("key".to_owned(), reply.to_owned())?;
+            assert_eq!(client.get("key".to_owned())?, Some(reply.to_owned()));
+        } else {
+            let mut stream = TcpStream::connect(addr)?;
+            stream.write_all(request)?;
+            let mut answer = String::new();
+            BufReader::new(stream).read_line(&mut answer)?;
+            assert!(answer.starts_with(reply), "{:?}: {}", protocol, answer);
+        }
+
+        // the server has closed the idle connections
+        for mut stream in idle {
+            assert_eq!(stream.read(&mut [0; 1])?, 0);
+        }
+        drop(txn);
+    }
+    Ok(())
+}
+
+/// Start a RESP2 server of `engine` on a free port
+fn spawn_resp_server<E: KvsEngine>(engine: E) -> Result<SocketAddr> {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let pool = SharedQueueThreadPool::new(2)?;
+    let mut server =
+        KvsServer::new(logger, engine, pool, "127.0.0.1:0")?.with_protocol(Protocol::Resp);
+    let addr = server.get_address();
+    thread::spawn(move || server.run());
+    Ok(addr)
+}
+
+/// Encode a command as a RESP2 array of bulk strings
+fn resp_command(args: &[&str]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    command.into_bytes()
+}
+
+/// Read a whole RESP2 reply, with its nested lines
+fn read_resp_reply<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut reply = String::new();
+    reader.read_line(&mut reply)?;
+    let n: i64 = reply[1..reply.len() - 2].parse().unwrap_or(-1);
+    match reply.as_bytes()[0] {
+        b'$' if n >= 0 => {
+            reader.read_line(&mut reply)?;
+        }
+        b'*' => {
+            for _ in 0..n {
+                reply.push_str(&read_resp_reply(reader)?);
+            }
+        }
+        _ => {}
+    }
+    Ok(reply)
+}
+
+fn resp_engine<E: KvsEngine>(engine: E) -> Result<()> {
+    let addr = spawn_resp_server(engine)?;
+    let stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut call = |args: &[&str]| -> Result<String> {
+        writer.write_all(&resp_command(args))?;
+        read_resp_reply(&mut reader)
+    };
+
+    assert_eq!(call(&["PING"])?, "+PONG\r\n");
+    assert_eq!(call(&["ping", "hi"])?, "$2\r\nhi\r\n");
+    assert_eq!(call(&["SET", "user:1", "alice"])?, "+OK\r\n");
+    assert_eq!(call(&["SET", "user:2", "bob"])?, "+OK\r\n");
+    assert_eq!(call(&["SET", "item:1", "book"])?, "+OK\r\n");
+    assert_eq!(call(&["GET", "user:1"])?, "$5\r\nalice\r\n");
+    assert_eq!(call(&["GET", "missing"])?, "$-1\r\n");
+    assert_eq!(call(&["SET", "user:1", "carol", "NX"])?, "$-1\r\n");
+    assert_eq!(call(&["SET", "user:3", "dave", "XX"])?, "$-1\r\n");
+    assert_eq!(call(&["SET", "user:3", "dave", "NX"])?, "+OK\r\n");
+    assert_eq!(call(&["EXISTS", "user:1", "missing", "user:1"])?, ":2\r\n");
+
+    assert_eq!(
+        call(&["KEYS", "user:*"])?,
+        "*3\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n$6\r\nuser:3\r\n"
+    );
+    assert_eq!(
+        call(&["KEYS", "*:[12]"])?,
+        "*3\r\n$6\r\nitem:1\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n"
+    );
+    assert_eq!(
+        call(&["SCAN", "0", "MATCH", "user:?", "COUNT", "2"])?,
+        "*2\r\n$1\r\n1\r\n*2\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n"
+    );
+    // the cursor resumes after the last key examined, even if keys before
+    // it are gone
+    assert_eq!(call(&["SET", "user:0", "erin"])?, "+OK\r\n");
+    assert_eq!(call(&["DEL", "user:1"])?, ":1\r\n");
+    assert_eq!(
+        call(&["SCAN", "1", "MATCH", "user:?", "COUNT", "2"])?,
+        "*2\r\n$1\r\n0\r\n*1\r\n$6\r\nuser:3\r\n"
+    );
+    assert_eq!(call(&["SCAN", "99"])?, "-ERR invalid cursor\r\n");
+    assert_eq!(call(&["DEL", "user:0"])?, ":1\r\n");
+    assert_eq!(call(&["SET", "user:1", "alice"])?, "+OK\r\n");
+
+    assert_eq!(call(&["EXPIRE", "missing", "10"])?, ":0\r\n");
+    assert_eq!(call(&["EXPIRE", "user:3", "10"])?, ":1\r\n");
+    assert_eq!(call(&["GET", "user:3"])?, "$4\r\ndave\r\n");
+    assert_eq!(call(&["EXPIRE", "user:3", "0"])?, ":1\r\n");
+    assert_eq!(call(&["SET", "session", "token", "PX", "100"])?, "+OK\r\n");
+    // conditions along with an expiry
+    assert_eq!(call(&["SET", "lock", "a", "NX", "PX", "100"])?, "+OK\r\n");
+    assert_eq!(call(&["SET", "lock", "b", "NX", "EX", "10"])?, "$-1\r\n");
+    assert_eq!(call(&["SET", "lease", "a", "XX", "EX", "10"])?, "$-1\r\n");
+    assert_eq!(call(&["SET", "lease", "a"])?, "+OK\r\n");
+    assert_eq!(call(&["SET", "lease", "b", "PX", "100", "XX"])?, "+OK\r\n");
+    assert_eq!(call(&["GET", "lease"])?, "$1\r\nb\r\n");
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(
+        call(&["EXISTS", "user:3", "session", "lock", "lease"])?,
+        ":0\r\n"
+    );
+    assert_eq!(call(&["SET", "lock", "c", "NX", "EX", "10"])?, "+OK\r\n");
+    assert_eq!(call(&["DEL", "lock"])?, ":1\r\n");
+
+    assert_eq!(call(&["DEL", "user:1", "user:2", "missing"])?, ":2\r\n");
+    assert_eq!(call(&["KEYS", "*"])?, "*1\r\n$6\r\nitem:1\r\n");
+    assert_eq!(
+        call(&["KEYS", "[h-j]tem\\:[^2]"])?,
+        "*1\r\n$6\r\nitem:1\r\n"
+    );
+
+    // patterns with many `*` take no longer than the key times the pattern
+    let long = "a".repeat(1000);
+    assert_eq!(call(&["SET", &long, "1"])?, "+OK\r\n");
+    assert_eq!(call(&["KEYS", "*a*a*a*a*a*a*a*a*a*a*b"])?, "*0\r\n");
+    assert_eq!(
+        call(&["KEYS", "*a*a*a*a*a*a*a*a*a*a"])?,
+        format!("*1\r\n$1000\r\n{}\r\n", long)
+    );
+    assert_eq!(call(&["DEL", &long])?, ":1\r\n");
+
+    assert_eq!(
+        call(&["GET"])?,
+        "-ERR wrong number of arguments for 'get' command\r\n"
+    );
+    assert_eq!(
+        call(&["SET", "a", "b", "EX", "0"])?,
+        "-ERR invalid expire time in 'set' command\r\n"
+    );
+    assert_eq!(
+        call(&["SET", "a", "b", "FOREVER"])?,
+        "-ERR syntax error\r\n"
+    );
+    assert_eq!(call(&["FLUSHALL"])?, "-ERR unknown command 'flushall'\r\n");
+    assert_eq!(call(&["QUIT"])?, "+OK\r\n");
+    Ok(())
+}
+
+#[test]
+fn resp() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    resp_engine(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn resp_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    resp_engine(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn resp_inline_and_pipelined() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_resp_server(KvStore::open(temp_dir.path())?)?;
+
+    // inline commands and several commands in one write, as telnet and
+    // pipelining clients send them
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"SET a 1\r\nGET a\r\n\r\nPING\r\n")?;
+    stream.write_all(&resp_command(&["GET", "a"]))?;
+    stream.write_all(b"*1\r\n$4\r\nPING\r\n*1\r\n$-x\r\n")?;
+    let mut replies = String::new();
+    stream.read_to_string(&mut replies)?;
+    assert_eq!(
+        replies,
+        "+OK\r\n$1\r\n1\r\n+PONG\r\n$1\r\n1\r\n+PONG\r\n\
+         -ERR Protocol error: invalid bulk length\r\n"
+    );
+    Ok(())
+}
+
+/// Start a memcached server of `engine` on a free port
+fn spawn_memcached_server<E: KvsEngine>(engine: E) -> Result<SocketAddr> {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let pool = SharedQueueThreadPool::new(2)?;
+    let mut server =
+        KvsServer::new(logger, engine, pool, "127.0.0.1:0")?.with_protocol(Protocol::Memcached);
+    let addr = server.get_address();
+    thread::spawn(move || server.run());
+    Ok(addr)
+}
+
+/// Read memcached reply lines up to the one ending it
+fn read_memcached_reply<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut reply = String::new();
+    loop {
+        let start = reply.len();
+        reader.read_line(&mut reply)?;
+        if !reply[start..].starts_with("VALUE ") {
+            return Ok(reply);
+        }
+        reader.read_line(&mut reply)?;
+    }
+}
+
+fn memcached_engine<E: KvsEngine>(engine: E) -> Result<()> {
+    let addr = spawn_memcached_server(engine)?;
+    let stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut call = |command: &str| -> Result<String> {
+        writer.write_all(command.as_bytes())?;
+        read_memcached_reply(&mut reader)
+    };
+
+    assert_eq!(call("set a 5 0 5\r\nhello\r\n")?, "STORED\r\n");
+    assert_eq!(
+        call("get a missing\r\n")?,
+        "VALUE a 5 5\r\nhello\r\nEND\r\n"
+    );
+    assert_eq!(call("add a 0 0 1\r\nx\r\n")?, "NOT_STORED\r\n");
+    assert_eq!(call("replace b 0 0 1\r\nx\r\n")?, "NOT_STORED\r\n");
+    assert_eq!(call("add b 1 0 1\r\nx\r\n")?, "STORED\r\n");
+    assert_eq!(call("replace b 2 0 1\r\ny\r\n")?, "STORED\r\n");
+    assert_eq!(call("get b\r\n")?, "VALUE b 2 1\r\ny\r\nEND\r\n");
+
+    // cas succeeds only with the unique of the latest gets
+    let gets = call("gets a\r\n")?;
+    let unique = gets
+        .split("\r\n")
+        .next()
+        .unwrap()
+        .rsplit(' ')
+        .next()
+        .unwrap();
+    assert!(gets.starts_with("VALUE a 5 5 "));
+    let cas = format!("cas a 6 0 5 {}\r\nworld\r\n", unique);
+    assert_eq!(call(&cas)?, "STORED\r\n");
+    assert_eq!(call(&cas)?, "EXISTS\r\n");
+    assert_eq!(call("cas missing 0 0 1 1\r\nx\r\n")?, "NOT_FOUND\r\n");
+    assert_eq!(call("get a\r\n")?, "VALUE a 6 5\r\nworld\r\nEND\r\n");
+
+    assert_eq!(call("set n 7 0 2\r\n10\r\n")?, "STORED\r\n");
+    assert_eq!(call("incr n 5\r\n")?, "15\r\n");
+    assert_eq!(call("decr n 100\r\n")?, "0\r\n");
+    assert_eq!(call("get n\r\n")?, "VALUE n 7 1\r\n0\r\nEND\r\n");
+    assert_eq!(call("incr missing 1\r\n")?, "NOT_FOUND\r\n");
+    assert_eq!(
+        call("incr a 1\r\n")?,
+        "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n"
+    );
+
+    // exptime counts seconds, or is a unix time past 30 days
+    assert_eq!(call("set t 0 1 1\r\nx\r\n")?, "STORED\r\n");
+    assert_eq!(call("set gone 0 -1 1\r\nx\r\n")?, "STORED\r\n");
+    assert_eq!(call("set past 0 1000000000 1\r\nx\r\n")?, "STORED\r\n");
+    assert_eq!(call("get t gone past\r\n")?, "VALUE t 0 1\r\nx\r\nEND\r\n");
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(call("get t\r\n")?, "END\r\n");
+
+    assert_eq!(call("delete a\r\n")?, "DELETED\r\n");
+    assert_eq!(call("delete a\r\n")?, "NOT_FOUND\r\n");
+    assert_eq!(
+        call("set a 0 0 1 noreply\r\nx\r\ndelete b noreply\r\nget a b\r\n")?,
+        "VALUE a 0 1\r\nx\r\nEND\r\n"
+    );
+
+    assert_eq!(call("flush_all\r\n")?, "ERROR\r\n");
+    assert_eq!(
+        call("set a x 0 1\r\n")?,
+        "CLIENT_ERROR bad command line format\r\n"
+    );
+    let large = format!("set a 0 0 2000000\r\n{}\r\n", "x".repeat(2000000));
+    assert_eq!(call(&large)?, "SERVER_ERROR object too large for cache\r\n");
+    assert!(call("version\r\n")?.starts_with("VERSION "));
+    assert_eq!(
+        call("set a 0 0 1\r\nxyz\r\n")?,
+        "CLIENT_ERROR bad data chunk\r\n"
+    );
+
+    // so does a length overflowing with the line ending of its data block
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(format!("set a 0 0 {}\r\n", u64::MAX).as_bytes())?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    assert_eq!(reply, "CLIENT_ERROR bad data chunk\r\n");
+    Ok(())
+}
+
+#[test]
+fn memcached() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    memcached_engine(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn memcached_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    memcached_engine(SledKvsEngine::open(temp_dir.path())?)
+}
+
+/// Values read the same through memcached and natively, whichever wrote them
+fn memcached_native_engine<E: KvsEngine>(engine: E) -> Result<()> {
+    let addr = spawn_memcached_server(engine.clone())?;
+    let stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut call = |command: &str| -> Result<String> {
+        writer.write_all(command.as_bytes())?;
+        read_memcached_reply(&mut reader)
+    };
+
+    // a native value reads with flags 0 and its bytes unchanged
+    engine.set(b"native".to_vec(), b"value".to_vec())?;
+    assert_eq!(
+        call("get native\r\n")?,
+        "VALUE native 0 5\r\nvalue\r\nEND\r\n"
+    );
+
+    // an item reads natively as its data alone
+    assert_eq!(call("set item 5 0 4\r\ndata\r\n")?, "STORED\r\n");
+    assert_eq!(engine.get(b"item".to_vec())?, Some(b"data".to_vec()));
+
+    // flags of an item overwritten natively are dropped
+    engine.set(b"item".to_vec(), b"other".to_vec())?;
+    assert_eq!(call("get item\r\n")?, "VALUE item 0 5\r\nother\r\nEND\r\n");
+
+    // and flags of a deleted item do not come back with a new one
+    assert_eq!(call("set gone 7 0 1\r\nx\r\n")?, "STORED\r\n");
+    assert_eq!(call("delete gone\r\n")?, "DELETED\r\n");
+    engine.set(b"gone".to_vec(), b"x".to_vec())?;
+    assert_eq!(call("get gone\r\n")?, "VALUE gone 0 1\r\nx\r\nEND\r\n");
+    Ok(())
+}
+
+#[test]
+fn memcached_native() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    memcached_native_engine(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn memcached_native_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    memcached_native_engine(SledKvsEngine::open(temp_dir.path())?)
+}
+
+/// Start an HTTP gateway of `engine` on a free port
+fn spawn_http_server<E: KvsEngine>(engine: E) -> Result<SocketAddr> {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let pool = SharedQueueThreadPool::new(2)?;
+    let mut server =
+        KvsServer::new(logger, engine, pool, "127.0.0.1:0")?.with_protocol(Protocol::Http);
+    let addr = server.get_address();
+    thread::spawn(move || server.run());
+    Ok(addr)
+}
+
+/// Send one HTTP request on a connection of its own, returning the status
+/// and body of the response
+fn http_call(addr: SocketAddr, method: &str, target: &str, body: &str) -> Result<(u16, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: kvs\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        target,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").unwrap().1.to_owned();
+    Ok((status, body))
+}
+
+fn http_engine<E: KvsEngine>(engine: E) -> Result<()> {
+    let addr = spawn_http_server(engine)?;
+    let call = |method: &str, target: &str, body: &str| http_call(addr, method, target, body);
+
+    assert_eq!(
+        call("GET", "/health", "")?,
+        (200, r#"{"status":"ok"}"#.to_owned())
+    );
+    assert_eq!(
+        call("PUT", "/keys/user%2F1", r#"{"value":"alice"}"#)?,
+        (204, String::new())
+    );
+    assert_eq!(
+        call("PUT", "/keys/user%2F2", r#"{"value":"bob","ttl_ms":1000}"#)?,
+        (204, String::new())
+    );
+    call("PUT", "/keys/item", r#"{"value":"book"}"#)?;
+    assert_eq!(
+        call("GET", "/keys/user%2F1", "")?,
+        (200, r#"{"key":"user/1","value":"alice"}"#.to_owned())
+    );
+    assert_eq!(
+        call("GET", "/keys?prefix=user%2F", "")?,
+        (
+            200,
+            r#"{"next_cursor":null,"pairs":[{"key":"user/1","value":"alice"},{"key":"user/2","value":"bob"}]}"#
+                .to_owned()
+        )
+    );
+    let (status, stats) = call("GET", "/stats", "")?;
+    let stats: serde_json::Value = serde_json::from_str(&stats).unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(stats["keys"], 3);
+    assert!(stats["disk_bytes"].as_u64().unwrap() > 0);
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(call("GET", "/keys/user%2F2", "")?.0, 404);
+
+    assert_eq!(call("DELETE", "/keys/item", "")?, (204, String::new()));
+    assert_eq!(
+        call("DELETE", "/keys/item", "")?,
+        (404, r#"{"error":"Key not found"}"#.to_owned())
+    );
+    assert_eq!(call("PUT", "/keys/bad", "value")?.0, 400);
+    assert_eq!(call("POST", "/keys/item", "")?.0, 405);
+    assert_eq!(call("GET", "/nowhere", "")?.0, 404);
+
+    // keys and values which are not UTF-8 come in base64
+    assert_eq!(
+        call(
+            "PUT",
+            "/keys/bin%FF",
+            r#"{"value":"AAH/","encoding":"base64"}"#
+        )?,
+        (204, String::new())
+    );
+    let binary = r#"{"encoding":"base64","key":"Ymlu/w==","value":"AAH/"}"#;
+    assert_eq!(call("GET", "/keys/bin%FF", "")?, (200, binary.to_owned()));
+    assert_eq!(
+        call("GET", "/keys?prefix=bin", "")?,
+        (
+            200,
+            format!(r#"{{"next_cursor":null,"pairs":[{}]}}"#, binary)
+        )
+    );
+    for body in [
+        r#"{"value":"AAH","encoding":"base64"}"#,
+        r#"{"value":"A=AA","encoding":"base64"}"#,
+        r#"{"value":"AAH/","encoding":"rot13"}"#,
+    ] {
+        assert_eq!(call("PUT", "/keys/bin%FF", body)?.0, 400);
+    }
+
+    // pages of keys, each going on from the cursor of the last one
+    for i in 1..=5 {
+        call("PUT", &format!("/keys/page:{}", i), r#"{"value":"v"}"#)?;
+    }
+    let mut keys = Vec::new();
+    let mut target = "/keys?prefix=page%3A&limit=2".to_owned();
+    loop {
+        let (status, page) = call("GET", &target, "")?;
+        assert_eq!(status, 200);
+        let page: serde_json::Value = serde_json::from_str(&page).unwrap();
+        let pairs = page["pairs"].as_array().unwrap();
+        assert!(pairs.len() <= 2);
+        keys.extend(
+            pairs
+                .iter()
+                .map(|pair| pair["key"].as_str().unwrap().to_owned()),
+        );
+        match page["next_cursor"].as_str() {
+            Some(cursor) => target = format!("/keys?prefix=page%3A&limit=2&cursor={}", cursor),
+            None => break,
+        }
+    }
+    assert_eq!(keys, ["page:1", "page:2", "page:3", "page:4", "page:5"]);
+    assert_eq!(call("GET", "/keys?limit=0", "")?.0, 400);
+    assert_eq!(call("GET", "/keys?limit=10001", "")?.0, 400);
+
+    // a wrong method is answered with the methods allowed
+    for (method, target, allow) in [
+        ("POST", "/keys/item", "GET, PUT, DELETE"),
+        ("DELETE", "/keys", "GET"),
+        ("PUT", "/health", "GET"),
+    ] {
+        let mut stream = TcpStream::connect(addr)?;
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nConnection: close\r\n\r\n",
+            method, target
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(response.contains(&format!("\r\nAllow: {}\r\n", allow)));
+    }
+    Ok(())
+}
+
+#[test]
+fn http() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    http_engine(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn http_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    http_engine(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn http_keep_alive() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_http_server(KvStore::open(temp_dir.path())?)?;
+
+    // requests of one connection are answered in order until it is closed
+    let mut stream = TcpStream::connect(addr)?;
+    let body = r#"{"value":"1"}"#;
+    write!(
+        stream,
+        "PUT /keys/a HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    stream.write_all(b"GET /keys/a HTTP/1.1\r\n\r\n")?;
+    stream.write_all(b"GET /keys/a HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+    let mut responses = String::new();
+    stream.read_to_string(&mut responses)?;
+    assert_eq!(responses.matches("HTTP/1.1 204 No Content").count(), 1);
+    assert_eq!(responses.matches(r#"{"key":"a","value":"1"}"#).count(), 2);
+    assert!(responses.ends_with(r#"{"key":"a","value":"1"}"#));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"NONSENSE\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    Ok(())
+}